@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_updated_at_idx;
+
+DROP TRIGGER set_updated_at ON users;
+
+ALTER TABLE users
+    DROP COLUMN deleted_at,
+    DROP COLUMN updated_at;
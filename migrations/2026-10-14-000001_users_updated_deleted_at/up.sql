@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE users
+    ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ADD COLUMN deleted_at TIMESTAMP;
+
+UPDATE users SET updated_at = created_at;
+
+SELECT diesel_manage_updated_at('users');
+
+CREATE INDEX users_updated_at_idx ON users (updated_at, id);
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX users_email_domain_idx ON users (lower(split_part(email, '@', 2)));
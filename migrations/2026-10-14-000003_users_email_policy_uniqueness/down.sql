@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_email_idx;
+DROP INDEX IF EXISTS users_email_key, users_name_email_key;
+
+ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
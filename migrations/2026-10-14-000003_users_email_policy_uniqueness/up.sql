@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Email uniqueness depends on UNIQUENESS_POLICY. The unique index for the
+-- configured policy is created at startup, see `uniqueness::ensure_index`,
+-- so the column constraint becomes a plain lookup index.
+ALTER TABLE users DROP CONSTRAINT users_email_key;
+
+CREATE INDEX users_email_idx ON users (email);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_is_stale_idx;
+
+ALTER TABLE users
+    DROP COLUMN is_stale,
+    DROP COLUMN last_login;
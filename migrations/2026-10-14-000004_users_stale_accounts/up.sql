@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE users
+    ADD COLUMN last_login TIMESTAMP,
+    ADD COLUMN is_stale BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX users_is_stale_idx ON users (id) WHERE is_stale;
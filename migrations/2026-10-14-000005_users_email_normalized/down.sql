@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_email_normalized_idx;
+
+ALTER TABLE users DROP COLUMN email_normalized;
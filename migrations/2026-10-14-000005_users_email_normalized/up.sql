@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Lowercased email kept by Postgres, compared against instead of `email`
+-- when EMAIL_CASE_INSENSITIVE is set so the typed casing is kept for display
+ALTER TABLE users
+    ADD COLUMN email_normalized VARCHAR NOT NULL GENERATED ALWAYS AS (lower(email)) STORED;
+
+CREATE INDEX users_email_normalized_idx ON users (email_normalized);
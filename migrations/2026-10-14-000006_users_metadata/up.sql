@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Free-form JSON object per user, merged into by POST /users/{id}/metadata
+ALTER TABLE users ADD COLUMN metadata JSONB;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- For the `meta.<key>` filters of get_users, see `handler::metadata_matches`
+CREATE INDEX users_metadata_idx ON users USING GIN (metadata);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_display_name_idx;
+
+ALTER TABLE users DROP COLUMN display_name;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- "first last", kept by Postgres. btrim drops the separator when either
+-- name is empty.
+ALTER TABLE users
+    ADD COLUMN display_name VARCHAR NOT NULL
+        GENERATED ALWAYS AS (btrim(first_name || ' ' || last_name)) STORED;
+
+CREATE INDEX users_display_name_idx ON users (display_name, id);
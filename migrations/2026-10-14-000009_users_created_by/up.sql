@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Who added the user, null when the request wasn't authenticated
+ALTER TABLE users ADD COLUMN created_by VARCHAR;
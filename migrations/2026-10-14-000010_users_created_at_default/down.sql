@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users ALTER COLUMN created_at DROP DEFAULT;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- created_at comes from the database clock, like updated_at and deleted_at
+ALTER TABLE users ALTER COLUMN created_at SET DEFAULT CURRENT_TIMESTAMP;
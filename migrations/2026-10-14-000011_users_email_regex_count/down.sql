@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP FUNCTION users_email_regex_count(text);
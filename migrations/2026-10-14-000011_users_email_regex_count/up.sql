@@ -0,0 +1,18 @@
+-- Your SQL goes here
+-- Counts the active users whose email matches `pattern` case-insensitively.
+-- An invalid pattern (2201B) or a statement timeout (57014) is returned as
+-- `state` and `detail` instead of raised, so callers can tell them apart by
+-- SQLSTATE; the client only sees the error message.
+CREATE FUNCTION users_email_regex_count(
+    pattern text,
+    OUT state text,
+    OUT detail text,
+    OUT total bigint
+) AS $$
+BEGIN
+    SELECT count(*) INTO total FROM users WHERE deleted_at IS NULL AND email ~* pattern;
+EXCEPTION WHEN invalid_regular_expression OR query_canceled THEN
+    state := SQLSTATE;
+    detail := SQLERRM;
+END
+$$ LANGUAGE plpgsql;
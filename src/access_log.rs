@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+
+use crate::models;
+
+// Requests kept by `RecentProblems`, the oldest are dropped first
+const RECENT_PROBLEMS: usize = 100;
+
+// Decides which requests get an access log line: one in `rate`, counted
+// across all workers. 5xx responses are always logged.
+#[derive(Clone)]
+pub struct LogSampler {
+    seen: Arc<AtomicU64>,
+    rate: u64,
+}
+
+impl LogSampler {
+    pub fn new(rate: u64) -> LogSampler {
+        LogSampler {
+            seen: Arc::new(AtomicU64::new(0)),
+            rate: rate.max(1),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.rate)
+    }
+}
+
+// The last RECENT_PROBLEMS requests that failed with a 5xx or took longer
+// than `slow`, for `/admin/recent-problems`. Shared by all workers.
+pub struct RecentProblems {
+    requests: Mutex<VecDeque<models::ProblemRequest>>,
+    // None disables tracking slow requests
+    slow: Option<Duration>,
+}
+
+impl RecentProblems {
+    pub fn new(slow: Option<Duration>) -> RecentProblems {
+        RecentProblems {
+            requests: Mutex::new(VecDeque::with_capacity(RECENT_PROBLEMS)),
+            slow,
+        }
+    }
+
+    fn record(&self, request: models::ProblemRequest) {
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() == RECENT_PROBLEMS {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    // Newest first
+    pub fn recent(&self) -> Vec<models::ProblemRequest> {
+        self.requests.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+// Access log in the format of actix's default Logger, minus the referer and
+// user agent. Without a `LogSampler` every request is logged. Also feeds
+// `RecentProblems` when it is mounted.
+pub async fn log_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let sampled = req
+        .app_data::<Data<LogSampler>>()
+        .is_none_or(|sampler| sampler.sample());
+    let started = Instant::now();
+    let peer = req
+        .connection_info()
+        .peer_addr()
+        .unwrap_or("-")
+        .to_string();
+    let line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+    let problems = req.app_data::<Data<RecentProblems>>().cloned();
+    let (method, path) = (req.method().to_string(), req.path().to_string());
+    // Set by the proxy in front, if any
+    let request_id = req
+        .headers()
+        .get("X-Request-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let res = next.call(req).await;
+
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let elapsed = started.elapsed();
+    if is_logged(sampled, status) {
+        log::info!("{} \"{}\" {} {:.6}", peer, line, status.as_u16(), elapsed.as_secs_f64());
+    }
+
+    if let Some(problems) = problems {
+        let slow = problems.slow.is_some_and(|slow| elapsed > slow);
+        if slow || status.is_server_error() {
+            problems.record(models::ProblemRequest {
+                at: chrono::Utc::now().naive_utc(),
+                kind: if status.is_server_error() { "error" } else { "slow" },
+                method,
+                path,
+                status: status.as_u16(),
+                duration_ms: elapsed.as_secs_f64() * 1000.0,
+                request_id,
+            });
+        }
+    }
+
+    res
+}
+
+// Server errors bypass the sampler
+fn is_logged(sampled: bool, status: StatusCode) -> bool {
+    sampled || status.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_in_rate_requests_is_sampled() {
+        let sampler = LogSampler::new(3);
+        let sampled: Vec<bool> = (0..6).map(|_| sampler.sample()).collect();
+        assert_eq!(sampled, [true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn server_errors_bypass_the_sampler() {
+        assert!(is_logged(false, StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(is_logged(false, StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_logged(false, StatusCode::NOT_FOUND));
+        assert!(!is_logged(false, StatusCode::OK));
+        assert!(is_logged(true, StatusCode::OK));
+    }
+}
@@ -0,0 +1,632 @@
+use crate::access_log::RecentProblems;
+use crate::jobs::JobStore;
+use crate::metrics::POOL_METRICS;
+use crate::readiness::Readiness;
+use crate::tasks::Background;
+use crate::{config::AppConfig, handler::get_conn_from_db, models, user_error::UserError, validation, DbPool};
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+// Rows updated per transaction by `/admin/repair-timestamps`
+const REPAIR_BATCH_SIZE: i64 = 1000;
+// Rows loaded at a time by the table scans of `/admin/table-checksum` and
+// `/admin/normalization-preview`
+const SCAN_BATCH_SIZE: i64 = 1000;
+// Rows `/admin/normalization-preview` scans by default and at most, and the
+// changed and rejected rows it lists
+const DEFAULT_PREVIEW_ROWS: i64 = 10_000;
+const MAX_PREVIEW_ROWS: i64 = 100_000;
+const PREVIEW_SAMPLES: usize = 20;
+
+// Columns the models expect in the users table, as reported by
+// information_schema: (name, data_type, nullable). Keep in sync with schema.rs.
+const EXPECTED_USER_COLUMNS: &[(&str, &str, bool)] = &[
+    ("id", "integer", false),
+    ("user_id", "uuid", false),
+    ("first_name", "character varying", false),
+    ("last_name", "character varying", false),
+    ("email", "character varying", false),
+    ("created_at", "timestamp without time zone", false),
+    ("updated_at", "timestamp without time zone", false),
+    ("deleted_at", "timestamp without time zone", true),
+    ("last_login", "timestamp without time zone", true),
+    ("is_stale", "boolean", false),
+    ("email_normalized", "character varying", false),
+    ("metadata", "jsonb", true),
+    ("display_name", "character varying", false),
+    ("created_by", "character varying", true),
+];
+
+// Admin routes need the configured key in the X-Admin-Key header. Without an
+// ADMIN_KEY every admin request is refused.
+pub fn require_admin(req: &HttpRequest, config: &AppConfig) -> Result<(), UserError> {
+    let provided = req
+        .headers()
+        .get("X-Admin-Key")
+        .and_then(|value| value.to_str().ok());
+
+    match (&config.admin_key, provided) {
+        (Some(expected), Some(provided)) if keys_match(expected, provided) => Ok(()),
+        _ => Err(UserError::Forbidden),
+    }
+}
+
+// Compares the SHA-256 of both keys without stopping at the first difference,
+// so the time taken says nothing about how much of the key was right. Hashing
+// first also keeps the length of the key out of the timing.
+fn keys_match(expected: &str, provided: &str) -> bool {
+    let expected = Sha256::digest(expected.as_bytes());
+    let provided = Sha256::digest(provided.as_bytes());
+    expected
+        .iter()
+        .zip(provided.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// Who is making the request, for attribution. The admin key is the only
+// credential there is, so this is "admin" or None for anonymous requests.
+pub fn principal(req: &HttpRequest, config: &AppConfig) -> Option<String> {
+    require_admin(req, config).ok().map(|_| "admin".to_string())
+}
+
+#[derive(QueryableByName)]
+struct ColumnInfo {
+    #[diesel(sql_type = Text)]
+    column_name: String,
+    #[diesel(sql_type = Text)]
+    data_type: String,
+    #[diesel(sql_type = Text)]
+    is_nullable: String,
+}
+
+pub async fn schema_check(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let columns_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        diesel::sql_query(
+            "SELECT column_name::text, data_type::text, is_nullable::text \
+             FROM information_schema.columns \
+             WHERE table_schema = current_schema() AND table_name = 'users'",
+        )
+        .load::<ColumnInfo>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    let columns = columns_result?;
+
+    let mut report = models::SchemaReport {
+        ok: true,
+        missing: Vec::new(),
+        mismatched: Vec::new(),
+        unexpected: Vec::new(),
+    };
+
+    for (name, data_type, nullable) in EXPECTED_USER_COLUMNS {
+        let expected = format_column_type(data_type, *nullable);
+
+        match columns.iter().find(|column| column.column_name == *name) {
+            None => report.missing.push(name.to_string()),
+            Some(column) => {
+                let actual =
+                    format_column_type(&column.data_type, column.is_nullable == "YES");
+                if actual != expected {
+                    report.mismatched.push(models::ColumnMismatch {
+                        column: name.to_string(),
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+    }
+
+    // Extra columns don't break the models, so they are reported but
+    // don't fail the check
+    report.unexpected = columns
+        .iter()
+        .filter(|column| {
+            !EXPECTED_USER_COLUMNS
+                .iter()
+                .any(|(name, _, _)| *name == column.column_name)
+        })
+        .map(|column| column.column_name.clone())
+        .collect();
+    report.ok = report.missing.is_empty() && report.mismatched.is_empty();
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: if report.ok { "OK" } else { "MISMATCH" }.to_string(),
+        message: "Schema checked".to_string(),
+        data: Some(report),
+        warnings: Vec::new(),
+    }))
+}
+
+fn format_column_type(data_type: &str, nullable: bool) -> String {
+    if nullable {
+        format!("{} NULL", data_type)
+    } else {
+        format!("{} NOT NULL", data_type)
+    }
+}
+
+// The configuration the process is running with, secrets redacted
+pub async fn effective_config(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Effective configuration".to_string(),
+        data: Some(config.get_ref()),
+        warnings: Vec::new(),
+    }))
+}
+
+pub async fn recent_problems(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    problems: web::Data<RecentProblems>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Recent slow and failed requests".to_string(),
+        data: Some(problems.recent()),
+        warnings: Vec::new(),
+    }))
+}
+
+#[derive(QueryableByName)]
+struct LastMigration {
+    #[diesel(sql_type = Nullable<Text>)]
+    version: Option<String>,
+}
+
+// Round trip to the database and the newest applied migration. The
+// migrations table only exists once diesel has run a migration.
+fn database_status(pool: &DbPool) -> models::DatabaseStatus {
+    let started = Instant::now();
+    let checked = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        diesel::sql_query("SELECT to_regclass('__diesel_schema_migrations')::text AS version")
+            .get_result::<LastMigration>(&mut conn)
+            .and_then(|table| match table.version {
+                Some(_) => diesel::sql_query(
+                    "SELECT max(version)::text AS version FROM __diesel_schema_migrations",
+                )
+                .get_result::<LastMigration>(&mut conn),
+                None => Ok(table),
+            })
+            .map_err(|e| e.to_string())
+    });
+
+    match checked {
+        Ok(last) => models::DatabaseStatus {
+            ok: true,
+            latency_ms: Some(started.elapsed().as_secs_f64() * 1000.0),
+            last_migration: last.version,
+            error: None,
+        },
+        Err(e) => models::DatabaseStatus {
+            ok: false,
+            latency_ms: None,
+            last_migration: None,
+            error: Some(e),
+        },
+    }
+}
+
+#[derive(QueryableByName)]
+struct MigrationCount {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+// The migrations applied to the database, oldest first, with when each ran.
+// Empty until diesel has run a migration and created its table.
+pub async fn applied_migrations(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let first_page_only = config.total_on_first_page_only;
+
+    let migrations_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        let table = diesel::sql_query("SELECT to_regclass('__diesel_schema_migrations')::text AS version")
+            .get_result::<LastMigration>(&mut conn)?;
+        let wants_total = pagination.wants_total(first_page_only);
+        if table.version.is_none() {
+            return Ok(models::Paginated::new(Vec::new(), &pagination, wants_total.then_some(0)));
+        }
+
+        let total = wants_total
+            .then(|| {
+                diesel::sql_query("SELECT count(*) AS count FROM __diesel_schema_migrations")
+                    .get_result::<MigrationCount>(&mut conn)
+                    .map(|counted| counted.count)
+            })
+            .transpose()?;
+
+        let items = diesel::sql_query(
+            "SELECT version::text AS version, run_on FROM __diesel_schema_migrations \
+             ORDER BY run_on, version LIMIT $1 OFFSET $2",
+        )
+        .bind::<BigInt, _>(pagination.per_page())
+        .bind::<BigInt, _>(pagination.offset())
+        .load::<models::AppliedMigration>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+    })
+    .await
+    .map_err(|_| UserError::Unavailable("Error reading the migrations".to_string()))?;
+
+    match migrations_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Applied migrations".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Everything the service can report about itself in one place. 503 when the
+// database is unreachable, a startup check failed or shutdown has begun.
+pub async fn system_status(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+    readiness: web::Data<Readiness>,
+    background: web::Data<Background>,
+    jobs: web::Data<JobStore>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let database = {
+        let pool = pool.clone();
+        web::block(move || database_status(&pool))
+            .await
+            .map_err(|_| UserError::Unavailable("Error checking the database".to_string()))?
+    };
+    let state = pool.state();
+    let background = background.status();
+    let readiness = readiness.problems();
+
+    let healthy = database.ok && readiness.is_empty() && !background.shutting_down;
+    let status = models::SystemStatus {
+        healthy,
+        database,
+        pool: models::PoolHealth {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: pool.max_size(),
+            wait: POOL_METRICS.summary(),
+        },
+        readiness,
+        background,
+        jobs: jobs.counts(),
+    };
+
+    let mut response = if healthy {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.json(models::GenericResponse {
+        status: if healthy { "OK" } else { "DEGRADED" }.to_string(),
+        message: "System status".to_string(),
+        data: Some(status),
+        warnings: Vec::new(),
+    }))
+}
+
+fn pool_stats(pool: &DbPool) -> models::PoolStats {
+    let state = pool.state();
+
+    models::PoolStats {
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        max_size: pool.max_size(),
+    }
+}
+
+// Swaps in a freshly built pool, for recovering from stuck connections without
+// a restart. With POOL_MODE=per_worker only the pool of the worker serving the
+// request is rebuilt.
+pub async fn recycle_pool(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let before = pool_stats(&pool);
+    let database_url = config.database_url.clone();
+
+    let recycle_result = web::block(move || {
+        pool.recycle(&database_url)?;
+        Ok::<_, diesel::r2d2::PoolError>(pool_stats(&pool))
+    })
+    .await
+    .map_err(|_| UserError::Unavailable("Error rebuilding the pool".to_string()))?;
+
+    match recycle_result {
+        Ok(after) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Pool recycled".to_string(),
+            data: Some(models::PoolRecycle { before, after }),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(UserError::Unavailable(format!("Error rebuilding the pool: {}", e))),
+    }
+}
+
+// Clamps future-dated created_at values, typically from imports, to now.
+// Each batch is its own transaction so a large repair doesn't hold one long
+// transaction. created_at is NOT NULL, so there are no missing values to fill.
+pub async fn repair_timestamps(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let repair_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let mut report = models::TimestampRepair { fixed: 0, batches: 0 };
+
+        loop {
+            let fixed = conn.transaction(|conn| {
+                let batch = users
+                    .select(id)
+                    .filter(created_at.gt(diesel::dsl::now))
+                    .limit(REPAIR_BATCH_SIZE)
+                    .for_update()
+                    .load::<i32>(conn)?;
+
+                diesel::update(users.filter(id.eq_any(batch)))
+                    .set(created_at.eq(diesel::dsl::now))
+                    .execute(conn)
+            })?;
+
+            if fixed == 0 {
+                break;
+            }
+            report.fixed += fixed;
+            report.batches += 1;
+        }
+
+        Ok::<_, UserError>(report)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match repair_result {
+        Ok(report) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Timestamps repaired".to_string(),
+            data: Some(report),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// SHA-256 over every row of users, soft-deleted ones included, in id order,
+// for comparing the data of two environments. Rows are read in batches from
+// one repeatable read snapshot, so the table is never held in memory whole.
+pub async fn table_checksum(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let checksum_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        conn.build_transaction().repeatable_read().read_only().run(|conn| {
+            let mut hasher = Sha256::new();
+            let mut rows = 0;
+            let mut after = None;
+
+            loop {
+                let mut query = users.order(id.asc()).limit(SCAN_BATCH_SIZE).into_boxed();
+                if let Some(last_id) = after {
+                    query = query.filter(id.gt(last_id));
+                }
+                let batch = query.load::<models::User>(conn)?;
+
+                for user in &batch {
+                    hasher.update(user.canonical());
+                    hasher.update(b"\n");
+                }
+                rows += batch.len();
+                match batch.last() {
+                    Some(last) if batch.len() as i64 == SCAN_BATCH_SIZE => after = Some(last.id),
+                    _ => break,
+                }
+            }
+
+            Ok::<_, UserError>(models::TableChecksum {
+                algorithm: "sha256",
+                digest: format!("{:x}", hasher.finalize()),
+                rows,
+            })
+        })
+    })
+    .await
+    .map_err(|_| UserError::Unavailable("Error computing the checksum".to_string()))?;
+
+    match checksum_result {
+        Ok(checksum) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Table checksum".to_string(),
+            data: Some(checksum),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// What storing a user's fields again under the current validation would
+// change, see `preview_normalization`
+fn normalization_changes(user: &models::User) -> Result<Vec<models::FieldChange>, UserError> {
+    let normalized = validation::validate_new_user(models::NewUser {
+        first_name: user.first_name.clone(),
+        last_name: user.last_name.clone(),
+        email: user.email.clone(),
+    })?;
+
+    let fields = [
+        ("first_name", &user.first_name, normalized.first_name),
+        ("last_name", &user.last_name, normalized.last_name),
+        ("email", &user.email, normalized.email),
+    ];
+    Ok(fields
+        .into_iter()
+        .filter(|(_, before, after)| *before != after)
+        .map(|(field, before, after)| models::FieldChange {
+            field,
+            before: before.clone(),
+            after,
+        })
+        .collect())
+}
+
+// Dry run of normalizing the stored names and emails the way add/update do
+// now (trimming, SANITIZE_INPUT, EMAIL_TRIM, EMAIL_LOWERCASE), for rows
+// stored before a setting changed. Scans up to `limit` rows in id order,
+// soft-deleted ones included, and changes nothing.
+pub async fn preview_normalization(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+    query: web::Query<models::NormalizationPreviewQuery>,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_PREVIEW_ROWS);
+    if !(1..=MAX_PREVIEW_ROWS).contains(&limit) {
+        return Err(UserError::BadRequest(format!(
+            "limit must be between 1 and {}",
+            MAX_PREVIEW_ROWS
+        )));
+    }
+
+    let preview_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let mut preview = models::NormalizationPreview {
+            scanned: 0,
+            changed: 0,
+            rejected: 0,
+            truncated: false,
+            samples: Vec::new(),
+            rejections: Vec::new(),
+        };
+        let mut after = None;
+
+        while (preview.scanned as i64) < limit {
+            let remaining = limit - preview.scanned as i64;
+            let mut batch_query = users
+                .order(id.asc())
+                .limit(remaining.min(SCAN_BATCH_SIZE))
+                .into_boxed();
+            if let Some(last_id) = after {
+                batch_query = batch_query.filter(id.gt(last_id));
+            }
+            let batch = batch_query.load::<models::User>(&mut conn)?;
+
+            for user in &batch {
+                match normalization_changes(user) {
+                    Ok(changes) if changes.is_empty() => {}
+                    Ok(changes) => {
+                        preview.changed += 1;
+                        if preview.samples.len() < PREVIEW_SAMPLES {
+                            preview.samples.push(models::NormalizationSample {
+                                user_id: user.user_id,
+                                changes,
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        preview.rejected += 1;
+                        if preview.rejections.len() < PREVIEW_SAMPLES {
+                            preview.rejections.push(models::NormalizationRejection {
+                                user_id: user.user_id,
+                                error: e.to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+            preview.scanned += batch.len();
+            match batch.last() {
+                Some(last) if batch.len() as i64 == remaining.min(SCAN_BATCH_SIZE) => {
+                    after = Some(last.id)
+                }
+                _ => return Ok(preview),
+            }
+        }
+
+        preview.truncated = match after {
+            Some(last_id) => diesel::select(diesel::dsl::exists(users.filter(id.gt(last_id))))
+                .get_result::<bool>(&mut conn)?,
+            None => false,
+        };
+        Ok::<_, UserError>(preview)
+    })
+    .await
+    .map_err(|_| UserError::Unavailable("Error scanning users".to_string()))?;
+
+    match preview_result {
+        Ok(preview) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Normalization preview, nothing was changed".to_string(),
+            data: Some(preview),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_only_the_same_key() {
+        assert!(keys_match("s3cret", "s3cret"));
+        assert!(!keys_match("s3cret", "s3cre"));
+        assert!(!keys_match("s3cret", "s3cret "));
+        assert!(!keys_match("s3cret", ""));
+    }
+}
@@ -0,0 +1,36 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::config::AppConfig;
+
+// Sets Cache-Control from CACHE_CONTROL on successful reads of the configured
+// routes. Mutations always get no-store, whatever the route.
+pub async fn cache_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let read = matches!(*req.method(), Method::GET | Method::HEAD);
+    let mut res = next.call(req).await?;
+
+    let value = if !read {
+        Some(HeaderValue::from_static("no-store"))
+    } else if res.status().is_success() {
+        let pattern = res.request().match_pattern();
+        res.request()
+            .app_data::<web::Data<AppConfig>>()
+            .zip(pattern)
+            .and_then(|(config, pattern)| config.cache_control.get(&pattern).cloned())
+            .and_then(|value| HeaderValue::try_from(value).ok())
+    } else {
+        None
+    };
+
+    if let Some(value) = value {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    Ok(res)
+}
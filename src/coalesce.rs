@@ -0,0 +1,148 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::web::Bytes;
+use actix_web::HttpRequest;
+use chrono::NaiveDateTime;
+use dashmap::DashMap;
+use uuid::Uuid;
+
+use crate::events::{ChangeEvent, ChangeKind};
+use crate::models;
+
+// Users with a response kept at most, expired ones are dropped first when full
+const MAX_COALESCED: usize = 10_000;
+
+struct Recent {
+    key: u64,
+    recorded_at: Instant,
+    // The updated_at of the row the update wrote
+    updated_at: NaiveDateTime,
+    body: Bytes,
+}
+
+// The last successful `/update/{id}` response of each user, for
+// UPDATE_COALESCE_MS. An update identical to the last one, committed within
+// the window, gets the same response again without touching the database.
+// Any other change to the user seen on the change feed forgets it; changes
+// made outside the API, like the stale account check, can be missed for up
+// to the window. Only committed updates are recorded, so duplicates sent
+// while the first is still running are applied again, one after the other.
+#[derive(Clone)]
+pub struct UpdateCoalescer {
+    recent: Arc<DashMap<Uuid, Recent>>,
+    window: Duration,
+}
+
+impl UpdateCoalescer {
+    pub fn new(window: Duration) -> UpdateCoalescer {
+        UpdateCoalescer {
+            recent: Arc::new(DashMap::new()),
+            window,
+        }
+    }
+
+    // Identical updates are the same path and query (the user, and options
+    // like `changed_only` or `uuid_format` that shape the response), the same
+    // If-Unmodified-Since and the same validated changeset
+    pub fn key(req: &HttpRequest, changes: &models::UpdateUser) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        req.path().hash(&mut hasher);
+        req.query_string().hash(&mut hasher);
+        req.headers()
+            .get(actix_web::http::header::IF_UNMODIFIED_SINCE)
+            .map(|value| value.as_bytes())
+            .hash(&mut hasher);
+        (&changes.first_name, &changes.last_name, &changes.email).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn get(&self, user_id: Uuid, key: u64) -> Option<Bytes> {
+        let recent = self.recent.get(&user_id)?;
+        if recent.key == key && recent.recorded_at.elapsed() < self.window {
+            return Some(recent.body.clone());
+        }
+        drop(recent);
+        self.recent.remove(&user_id);
+        None
+    }
+
+    pub fn record(&self, key: u64, user: &models::User, body: Bytes) {
+        if self.recent.len() >= MAX_COALESCED {
+            self.recent.retain(|_, recent| recent.recorded_at.elapsed() < self.window);
+        }
+        if self.recent.len() < MAX_COALESCED || self.recent.contains_key(&user.user_id) {
+            self.recent.insert(
+                user.user_id,
+                Recent {
+                    key,
+                    recorded_at: Instant::now(),
+                    updated_at: user.updated_at,
+                    body,
+                },
+            );
+        }
+    }
+
+    // Forgets a user's response on a change other than the recorded update
+    // itself, which is recognized by its updated_at
+    pub fn observe(&self, event: &ChangeEvent) {
+        self.recent.remove_if(&event.user.user_id, |_, recorded| {
+            event.kind == ChangeKind::Delete || recorded.updated_at != event.user.updated_at
+        });
+    }
+
+    pub fn clear(&self) {
+        self.recent.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use diesel::prelude::*;
+
+    fn updated_at(conn: &mut diesel::PgConnection, of: Uuid) -> NaiveDateTime {
+        use crate::schema::users::dsl::*;
+        users.filter(user_id.eq(of)).select(updated_at).first(conn).unwrap()
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn a_repeated_update_gets_the_first_response() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let config = testing::config(&[("UPDATE_COALESCE_MS", "60000")]);
+        let coalescer = UpdateCoalescer::new(Duration::from_secs(60));
+        let update = |last_name: &str| {
+            TestRequest::post()
+                .uri(&format!("/update/{}", ada.user_id))
+                .set_json(serde_json::json!({"last_name": last_name}))
+        };
+        let send = |req| testing::call_with(&pool, &config, Some(&coalescer), req);
+
+        let first = send(update("Byron")).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().get("X-Coalesced").is_none());
+        let first_body = actix_web::test::read_body(first).await;
+        let written_at = updated_at(&mut conn, ada.user_id);
+
+        let second = send(update("Byron")).await;
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers().get("X-Coalesced").unwrap(), "true");
+        assert_eq!(actix_web::test::read_body(second).await, first_body);
+        assert_eq!(updated_at(&mut conn, ada.user_id), written_at);
+
+        let different = send(update("King")).await;
+        assert!(different.headers().get("X-Coalesced").is_none());
+        assert_ne!(updated_at(&mut conn, ada.user_id), written_at);
+    }
+}
@@ -0,0 +1,78 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::config::AppConfig;
+
+// Runs inside `Compress`, which leaves alone responses that already have a
+// Content-Encoding. Marking bodies under COMPRESS_MIN_BYTES as identity
+// keeps the small CRUD responses uncompressed, where compressing costs more
+// CPU than it saves bytes. Streamed bodies have no size and are compressed.
+// The marker is removed again by `strip_identity`, outside of `Compress`.
+pub async fn skip_small(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+
+    let min = res
+        .request()
+        .app_data::<web::Data<AppConfig>>()
+        .and_then(|config| config.compress_min_bytes)
+        .unwrap_or(0);
+    if let BodySize::Sized(size) = res.response().body().size() {
+        if size < min && !res.headers().contains_key(header::CONTENT_ENCODING) {
+            res.headers_mut()
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+        }
+    }
+
+    Ok(res)
+}
+
+// RFC 9110 reserves identity for Accept-Encoding, it must not be sent as a
+// Content-Encoding. Removes the marker `skip_small` left for `Compress`.
+pub async fn strip_identity(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+
+    if res.headers().get(header::CONTENT_ENCODING) == Some(&HeaderValue::from_static("identity")) {
+        res.headers_mut().remove(header::CONTENT_ENCODING);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn only_responses_over_the_threshold_are_compressed() {
+        let config = crate::testing::config(&[("COMPRESS_MIN_BYTES", "1024")]);
+        let app = App::new()
+            .app_data(web::Data::new(config.clone()))
+            .route("/small", web::get().to(|| async { HttpResponse::Ok().body("x".repeat(100)) }))
+            .route("/large", web::get().to(|| async { HttpResponse::Ok().body("x".repeat(4096)) }));
+        let app = test::init_service(crate::with_middleware(app, &config)).await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                .to_request()
+        };
+
+        let small = test::call_service(&app, get("/small")).await;
+        assert!(!small.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(test::read_body(small).await.len(), 100);
+
+        let large = test::call_service(&app, get("/large")).await;
+        assert_eq!(large.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert!(test::read_body(large).await.len() < 4096);
+    }
+}
@@ -0,0 +1,82 @@
+use actix_web::http::header::HeaderMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::user_error::UserError;
+
+// Checks the body against the optional Content-MD5 (RFC 1864) and Digest
+// (RFC 3230) headers. Digest algorithms other than md5 and sha-256 are
+// ignored; any supported one that doesn't match fails the request.
+pub fn verify_body(headers: &HeaderMap, body: &[u8]) -> Result<(), UserError> {
+    if let Some(expected) = headers.get("Content-MD5") {
+        let expected = expected.to_str().map_err(|_| invalid_header("Content-MD5"))?;
+        check("Content-MD5", expected.trim(), &Md5::digest(body))?;
+    }
+
+    if let Some(digest) = headers.get("Digest") {
+        let digest = digest.to_str().map_err(|_| invalid_header("Digest"))?;
+
+        for entry in digest.split(',') {
+            let (algorithm, expected) = entry.split_once('=').ok_or_else(|| invalid_header("Digest"))?;
+
+            match algorithm.trim().to_lowercase().as_str() {
+                "md5" => check("Digest", expected.trim(), &Md5::digest(body))?,
+                "sha-256" => check("Digest", expected.trim(), &Sha256::digest(body))?,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check(header: &str, expected: &str, actual: &[u8]) -> Result<(), UserError> {
+    let expected = STANDARD.decode(expected).map_err(|_| invalid_header(header))?;
+    if expected != actual {
+        return Err(UserError::BadRequest(format!("body does not match the {} header", header)));
+    }
+    Ok(())
+}
+
+fn invalid_header(header: &str) -> UserError {
+    UserError::BadRequest(format!("invalid {} header", header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    const BODY: &[u8] = br#"{"first_name":"Ada"}"#;
+    const MD5: &str = "khAP5ZBIdt4kFlZwgH+hIQ==";
+    const SHA_256: &str = "x2HA4jpvmFo0AGQ4bnYwul61c+RDsV1Lt+B1/KI1sik=";
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn matching_digests_pass() {
+        assert!(verify_body(&HeaderMap::new(), BODY).is_ok());
+        assert!(verify_body(&headers("content-md5", MD5), BODY).is_ok());
+        let digest = format!("SHA-256={}, md5={}, unixsum=30637", SHA_256, MD5);
+        assert!(verify_body(&headers("digest", &digest), BODY).is_ok());
+    }
+
+    #[test]
+    fn mismatched_digests_are_bad_requests() {
+        let corrupted = br#"{"first_name":"Adb"}"#;
+        let md5 = verify_body(&headers("content-md5", MD5), corrupted);
+        assert!(matches!(md5, Err(UserError::BadRequest(message)) if message.contains("Content-MD5")));
+        let sha = verify_body(&headers("digest", &format!("sha-256={}", SHA_256)), corrupted);
+        assert!(matches!(sha, Err(UserError::BadRequest(message)) if message.contains("Digest")));
+
+        let garbage = verify_body(&headers("digest", "sha-256"), BODY);
+        let expected = "invalid Digest header";
+        assert!(matches!(garbage, Err(UserError::BadRequest(message)) if message == expected));
+    }
+}
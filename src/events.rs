@@ -0,0 +1,63 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::models::User;
+
+// Events a subscriber can lag behind by before it is told it lagged
+const FEED_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Upsert,
+    Delete,
+}
+
+// A committed change to a user, with the row as it was written
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub user: User,
+}
+
+impl ChangeEvent {
+    pub fn upsert(user: User) -> ChangeEvent {
+        ChangeEvent {
+            kind: ChangeKind::Upsert,
+            user,
+        }
+    }
+
+    pub fn delete(user: User) -> ChangeEvent {
+        ChangeEvent {
+            kind: ChangeKind::Delete,
+            user,
+        }
+    }
+}
+
+// Broadcasts changes made through the API once their transaction commits.
+// Changes made directly in the database or by background tasks aren't seen.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> ChangeFeed {
+        ChangeFeed {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+}
+
+impl ChangeFeed {
+    pub fn publish(&self, event: ChangeEvent) {
+        // No subscribers is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -0,0 +1,70 @@
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::sql_types::Text;
+
+use crate::models;
+
+// `EXPLAIN <options> <query>`, yielding one plan line per row, or the whole
+// plan in one row with FORMAT JSON
+struct Explain<Q> {
+    options: &'static str,
+    query: Q,
+}
+
+impl<Q> QueryId for Explain<Q> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Explain<Q> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN ");
+        out.push_sql(self.options);
+        self.query.walk_ast(out.reborrow())
+    }
+}
+
+impl<Q> Query for Explain<Q> {
+    type SqlType = Text;
+}
+
+impl<Q> RunQueryDsl<PgConnection> for Explain<Q> {}
+
+// The SQL Diesel generates for `query` with its binds, and the plan Postgres
+// picks for it when `with_plan` is set
+pub fn explain<Q>(
+    conn: &mut PgConnection,
+    query: Q,
+    with_plan: bool,
+) -> QueryResult<models::QueryExplain>
+where
+    Q: QueryFragment<Pg>,
+{
+    let sql = diesel::debug_query::<Pg, _>(&query).to_string();
+    let plan = if with_plan {
+        Some(Explain { options: "", query }.load::<String>(conn)?)
+    } else {
+        None
+    };
+
+    Ok(models::QueryExplain { sql, plan })
+}
+
+// The planner's row estimate for `query`, from table statistics instead of
+// running it. Only as fresh as the last ANALYZE.
+pub fn estimate_rows<Q>(conn: &mut PgConnection, query: Q) -> QueryResult<i64>
+where
+    Q: QueryFragment<Pg>,
+{
+    let plan = Explain {
+        options: "(FORMAT JSON) ",
+        query,
+    }
+    .get_result::<String>(conn)?;
+
+    let plan: serde_json::Value = serde_json::from_str(&plan)
+        .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?;
+
+    Ok(plan[0]["Plan"]["Plan Rows"].as_f64().unwrap_or_default() as i64)
+}
@@ -1,7 +1,37 @@
-use crate::{models, user_error::UserError, DbPool};
-use actix_web::{web, HttpResponse, Responder};
+use crate::{
+    admin,
+    coalesce::UpdateCoalescer,
+    config::{AppConfig, EmptyUpdatePolicy, ValidationErrors, ValidationOrder},
+    events::{ChangeEvent, ChangeFeed, ChangeKind},
+    explain,
+    jobs::JobStore,
+    json::Json,
+    metrics::POOL_METRICS,
+    models, mx,
+    readiness::Readiness,
+    retention,
+    tasks::Background,
+    tx::{retry_on_conflict, rolled_back, TxConn},
+    uniqueness::{
+        ensure_unique, find_conflicting_emails, find_duplicate, rules_conflict, UniquenessRules,
+    },
+    user_error::{Throttle, UserError},
+    validation, vcard, DbPool,
+};
+use actix_web::http::header::{self, ContentType, EntityTag, Header, IfNoneMatch, IfUnmodifiedSince};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError};
 use chrono::prelude::*;
 use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::sql_types::{Bool, Integer, Jsonb, Nullable, Text, Timestamp};
+use futures_util::{stream, StreamExt};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
 pub async fn health_checker() -> impl Responder {
@@ -10,149 +40,4217 @@ pub async fn health_checker() -> impl Responder {
         status: "OK".to_string(),
         message: "Working".to_string(),
         data: None,
+        warnings: Vec::new(),
     };
     HttpResponse::Ok().json(response)
 }
 
-fn get_conn_from_db(
-    pool: web::Data<diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<PgConnection>>>,
-) -> diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<PgConnection>> {
-    let conn = pool
-        .get()
-        .expect("Error getting a connection from the pool");
-    conn
+pub async fn healthz(pool: web::Data<DbPool>) -> impl Responder {
+    let state = pool.state();
+
+    let response = models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Pool status".to_string(),
+        data: Some(models::PoolHealth {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_size: pool.max_size(),
+            wait: POOL_METRICS.summary(),
+        }),
+        warnings: Vec::new(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn readyz(readiness: web::Data<Readiness>) -> impl Responder {
+    let problems = readiness.problems();
+
+    let response = models::GenericResponse {
+        status: if problems.is_empty() { "OK" } else { "NOT_READY" }.to_string(),
+        message: if problems.is_empty() { "Ready" } else { "Not ready" }.to_string(),
+        data: Some(problems),
+        warnings: Vec::new(),
+    };
+
+    if readiness.is_ready() {
+        HttpResponse::Ok().json(response)
+    } else {
+        HttpResponse::ServiceUnavailable().json(response)
+    }
+}
+
+diesel::define_sql_function! {
+    fn char_length(value: Text) -> diesel::sql_types::Integer;
+}
+
+diesel::define_sql_function! {
+    fn float8(value: diesel::sql_types::Integer) -> diesel::sql_types::Double;
+}
+
+// A filter on the users table usable with boxed queries
+pub(crate) type UserPredicate = Box<
+    dyn diesel::expression::BoxableExpression<
+        crate::schema::users::table,
+        diesel::pg::Pg,
+        SqlType = Bool,
+    >,
+>;
+
+// A connection from the pool. Running out of `get_timeout` while every
+// connection is busy is a 503 asking to retry once a get could have succeeded.
+pub(crate) fn get_conn_from_db(
+    pool: web::Data<DbPool>,
+) -> Result<diesel::r2d2::PooledConnection<diesel::r2d2::ConnectionManager<PgConnection>>, UserError>
+{
+    let started = Instant::now();
+
+    match pool.get() {
+        Ok(conn) => {
+            POOL_METRICS.record_wait(started.elapsed());
+            Ok(conn)
+        }
+        Err(e) => {
+            POOL_METRICS.record_timeout();
+            log::warn!("Error getting a connection from the pool: {}", e);
+            Err(UserError::PoolExhausted(Throttle {
+                limit: pool.max_size() as usize,
+                remaining: 0,
+                retry_after_secs: pool.get_timeout().as_secs().max(1),
+            }))
+        }
+    }
+}
+
+// Write paths shared by the single-user handlers and batch operations
+
+// These run inside a transaction, see `uniqueness::ensure_unique`
+
+// Inserts tried before giving up when the generated user_id is taken
+const USER_ID_ATTEMPTS: u32 = 3;
+const USER_ID_CONSTRAINT: &str = "users_user_id_key";
+
+// A new user and the ones removed to make room for it, see
+// `retention::trim_to_cap`. Both are for the change feed once committed.
+#[derive(Debug)]
+pub(crate) struct Inserted {
+    pub user: models::User,
+    pub trimmed: Vec<models::User>,
+}
+
+impl Inserted {
+    pub fn events(&self) -> impl Iterator<Item = ChangeEvent> + '_ {
+        std::iter::once(ChangeEvent::upsert(self.user.clone()))
+            .chain(self.trimmed.iter().cloned().map(ChangeEvent::delete))
+    }
+}
+
+pub(crate) fn insert_user(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    form: models::NewUser,
+    creator: Option<String>,
+) -> Result<Inserted, UserError> {
+    insert_user_with(conn, rules, form, creator, Uuid::new_v4)
+}
+
+// `insert_user` taking its user_ids from `new_user_id`
+fn insert_user_with(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    form: models::NewUser,
+    creator: Option<String>,
+    mut new_user_id: impl FnMut() -> Uuid,
+) -> Result<Inserted, UserError> {
+    ensure_unique(conn, rules, &form.first_name, &form.last_name, &form.email, None)?;
+
+    use crate::schema::users::dsl::*;
+
+    let mut new_user = models::Users {
+        id: None,
+        user_id: new_user_id(),
+        first_name: form.first_name,
+        last_name: form.last_name,
+        email: form.email,
+        created_by: creator,
+    };
+
+    // A user_id collision retries with a fresh one. Each attempt runs in a
+    // savepoint so the failed insert doesn't abort the caller's transaction.
+    for attempt in 1..=USER_ID_ATTEMPTS {
+        let inserted =
+            conn.transaction(|conn| diesel::insert_into(users).values(&new_user).get_result(conn));
+
+        match inserted {
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info))
+                if info.constraint_name() == Some(USER_ID_CONSTRAINT) =>
+            {
+                log::warn!("user_id {} already taken (attempt {})", new_user.user_id, attempt);
+                new_user.user_id = new_user_id();
+            }
+            result => {
+                let user = result?;
+                let trimmed = retention::trim_to_cap(conn)?;
+                return Ok(Inserted { user, trimmed });
+            }
+        }
+    }
+
+    Err(UserError::AddingUser)
+}
+
+#[derive(QueryableByName)]
+struct InsertedId {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+}
+
+// `insert_user` that inserts nothing and returns None when the user would
+// duplicate another one under `rules`. The insert yields to the policy's
+// unique index with ON CONFLICT DO NOTHING, so Postgres makes it wait for a
+// concurrent insert of the same user rather than race it, and the duplicate
+// is committed by the time the caller looks it up.
+fn insert_unless_duplicate(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    form: &models::NewUser,
+) -> Result<Option<Inserted>, UserError> {
+    let query = format!(
+        "INSERT INTO users (user_id, first_name, last_name, email) VALUES ($1, $2, $3, $4) \
+         ON CONFLICT {} DO NOTHING RETURNING id",
+        rules.conflict_target()
+    );
+
+    // As in `insert_user_with`, a user_id collision retries with a fresh one
+    for attempt in 1..=USER_ID_ATTEMPTS {
+        let new_user_id = Uuid::new_v4();
+        let inserted = conn.transaction(|conn| {
+            diesel::sql_query(query.clone())
+                .bind::<diesel::sql_types::Uuid, _>(new_user_id)
+                .bind::<Text, _>(form.first_name.clone())
+                .bind::<Text, _>(form.last_name.clone())
+                .bind::<Text, _>(form.email.clone())
+                .get_result::<InsertedId>(conn)
+                .optional()
+        });
+
+        match inserted {
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info))
+                if info.constraint_name() == Some(USER_ID_CONSTRAINT) =>
+            {
+                log::warn!("user_id {} already taken (attempt {})", new_user_id, attempt);
+            }
+            result => {
+                let Some(inserted) = result? else { return Ok(None) };
+                use crate::schema::users::dsl::*;
+                let user = users.filter(id.eq(inserted.id)).first::<models::User>(conn)?;
+                let trimmed = retention::trim_to_cap(conn)?;
+                return Ok(Some(Inserted { user, trimmed }));
+            }
+        }
+    }
+
+    Err(UserError::AddingUser)
+}
+
+// Returns the user after the update, or None if there is no active user with
+// that id. Only the provided fields are changed; an empty changeset is a no-op.
+pub(crate) fn update_active_user(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    parsed_user_id: Uuid,
+    changes: &models::UpdateUser,
+) -> Result<Option<models::User>, UserError> {
+    use crate::schema::users::dsl::*;
+
+    let target = users.filter(user_id.eq(parsed_user_id)).filter(deleted_at.is_null());
+
+    let current = match target.first::<models::User>(conn).optional()? {
+        Some(current) => current,
+        None => return Ok(None),
+    };
+
+    if !changes.has_changes() {
+        return Ok(Some(current));
+    }
+
+    ensure_changes_unique(conn, rules, &current, changes)?;
+
+    Ok(Some(diesel::update(target).set(changes).get_result(conn)?))
+}
+
+// `ensure_unique` for `current` with `changes` applied
+fn ensure_changes_unique(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    current: &models::User,
+    changes: &models::UpdateUser,
+) -> Result<(), UserError> {
+    ensure_unique(
+        conn,
+        rules,
+        changes.first_name.as_deref().unwrap_or(&current.first_name),
+        changes.last_name.as_deref().unwrap_or(&current.last_name),
+        changes.email.as_deref().unwrap_or(&current.email),
+        Some(current.user_id),
+    )
+}
+
+// Soft delete, the row is kept as a tombstone for delta sync
+pub(crate) fn soft_delete_user(
+    conn: &mut PgConnection,
+    parsed_user_id: Uuid,
+) -> QueryResult<Option<models::User>> {
+    use crate::schema::users::dsl::*;
+
+    diesel::update(users.filter(user_id.eq(parsed_user_id)).filter(deleted_at.is_null()))
+        .set(deleted_at.eq(diesel::dsl::now))
+        .get_result(conn)
+        .optional()
+}
+
+// Query parameters get_users understands, checked with STRICT_QUERY
+const GET_USERS_PARAMS: &[&str] = &[
+    "page",
+    "per_page",
+    "with_total",
+    "estimate_total",
+    "explain",
+    "plan",
+    "sort",
+    "name",
+    "check_deliverable",
+    "meta.*",
+    "uuid_format",
+];
+
+// Escapes `%` and `_` so they are matched literally by LIKE
+fn like_escape(search: &str) -> String {
+    search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
 }
 
-pub async fn get_users(pool: web::Data<DbPool>) -> Result<HttpResponse, UserError> {
+fn display_name_contains(search: &str) -> UserPredicate {
+    use crate::schema::users::dsl::*;
+
+    Box::new(display_name.ilike(format!("%{}%", like_escape(search))))
+}
+
+// How well a user matches `/users/search`, higher first: an exact match
+// ranks over a prefix, a prefix over a substring, and within each a name
+// (first, last or full) over the email.
+fn search_relevance(
+    search: &str,
+) -> Box<dyn BoxableExpression<crate::schema::users::table, Pg, SqlType = Integer>> {
+    let exact = search.to_lowercase();
+    let prefix = format!("{}%", like_escape(&exact));
+    let contains = format!("%{}%", like_escape(&exact));
+
+    // The patterns are bound once in a subquery, diesel numbers every bind
+    Box::new(
+        diesel::dsl::sql::<Integer>(
+            "(SELECT CASE \
+             WHEN lower(first_name) = exact OR lower(last_name) = exact \
+                OR lower(display_name) = exact THEN 6 \
+             WHEN lower(email) = exact THEN 5 \
+             WHEN lower(first_name) LIKE prefix OR lower(last_name) LIKE prefix \
+                OR lower(display_name) LIKE prefix THEN 4 \
+             WHEN lower(email) LIKE prefix THEN 3 \
+             WHEN lower(display_name) LIKE contains THEN 2 \
+             ELSE 1 END FROM (SELECT ",
+        )
+        .bind::<Text, _>(exact)
+        .sql(" AS exact, ")
+        .bind::<Text, _>(prefix)
+        .sql(" AS prefix, ")
+        .bind::<Text, _>(contains)
+        .sql(" AS contains) AS search)"),
+    )
+}
+
+// Users whose metadata has every key with the given value. Values are
+// compared as text with `->>`, so `meta.age=30` matches both 30 and "30". The
+// `?` key check is what lets the GIN index narrow the rows first.
+fn metadata_matches(filters: &[(String, String)]) -> UserPredicate {
+    filters
+        .iter()
+        .fold(Box::new(diesel::dsl::sql::<Bool>("TRUE")), |predicate, (key, value)| {
+            let matches = diesel::dsl::sql::<Bool>("(metadata ? ")
+                .bind::<Text, _>(key.clone())
+                .sql(" AND metadata ->> ")
+                .bind::<Text, _>(key.clone())
+                .sql(" = ")
+                .bind::<Text, _>(value.clone())
+                .sql(")");
+            Box::new(predicate.and(matches))
+        })
+}
+
+pub async fn get_users(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    pagination: web::Query<models::Pagination>,
+    estimate: web::Query<models::EstimateQuery>,
+    explain: web::Query<models::ExplainQuery>,
+    list: web::Query<models::UserListQuery>,
+) -> Result<HttpResponse, UserError> {
+    if config.strict_query {
+        validation::reject_unknown_params(req.query_string(), GET_USERS_PARAMS)?;
+    }
+    let resolver = deliverability_resolver(&req, list.check_deliverable)?;
+
+    let explain_requested = explain.explain.unwrap_or(false);
+    if explain_requested && !config.allow_explain {
+        return Err(UserError::BadRequest("explain is not enabled".to_string()));
+    }
+    let with_plan = explain.plan.unwrap_or(false);
+    let first_page_only = config.total_on_first_page_only;
+    let estimate_total = estimate.estimate_total.unwrap_or(false);
+    let meta_filters = validation::parse_meta_filters(req.query_string())?;
+    let range = validation::parse_items_range(
+        req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()),
+    )?;
+    let sort = list.sort.unwrap_or_default();
+    let name_search = list.name.clone().filter(|search| !search.trim().is_empty());
+    let matches = move || -> UserPredicate {
+        let predicate = metadata_matches(&meta_filters);
+        match &name_search {
+            Some(search) => Box::new(predicate.and(display_name_contains(search.trim()))),
+            None => predicate,
+        }
+    };
+
     let user_result = web::block(move || {
-        let mut conn = get_conn_from_db(pool);
+        let mut conn = get_conn_from_db(pool)?;
 
         use crate::schema::users::dsl::*;
 
-        let user = users.load::<models::User>(&mut conn);
+        // A closure since the boxed filters can't be cloned for explain
+        let query = |limit: i64, skip: i64| {
+            let query = users
+                .into_boxed()
+                .filter(deleted_at.is_null())
+                .filter(matches())
+                .limit(limit)
+                .offset(skip);
+            match sort {
+                models::UserSort::Id => query.order(id.asc()),
+                models::UserSort::DisplayName => query.order((display_name.asc(), id.asc())),
+            }
+        };
+
+        // Ranges always count exactly, Content-Range needs the total. At most
+        // MAX_PER_PAGE items are served, Content-Range tells what was.
+        if let Some((first, last)) = range {
+            let total = users
+                .filter(deleted_at.is_null())
+                .filter(matches())
+                .count()
+                .get_result::<i64>(&mut conn)?;
+            if first >= total {
+                return Err(UserError::RangeNotSatisfiable(Some(total)));
+            }
+            let last = last.unwrap_or(i64::MAX).min(first + models::MAX_PER_PAGE - 1);
+            let items = query(last - first + 1, first).load::<models::User>(&mut conn)?;
+            return Ok(Listing::Range(models::ItemsRange { items, first, total }));
+        }
+
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| {
+                let active = users
+                    .filter(deleted_at.is_null())
+                    .filter(matches());
+                if estimate_total {
+                    explain::estimate_rows(&mut conn, active.select(id))
+                } else {
+                    active.count().get_result::<i64>(&mut conn)
+                }
+            })
+            .transpose()?;
+
+        let items = query(pagination.per_page(), pagination.offset())
+            .load::<models::User>(&mut conn)?;
+
+        let mut page = models::Paginated::new(items, &pagination, total);
+        page.total_estimated = estimate_total && total.is_some();
+        if explain_requested {
+            let query = query(pagination.per_page(), pagination.offset());
+            page.explain = Some(explain::explain(&mut conn, query, with_plan)?);
+        }
 
-        user
+        Ok(Listing::Page(page))
     })
     .await
-    .map_err(|_| UserError::NotFound)?;
+    .map_err(UserError::from)?;
 
     match user_result {
-        Ok(users_list) => Ok(HttpResponse::Ok().json(models::GenericResponse {
-            status: "OK".to_string(),
-            message: "Users Fetched successfully".to_string(),
-            data: Some(users_list),
-        })),
-        Err(diesel_error) => Err(UserError::from(UserError::DieselError(diesel_error))),
+        Ok(Listing::Page(page)) => {
+            let found = deliverability(&req, &page.items, resolver, &config).await;
+            Ok(HttpResponse::Ok()
+                .insert_header((header::ACCEPT_RANGES, "items"))
+                .json(models::GenericResponse {
+                    status: "OK".to_string(),
+                    message: "Users Fetched successfully".to_string(),
+                    data: Some(page.with_links(&req).map_items(|user| annotate(user, &found))),
+                    warnings: Vec::new(),
+                }))
+        }
+        Ok(Listing::Range(range)) => {
+            let found = deliverability(&req, &range.items, resolver, &config).await;
+            let last = range.first + range.items.len() as i64 - 1;
+            Ok(HttpResponse::PartialContent()
+                .insert_header((
+                    header::CONTENT_RANGE,
+                    format!("items {}-{}/{}", range.first, last, range.total),
+                ))
+                .json(models::GenericResponse {
+                    status: "OK".to_string(),
+                    message: "Users Fetched successfully".to_string(),
+                    data: Some(
+                        range
+                            .items
+                            .into_iter()
+                            .map(|user| annotate(user, &found))
+                            .collect::<Vec<_>>(),
+                    ),
+                    warnings: Vec::new(),
+                }))
+        }
+        Err(e) => Err(e),
     }
 }
 
-pub async fn add_user(
+// The resolver for `?check_deliverable=true`, None without the flag
+fn deliverability_resolver(
+    req: &HttpRequest,
+    check_deliverable: Option<bool>,
+) -> Result<Option<web::Data<TokioAsyncResolver>>, UserError> {
+    if !check_deliverable.unwrap_or(false) {
+        return Ok(None);
+    }
+    match req.app_data::<web::Data<TokioAsyncResolver>>() {
+        Some(resolver) => Ok(Some(resolver.clone())),
+        None => Err(UserError::BadRequest("MX checks need DNS_RESOLVER".to_string())),
+    }
+}
+
+// Whether the email domains of `found_users` have MX records, looked up
+// through the cache and time-boxed to DNS_TIMEOUT_MS, see
+// `mx::lookup_domains`. None without a resolver, i.e. without the flag.
+async fn deliverability(
+    req: &HttpRequest,
+    found_users: &[models::User],
+    resolver: Option<web::Data<TokioAsyncResolver>>,
+    config: &AppConfig,
+) -> Option<HashMap<String, bool>> {
+    let resolver = resolver?;
+    let cache = req.app_data::<web::Data<mx::MxCache>>()?;
+    let domains = found_users
+        .iter()
+        .filter_map(|user| validation::email_domain(&user.email))
+        .collect();
+    Some(mx::lookup_domains(cache, &resolver, domains, config.dns_timeout()).await)
+}
+
+// An email without a valid domain can't be delivered to, a domain missing
+// from `found` is unknown
+fn annotate(user: models::User, found: &Option<HashMap<String, bool>>) -> models::AnnotatedUser {
+    let email_deliverable = found.as_ref().map(|found| match validation::email_domain(&user.email) {
+        Some(domain) => found.get(&domain).copied(),
+        None => Some(false),
+    });
+    models::AnnotatedUser {
+        user,
+        email_deliverable,
+    }
+}
+
+enum Listing {
+    Page(models::Paginated<models::User>),
+    // With a `Range: items=` header
+    Range(models::ItemsRange),
+}
+
+// Active users whose name or email contains `q`, most relevant first, see
+// `search_relevance`. Ties are in id order.
+pub async fn search_users(
+    req: HttpRequest,
     pool: web::Data<DbPool>,
-    form: web::Json<models::NewUser>,
+    config: web::Data<AppConfig>,
+    pagination: web::Query<models::Pagination>,
+    search: web::Query<models::SearchQuery>,
 ) -> Result<HttpResponse, UserError> {
+    let q = search.q.trim().to_string();
+    if q.is_empty() {
+        return Err(UserError::BadRequest("q must not be empty".to_string()));
+    }
+    let first_page_only = config.total_on_first_page_only;
+
     let user_result = web::block(move || {
-        let mut conn = get_conn_from_db(pool);
+        let mut conn = get_conn_from_db(pool)?;
 
         use crate::schema::users::dsl::*;
 
-        let new_user = models::Users {
-            id: None,
-            user_id: Uuid::new_v4(),
-            first_name: form.first_name.to_string(),
-            last_name: form.last_name.to_string(),
-            email: form.email.to_string(),
-            created_at: Local::now().naive_local(),
+        let matches = || {
+            let contains = format!("%{}%", like_escape(&q));
+            users
+                .into_boxed()
+                .filter(deleted_at.is_null())
+                .filter(display_name.ilike(contains.clone()).or(email.ilike(contains)))
         };
 
-        diesel::insert_into(users)
-            .values(&new_user)
-            .execute(&mut conn)?;
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| matches().count().get_result::<i64>(&mut conn))
+            .transpose()?;
 
-        users
-            .order(id.desc())
-            .limit(1)
-            .load::<models::User>(&mut conn)
+        let items = matches()
+            .order((search_relevance(&q).desc(), id.asc()))
+            .limit(pagination.per_page())
+            .offset(pagination.offset())
+            .load::<models::User>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
     })
     .await
-    .map_err(|_| UserError::AddingUser)?;
+    .map_err(UserError::from)?;
 
     match user_result {
-        Ok(users_list) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
             status: "OK".to_string(),
-            message: "Users added successfully".to_string(),
-            data: Some(users_list),
+            message: "Users Fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
         })),
-        Err(diesel_error) => Err(UserError::from(UserError::DieselError(diesel_error))),
+        Err(e) => Err(e),
+    }
+}
+
+// The failures of input checked both for its format and uniqueness, as the
+// single error reported: the first in VALIDATION_ORDER, or with
+// VALIDATION_ERRORS=all every one of them. Other errors of the uniqueness
+// check aren't failures of the input and are returned as they are.
+fn report_failures(
+    order: ValidationOrder,
+    errors: ValidationErrors,
+    format: Vec<UserError>,
+    unique: Result<(), UserError>,
+) -> Result<(), UserError> {
+    let conflict = match unique {
+        Ok(()) => None,
+        Err(conflict @ UserError::Conflict(_)) => Some(conflict),
+        Err(e) => return Err(e),
+    };
+    let mut failures: Vec<UserError> = match order {
+        ValidationOrder::FormatFirst => format.into_iter().chain(conflict).collect(),
+        ValidationOrder::UniquenessFirst => conflict.into_iter().chain(format).collect(),
+    };
+
+    match (failures.len(), errors) {
+        (0, _) => Ok(()),
+        (1, _) | (_, ValidationErrors::First) => Err(failures.remove(0)),
+        (_, ValidationErrors::All) => Err(UserError::ValidationMany(failures)),
+    }
+}
+
+// Whether /add and /update/{id} check the format inside the transaction,
+// together with uniqueness, instead of before it
+fn form_checked_in_tx(order: ValidationOrder, errors: ValidationErrors) -> bool {
+    order == ValidationOrder::UniquenessFirst || errors == ValidationErrors::All
+}
+
+pub async fn add_user(
+    req: HttpRequest,
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    form: Json<models::NewUser>,
+) -> Result<HttpResponse, UserError> {
+    let raw = form.into_inner();
+    let order = config.validation_order;
+    let errors = config.validation_errors;
+    // Format failures are only reported up front when no other failure can
+    // come with them
+    let form = match (order, errors) {
+        (ValidationOrder::FormatFirst, ValidationErrors::First) => {
+            validation::validate_new_user(raw.clone())?
+        }
+        _ => validation::lenient_new_user(&raw),
+    };
+    let warnings = validation::email_warnings(&form.email);
+    let rules = config.uniqueness();
+    let creator = admin::principal(&req, &config);
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            if form_checked_in_tx(order, errors) {
+                let (first, last, email) = (&form.first_name, &form.last_name, &form.email);
+                let unique = ensure_unique(conn, rules, first, last, email, None);
+                report_failures(order, errors, validation::new_user_failures(&raw), unique)?;
+            }
+            insert_user(conn, rules, form.clone(), creator.clone())
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(inserted) => {
+            inserted.events().for_each(|event| tx.publish_on_commit(event));
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Users added successfully".to_string(),
+                data: Some(vec![inserted.user]),
+                warnings,
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// 200 with the existing user, or 201 with a new one. The insert does nothing
+// on a conflict with the unique index of UNIQUENESS_POLICY, and the user it
+// conflicts with is selected instead, see `insert_unless_duplicate`.
+pub async fn get_or_create_user(
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    form: Json<models::NewUser>,
+) -> Result<HttpResponse, UserError> {
+    let form = validation::validate_new_user(form.into_inner())?;
+    let rules = config.uniqueness();
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            match insert_unless_duplicate(conn, rules, &form)? {
+                Some(inserted) => Ok((inserted.user, true, inserted.trimmed)),
+                None => {
+                    // A conflict with a deleted user under UNIQUENESS_POLICY=email
+                    // has nothing to return and stays a conflict
+                    let existing = find_duplicate(
+                        conn,
+                        rules,
+                        &form.first_name,
+                        &form.last_name,
+                        &form.email,
+                    )?
+                    .filter(|existing| existing.deleted_at.is_none())
+                    .ok_or_else(|| rules_conflict(rules))?;
+                    Ok((existing, false, Vec::new()))
+                }
+            }
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok((user, created, trimmed)) => {
+            let mut response = if created {
+                tx.publish_on_commit(ChangeEvent::upsert(user.clone()));
+                HttpResponse::Created()
+            } else {
+                HttpResponse::Ok()
+            };
+            trimmed.into_iter().for_each(|user| tx.publish_on_commit(ChangeEvent::delete(user)));
+
+            Ok(response.json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: if created { "User created" } else { "User already exists" }.to_string(),
+                data: Some(user),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// The If-Unmodified-Since header as a UTC timestamp. Invalid dates are
+// ignored, as RFC 7232 requires.
+fn if_unmodified_since(req: &HttpRequest) -> Option<NaiveDateTime> {
+    let header = IfUnmodifiedSince::parse(req).ok()?;
+    let since: std::time::SystemTime = header.0.into();
+    Some(DateTime::<Utc>::from(since).naive_utc())
+}
+
+// Fails with 412 if the active user was modified after `since`. HTTP dates
+// have second precision, so updated_at is truncated before comparing. The row
+// is locked until the surrounding transaction ends so the change that follows
+// applies to the state that was checked.
+fn check_unmodified_since(
+    conn: &mut PgConnection,
+    parsed_user_id: Uuid,
+    since: Option<NaiveDateTime>,
+) -> Result<(), UserError> {
+    let since = match since {
+        Some(since) => since,
+        None => return Ok(()),
+    };
+
+    use crate::schema::users::dsl::*;
+
+    let last_modified = users
+        .filter(user_id.eq(parsed_user_id))
+        .filter(deleted_at.is_null())
+        .select(updated_at)
+        .for_update()
+        .first::<NaiveDateTime>(conn)
+        .optional()?;
+
+    match last_modified {
+        Some(last_modified) if last_modified.with_nanosecond(0).unwrap_or(last_modified) > since => {
+            Err(UserError::PreconditionFailed)
+        }
+        _ => Ok(()),
     }
 }
 
 pub async fn update_user(
-    pool: web::Data<DbPool>,
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    coalescer: Option<web::Data<UpdateCoalescer>>,
     path: web::Path<(String,)>,
-    form: web::Json<models::UpdateUser>,
-) -> impl actix_web::Responder {
-    let user_result = web::block(move || {
-        let parsed_user_id = Uuid::parse_str(&path.into_inner().0).expect("Error parsing user_id");
+    query: web::Query<models::UpdateQuery>,
+    form: Json<models::UpdateUser>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let raw = form.into_inner();
+    let order = config.validation_order;
+    let errors = config.validation_errors;
+    let updated_user = match (order, errors) {
+        (ValidationOrder::FormatFirst, ValidationErrors::First) => {
+            validation::validate_update_user(raw.clone())?
+        }
+        _ => validation::lenient_update_user(&raw),
+    };
+    let warnings = updated_user
+        .email
+        .as_deref()
+        .map(validation::email_warnings)
+        .unwrap_or_default();
+    let rules = config.uniqueness();
+    let unmodified_since = if_unmodified_since(&req);
+    let changed_only = query.changed_only.unwrap_or(false);
+    let has_changes = updated_user.has_changes();
+    if !has_changes && config.empty_update_policy == EmptyUpdatePolicy::Reject {
+        return Err(UserError::BadRequest("no fields to update".to_string()).into());
+    }
+    let row_lock = config.update_row_lock;
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
 
-        let mut conn = get_conn_from_db(pool);
+    let coalesce_key = UpdateCoalescer::key(&req, &updated_user);
+    let coalesced = match (&coalescer, user_ref) {
+        (Some(coalescer), validation::UserRef::UserId(user_id)) => {
+            coalescer.get(user_id, coalesce_key)
+        }
+        _ => None,
+    };
+    if let Some(body) = coalesced {
+        return Ok(HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .insert_header(("X-Coalesced", "true"))
+            .body(body));
+    }
 
-        use crate::schema::users::dsl::*;
+    // Extracted here rather than as an argument so coalesced updates don't
+    // take a connection
+    let tx = TxConn::extract(&req).await?;
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            let parsed_user_id = resolve_user_id(conn, user_ref)?;
 
-        let updated_user = models::UpdateUser {
-            first_name: Some(form.first_name.clone().unwrap_or_default()),
-            last_name: Some(form.last_name.clone().unwrap_or_default()),
-            email: Some(form.email.clone().unwrap_or_default()),
-        };
+            use crate::schema::users::dsl::*;
 
-        diesel::update(users.filter(user_id.eq(parsed_user_id)))
-            .set(&updated_user)
-            .execute(&mut conn)?;
+            // Held until the request's transaction ends, so concurrent updates
+            // of this user apply one after the other, each on top of the last
+            if row_lock {
+                users
+                    .filter(user_id.eq(parsed_user_id))
+                    .select(id)
+                    .for_update()
+                    .first::<i32>(conn)
+                    .optional()?;
+            }
 
-        users
-            .order(id.desc())
-            .limit(1)
-            .load::<models::User>(&mut conn)
-    })
-    .await
-    .map_err(|_| UserError::UpdatingUser)?;
+            if form_checked_in_tx(order, errors) {
+                let current = users
+                    .filter(user_id.eq(parsed_user_id))
+                    .filter(deleted_at.is_null())
+                    .first::<models::User>(conn)
+                    .optional()?;
+                let unique = match (current, updated_user.has_changes()) {
+                    (Some(current), true) => {
+                        ensure_changes_unique(conn, rules, &current, &updated_user)
+                    }
+                    _ => Ok(()),
+                };
+                let format = validation::update_user_failures(&raw);
+                report_failures(order, errors, format, unique)?;
+            }
 
-    match user_result {
-        Ok(users_list) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            check_unmodified_since(conn, parsed_user_id, unmodified_since)?;
+
+            if changed_only {
+                let before = users
+                    .filter(user_id.eq(parsed_user_id))
+                    .filter(deleted_at.is_null())
+                    .first::<models::User>(conn)
+                    .optional()?
+                    .ok_or(UserError::NotFound)?;
+                let after = update_active_user(conn, rules, parsed_user_id, &updated_user)?
+                    .ok_or(UserError::NotFound)?;
+
+                let changed = models::changed_fields(&before, &after);
+                return Ok::<_, UserError>((Some(after), UpdateResult::Changed(changed)));
+            }
+
+            let updated = update_active_user(conn, rules, parsed_user_id, &updated_user)?;
+
+            Ok((
+                updated,
+                UpdateResult::Users(users.order(id.desc()).limit(1).load::<models::User>(conn)?),
+            ))
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    let (updated, result) = user_result?;
+    if let (Some(updated), true) = (&updated, has_changes) {
+        tx.publish_on_commit(ChangeEvent::upsert(updated.clone()));
+    }
+
+    let response = match result {
+        UpdateResult::Users(users_list) => serde_json::to_vec(&models::GenericResponse {
             status: "OK".to_string(),
             message: "Users updated successfully".to_string(),
             data: Some(users_list),
-        })),
-        Err(diesel_error) => Err(UserError::from(UserError::DieselError(diesel_error))),
+            warnings,
+        }),
+        UpdateResult::Changed(changed) => serde_json::to_vec(&models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users updated successfully".to_string(),
+            data: Some(changed),
+            warnings,
+        }),
     }
+    .map(web::Bytes::from)
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    if let (Some(coalescer), Some(updated)) = (coalescer, updated) {
+        let body = response.clone();
+        tx.on_commit(move || coalescer.record(coalesce_key, &updated, body));
+    }
+
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(response))
+}
+
+enum UpdateResult {
+    Users(Vec<models::User>),
+    // With `?changed_only=true`
+    Changed(serde_json::Map<String, serde_json::Value>),
 }
 
 pub async fn delete_user(
-    pool: web::Data<DbPool>,
+    req: HttpRequest,
+    tx: TxConn,
+    config: web::Data<AppConfig>,
     path: web::Path<(String,)>,
 ) -> impl actix_web::Responder {
-    let user_result = web::block(move || {
-        let parsed_user_id = Uuid::parse_str(&path.into_inner().0).expect("Error parsing user_id");
+    let unmodified_since = if_unmodified_since(&req);
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            let parsed_user_id = resolve_user_id(conn, user_ref)?;
+
+            check_unmodified_since(conn, parsed_user_id, unmodified_since)?;
+            let deleted = soft_delete_user(conn, parsed_user_id)?;
+
+            use crate::schema::users::dsl::*;
+
+            Ok::<_, UserError>((
+                deleted,
+                users.order(id.desc()).limit(1).load::<models::User>(conn)?,
+            ))
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    if let Ok((Some(deleted), _)) = &user_result {
+        tx.publish_on_commit(ChangeEvent::delete(deleted.clone()));
+    }
+
+    match user_result.map(|(_, users_list)| users_list) {
+        Ok(users_list) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users Deleted successfully".to_string(),
+            data: Some(users_list),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Undoes a soft delete, possible until the purge after SOFT_DELETE_GRACE_DAYS
+// removes the row. The user must still be unique among the active users.
+pub async fn restore_user(
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+    let rules = config.uniqueness();
 
-        let mut conn = get_conn_from_db(pool);
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            use crate::schema::users::dsl::*;
+
+            let deleted = users
+                .into_boxed()
+                .filter(user_ref_is(user_ref))
+                .filter(deleted_at.is_not_null())
+                .first::<models::User>(conn)
+                .optional()?
+                .ok_or(UserError::NotFound)?;
+            ensure_unique(
+                conn,
+                rules,
+                &deleted.first_name,
+                &deleted.last_name,
+                &deleted.email,
+                Some(deleted.user_id),
+            )?;
+
+            Ok(diesel::update(users.filter(id.eq(deleted.id)))
+                .set(deleted_at.eq(None::<NaiveDateTime>))
+                .get_result::<models::User>(conn)?)
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    if let Ok(restored) = &user_result {
+        tx.publish_on_commit(ChangeEvent::upsert(restored.clone()));
+    }
+
+    match user_result {
+        Ok(restored) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "User restored successfully".to_string(),
+            data: Some(restored),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Default and maximum page size for `/users/changes`
+const CHANGES_DEFAULT_LIMIT: i64 = 100;
+const CHANGES_MAX_LIMIT: i64 = 1000;
+
+pub async fn get_user_changes(
+    pool: web::Data<DbPool>,
+    query: web::Query<models::ChangesQuery>,
+) -> Result<HttpResponse, UserError> {
+    let since = DateTime::parse_from_rfc3339(&query.since)
+        .map_err(|_| UserError::BadRequest("since must be an RFC 3339 timestamp".to_string()))?
+        .naive_utc();
+    let after_id = query.after_id.unwrap_or(i32::MAX);
+    let limit = query
+        .limit
+        .unwrap_or(CHANGES_DEFAULT_LIMIT)
+        .clamp(1, CHANGES_MAX_LIMIT);
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
 
         use crate::schema::users::dsl::*;
-        
-        diesel::delete(users.filter(user_id.eq(parsed_user_id))).execute(&mut conn)?;
 
+        // Keyset on (updated_at, id) so rows sharing a timestamp aren't skipped
+        // between pages. Without `after_id` every row at `since` is excluded.
         users
-            .order(id.desc())
-            .limit(1)
+            .filter(
+                updated_at
+                    .gt(since)
+                    .or(updated_at.eq(since).and(id.gt(after_id))),
+            )
+            .order((updated_at.asc(), id.asc()))
+            .limit(limit)
             .load::<models::User>(&mut conn)
+        .map_err(UserError::from)
     })
     .await
-    .map_err(|_| UserError::DeletingUser)?;
+    .map_err(UserError::from)?;
 
     match user_result {
-        Ok(users_list) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+        Ok(changed) => {
+            let next = if changed.len() as i64 == limit {
+                changed.last().map(|user| models::ChangesCursor {
+                    since: user.updated_at,
+                    after_id: user.id,
+                })
+            } else {
+                None
+            };
+
+            let (deleted, updated): (Vec<_>, Vec<_>) =
+                changed.into_iter().partition(|user| user.deleted_at.is_some());
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Changes fetched successfully".to_string(),
+                data: Some(models::Changes {
+                    updated,
+                    deleted: deleted
+                        .into_iter()
+                        .filter_map(|user| {
+                            user.deleted_at.map(|deleted_at| models::Tombstone {
+                                user_id: user.user_id,
+                                deleted_at,
+                            })
+                        })
+                        .collect(),
+                    next,
+                }),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Pages of `/users/feed`
+const FEED_DEFAULT_LIMIT: i64 = 50;
+const FEED_MAX_LIMIT: i64 = 500;
+
+// Active users oldest first. Keyset on (created_at, id), so users added
+// while a client pages through are picked up on later pages instead of
+// shifting the ones already seen.
+pub async fn get_user_feed(
+    pool: web::Data<DbPool>,
+    query: web::Query<models::FeedQuery>,
+) -> Result<HttpResponse, UserError> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(validation::parse_feed_cursor)
+        .transpose()?;
+    let limit = query.limit.unwrap_or(FEED_DEFAULT_LIMIT).clamp(1, FEED_MAX_LIMIT);
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let mut feed = users
+            .filter(deleted_at.is_null())
+            .order((created_at.asc(), id.asc()))
+            .limit(limit)
+            .into_boxed();
+        if let Some(cursor) = cursor {
+            feed = feed.filter(
+                created_at
+                    .gt(cursor.created_at)
+                    .or(created_at.eq(cursor.created_at).and(id.gt(cursor.id))),
+            );
+        }
+
+        feed.load::<models::User>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(items) => {
+            let next_cursor = if items.len() as i64 == limit {
+                items.last().map(|user| {
+                    models::FeedCursor {
+                        created_at: user.created_at,
+                        id: user.id,
+                    }
+                    .encode()
+                })
+            } else {
+                None
+            };
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Users Fetched successfully".to_string(),
+                data: Some(models::Feed { items, next_cursor }),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Upper bound on operations in a single `/users/batch-ops` request
+const MAX_BATCH_OPS: usize = 500;
+
+// Successful operations shown in a dry run's response
+const DRY_RUN_SAMPLES: usize = 10;
+
+// Applies an ordered list of create/update/delete operations. In the default
+// atomic mode the whole batch runs in one transaction and the first failure
+// rolls everything back. In best_effort mode each operation runs in its own
+// savepoint and failures are reported per operation.
+pub async fn batch_ops(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    feed: web::Data<ChangeFeed>,
+    jobs: web::Data<JobStore>,
+    background: web::Data<Background>,
+    query: web::Query<models::BatchOpsQuery>,
+    ops: Json<Vec<models::BatchOp>>,
+) -> Result<HttpResponse, UserError> {
+    let ops = ops.into_inner();
+    if ops.is_empty() {
+        return Err(UserError::BadRequest("batch must contain at least one operation".to_string()));
+    }
+    if ops.len() > MAX_BATCH_OPS {
+        return Err(UserError::BadRequest(format!(
+            "batch may contain at most {} operations",
+            MAX_BATCH_OPS
+        )));
+    }
+    let settings = BatchSettings {
+        rules: config.uniqueness(),
+        mode: query.mode.unwrap_or_default(),
+        conflict: query.conflict.unwrap_or_default(),
+        retries: config.tx_retries,
+    };
+
+    let dry_run = query.dry_run.unwrap_or(false);
+
+    if query.precheck.unwrap_or(false) {
+        if settings.conflict != models::BatchConflict::Error {
+            return Err(UserError::BadRequest(
+                "precheck can only be combined with conflict=error".to_string(),
+            ));
+        }
+        precheck_batch(pool.clone(), settings.rules, &ops).await?;
+    }
+
+    if query.run_async.unwrap_or(false) {
+        if dry_run {
+            return Err(UserError::BadRequest("dry_run can't be combined with async".to_string()));
+        }
+
+        let job_id = jobs.start(ops.len());
+        background.spawn(async move {
+            let progress = {
+                let jobs = jobs.clone();
+                move |processed| jobs.progress(job_id, processed)
+            };
+            let outcome = apply_batch(pool, settings, ops, false, progress)
+                .await
+                .map(|results| {
+                    publish_batch(&feed, &results);
+                    serde_json::to_value(results).unwrap_or_default()
+                })
+                .map_err(|e| e.to_string());
+            jobs.finish(job_id, outcome);
+        });
+
+        return Ok(HttpResponse::Accepted()
+            .insert_header(("Location", format!("/jobs/{}", job_id)))
+            .json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Batch accepted".to_string(),
+                data: Some(models::JobAccepted { job_id }),
+                warnings: Vec::new(),
+            }));
+    }
+
+    let results = apply_batch(pool, settings, ops, dry_run, |_| {}).await?;
+
+    if dry_run {
+        let would_apply = results.iter().filter(|result| result.ok).count();
+        let samples = results
+            .into_iter()
+            .filter(|result| result.ok)
+            .take(DRY_RUN_SAMPLES)
+            .collect();
+
+        return Ok(HttpResponse::Ok().json(models::GenericResponse {
             status: "OK".to_string(),
-            message: "Users Deleted successfully".to_string(),
-            data: Some(users_list),
-        })),
-        Err(diesel_error) => Err(UserError::from(UserError::DieselError(diesel_error))),
+            message: "Batch dry run, nothing was applied".to_string(),
+            data: Some(models::BatchDryRun { would_apply, samples }),
+            warnings: Vec::new(),
+        }));
     }
-}
\ No newline at end of file
+
+    publish_batch(&feed, &results);
+
+    // In best_effort mode the outcome is per operation, like WebDAV's
+    // multi-status. An atomic batch either applied entirely or failed.
+    let mut response = match settings.mode {
+        models::BatchMode::Atomic => HttpResponse::Ok(),
+        models::BatchMode::BestEffort => HttpResponse::MultiStatus(),
+    };
+    Ok(response.json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: match settings.mode {
+            models::BatchMode::Atomic => "Batch applied successfully",
+            models::BatchMode::BestEffort => "Batch applied, see the status of each operation",
+        }
+        .to_string(),
+        data: Some(results),
+        warnings: Vec::new(),
+    }))
+}
+
+// Fails with a conflict naming the emails of create ops that duplicate
+// each other or an existing user
+async fn precheck_batch(
+    pool: web::Data<DbPool>,
+    rules: UniquenessRules,
+    ops: &[models::BatchOp],
+) -> Result<(), UserError> {
+    let new_users: Vec<(String, String, String)> = ops
+        .iter()
+        .filter_map(|op| match op {
+            models::BatchOp::Create { user } => {
+                Some((user.first_name.clone(), user.last_name.clone(), user.email.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let conflicting = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+        let new_users: Vec<_> = new_users
+            .iter()
+            .map(|(first, last, email)| (first.as_str(), last.as_str(), email.as_str()))
+            .collect();
+
+        find_conflicting_emails(&mut conn, rules, &new_users)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)??;
+
+    if conflicting.is_empty() {
+        return Ok(());
+    }
+    Err(UserError::Conflict(format!(
+        "These emails would duplicate existing users or other operations: {}",
+        conflicting.join(", ")
+    )))
+}
+
+#[derive(Clone, Copy)]
+struct BatchSettings {
+    rules: UniquenessRules,
+    mode: models::BatchMode,
+    conflict: models::BatchConflict,
+    retries: u32,
+}
+
+async fn apply_batch(
+    pool: web::Data<DbPool>,
+    settings: BatchSettings,
+    ops: Vec<models::BatchOp>,
+    dry_run: bool,
+    progress: impl Fn(usize) + Send + 'static,
+) -> Result<Vec<models::BatchOpResult>, UserError> {
+    web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        retry_on_conflict(settings.retries, || {
+            if dry_run {
+                rolled_back(&mut conn, |conn| run_batch(conn, settings, &ops, true, &progress))
+            } else {
+                conn.transaction(|conn| run_batch(conn, settings, &ops, false, &progress))
+            }
+        })
+    })
+    .await
+    .map_err(UserError::from)?
+}
+
+fn publish_batch(feed: &ChangeFeed, results: &[models::BatchOpResult]) {
+    for result in results {
+        if let Some(user) = &result.user {
+            feed.publish(match result.op {
+                "delete" => ChangeEvent::delete(user.clone()),
+                _ => ChangeEvent::upsert(user.clone()),
+            });
+        }
+        for user in &result.trimmed {
+            feed.publish(ChangeEvent::delete(user.clone()));
+        }
+    }
+}
+
+// Applies the operations inside the caller's transaction. With `dry_run`
+// each update and delete also reports the user as it was before. `progress`
+// is called with the number of operations done after each one.
+fn run_batch(
+    conn: &mut PgConnection,
+    settings: BatchSettings,
+    ops: &[models::BatchOp],
+    dry_run: bool,
+    progress: &dyn Fn(usize),
+) -> Result<Vec<models::BatchOpResult>, UserError> {
+    let mut results = Vec::with_capacity(ops.len());
+
+    for (index, op) in ops.iter().cloned().enumerate() {
+        let kind = op.kind();
+        let before = match &op {
+            models::BatchOp::Update { user_id, .. } | models::BatchOp::Delete { user_id }
+                if dry_run =>
+            {
+                use crate::schema::users::dsl as users_dsl;
+
+                users_dsl::users
+                    .filter(users_dsl::user_id.eq(*user_id))
+                    .filter(users_dsl::deleted_at.is_null())
+                    .first::<models::User>(conn)
+                    .optional()?
+            }
+            _ => None,
+        };
+
+        let applied = match settings.mode {
+            models::BatchMode::Atomic => apply_batch_op(conn, settings, op),
+            // A failure rolls back to the savepoint, not the whole batch
+            models::BatchMode::BestEffort => {
+                conn.transaction(|conn| apply_batch_op(conn, settings, op))
+            }
+        };
+
+        match applied {
+            Ok((user, outcome, trimmed)) => results.push(models::BatchOpResult {
+                index,
+                op: kind,
+                ok: true,
+                status: match outcome {
+                    Some(models::CreateOutcome::Inserted) => StatusCode::CREATED.as_u16(),
+                    _ => StatusCode::OK.as_u16(),
+                },
+                before,
+                outcome,
+                user,
+                error: None,
+                trimmed,
+            }),
+            Err(e) if settings.mode == models::BatchMode::Atomic => {
+                return Err(UserError::BatchOperation(index, Box::new(e)));
+            }
+            Err(e) => results.push(models::BatchOpResult {
+                index,
+                op: kind,
+                ok: false,
+                status: e.status_code().as_u16(),
+                before,
+                outcome: None,
+                user: None,
+                error: Some(e.to_string()),
+                trimmed: Vec::new(),
+            }),
+        }
+        progress(index + 1);
+    }
+
+    Ok(results)
+}
+
+// The written user, None for a skipped create, how a create went and the
+// users an insert trimmed, see `retention::trim_to_cap`
+type BatchOpApplied = (Option<models::User>, Option<models::CreateOutcome>, Vec<models::User>);
+
+fn apply_batch_op(
+    conn: &mut PgConnection,
+    settings: BatchSettings,
+    op: models::BatchOp,
+) -> Result<BatchOpApplied, UserError> {
+    let rules = settings.rules;
+
+    match op {
+        models::BatchOp::Create { user } => {
+            let user = validation::validate_new_user(user)?;
+            match (insert_user(conn, rules, user.clone(), None), settings.conflict) {
+                (Ok(inserted), _) => Ok((
+                    Some(inserted.user),
+                    Some(models::CreateOutcome::Inserted),
+                    inserted.trimmed,
+                )),
+                (Err(UserError::Conflict(_)), models::BatchConflict::Skip) => {
+                    Ok((None, Some(models::CreateOutcome::Skipped), Vec::new()))
+                }
+                (Err(UserError::Conflict(message)), models::BatchConflict::Update) => {
+                    // A conflict with a deleted user under UNIQUENESS_POLICY=email
+                    // has no active user to update and stays a conflict
+                    let existing = find_duplicate(
+                        conn,
+                        rules,
+                        &user.first_name,
+                        &user.last_name,
+                        &user.email,
+                    )?
+                    .filter(|existing| existing.deleted_at.is_none())
+                    .ok_or(UserError::Conflict(message))?;
+                    let changes = models::UpdateUser {
+                        first_name: Some(user.first_name),
+                        last_name: Some(user.last_name),
+                        email: None,
+                    };
+                    let updated = update_active_user(conn, rules, existing.user_id, &changes)?;
+                    Ok((updated, Some(models::CreateOutcome::Updated), Vec::new()))
+                }
+                (Err(e), _) => Err(e),
+            }
+        }
+        models::BatchOp::Update { user_id, changes } => {
+            let changes = validation::validate_update_user(changes)?;
+            let updated =
+                update_active_user(conn, rules, user_id, &changes)?.ok_or(UserError::NotFound)?;
+            Ok((Some(updated), None, Vec::new()))
+        }
+        models::BatchOp::Delete { user_id } => {
+            let deleted = soft_delete_user(conn, user_id)?.ok_or(UserError::NotFound)?;
+            Ok((Some(deleted), None, Vec::new()))
+        }
+    }
+}
+
+// Active users holding `address`, compared the way the uniqueness check
+// compares emails
+fn active_with_email(
+    rules: UniquenessRules,
+    address: &str,
+) -> crate::schema::users::BoxedQuery<'static, Pg> {
+    use crate::schema::users::dsl::*;
+
+    let query = users.into_boxed().filter(deleted_at.is_null());
+    if rules.case_insensitive_email {
+        query.filter(email_normalized.eq(address.to_lowercase()))
+    } else {
+        query.filter(email.eq(address.to_string()))
+    }
+}
+
+pub(crate) fn find_active_by_email(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    address: &str,
+) -> QueryResult<Option<models::User>> {
+    active_with_email(rules, address).first::<models::User>(conn).optional()
+}
+
+// Natural keys `/users/lookup` accepts
+const LOOKUP_KEYS: &[&str] = &["email"];
+
+// The single active user with `value` as its `key`. Under
+// UNIQUENESS_POLICY=name_email an email can belong to several users, which
+// is a 409 rather than an arbitrary pick.
+pub async fn lookup_user(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    query: web::Query<models::LookupQuery>,
+) -> Result<HttpResponse, UserError> {
+    if !LOOKUP_KEYS.contains(&query.key.as_str()) {
+        return Err(UserError::BadRequest(format!(
+            "key must be one of {}",
+            LOOKUP_KEYS.join(", ")
+        )));
+    }
+    let rules = config.uniqueness();
+    let value = validation::normalize_email(&query.value);
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        active_with_email(rules, &value)
+            .limit(2)
+            .load::<models::User>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(mut found) if found.len() == 1 => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "User Fetched successfully".to_string(),
+            data: found.pop(),
+            warnings: Vec::new(),
+        })),
+        Ok(found) if found.is_empty() => Err(UserError::NotFound),
+        Ok(_) => Err(UserError::Conflict("More than one user has this email".to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+// `/users/nearest-email` defaults and limits. fuzzystrmatch refuses
+// arguments over 255 characters.
+const DEFAULT_NEAREST_DISTANCE: i32 = 2;
+const MAX_NEAREST_DISTANCE: i32 = 5;
+const MAX_NEAREST_EMAIL_CHARS: usize = 255;
+
+// From the fuzzystrmatch extension
+diesel::define_sql_function! {
+    fn levenshtein_less_equal(source: Text, target: Text, max_d: Integer) -> Integer;
+}
+
+// Whether `levenshtein_less_equal` resolves on the search path. Checked in the
+// catalog up front, since diesel can't tell the undefined function error
+// (SQLSTATE 42883) of a missing fuzzystrmatch from other failures.
+fn has_levenshtein(conn: &mut PgConnection) -> QueryResult<bool> {
+    diesel::select(diesel::dsl::sql::<Bool>(
+        "to_regprocedure('levenshtein_less_equal(text, text, integer)') IS NOT NULL",
+    ))
+    .get_result(conn)
+}
+
+// The active user whose email is the fewest edits away from `email`, for
+// "did you mean" suggestions. Emails are compared lowercased. Only emails
+// whose length is within `max_distance` of the input can be that close, so
+// only those are scored, and scoring stops once a pair exceeds the distance.
+pub async fn get_nearest_email(
+    pool: web::Data<DbPool>,
+    query: web::Query<models::NearestEmailQuery>,
+) -> Result<HttpResponse, UserError> {
+    let target = validation::normalize_email(&query.email).to_lowercase();
+    if target.trim().is_empty() {
+        return Err(UserError::BadRequest("email must not be empty".to_string()));
+    }
+    let length = target.chars().count();
+    if length > MAX_NEAREST_EMAIL_CHARS {
+        return Err(UserError::BadRequest(format!(
+            "email must be at most {} characters",
+            MAX_NEAREST_EMAIL_CHARS
+        )));
+    }
+    let max_distance = query.max_distance.unwrap_or(DEFAULT_NEAREST_DISTANCE);
+    if !(0..=MAX_NEAREST_DISTANCE).contains(&max_distance) {
+        return Err(UserError::BadRequest(format!(
+            "max_distance must be between 0 and {}",
+            MAX_NEAREST_DISTANCE
+        )));
+    }
+    let length = length as i32;
+
+    let nearest_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+        if !has_levenshtein(&mut conn)? {
+            return Err(UserError::Unavailable(
+                "The fuzzystrmatch extension is not installed, add it to REQUIRED_EXTENSIONS"
+                    .to_string(),
+            ));
+        }
+
+        use crate::schema::users::dsl::*;
+
+        let distance = levenshtein_less_equal(email_normalized, target, max_distance);
+        users
+            .select((users::all_columns(), distance.clone()))
+            .filter(deleted_at.is_null())
+            .filter(char_length(email_normalized).between(length - max_distance, length + max_distance))
+            .filter(distance.clone().le(max_distance))
+            .order((distance.asc(), id.asc()))
+            .first::<(models::User, i32)>(&mut conn)
+            .optional()
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match nearest_result {
+        Ok(Some((user, distance))) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Nearest email found".to_string(),
+            data: Some(models::NearestEmail { user, distance }),
+            warnings: Vec::new(),
+        })),
+        Ok(None) => Err(UserError::NotFound),
+        Err(e) => Err(e),
+    }
+}
+
+// Matches on the lowercased domain part of the email, backed by the
+// users_email_domain_idx expression index
+fn email_domain_is(domain: String) -> UserPredicate {
+    Box::new(diesel::dsl::sql::<Bool>("lower(split_part(email, '@', 2)) = ").bind::<Text, _>(domain))
+}
+
+pub async fn get_users_by_domain(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    let domain = validation::validate_domain(&path.into_inner().0)?;
+    let first_page_only = config.total_on_first_page_only;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| {
+                users
+                    .into_boxed()
+                    .filter(deleted_at.is_null())
+                    .filter(email_domain_is(domain.clone()))
+                    .count()
+                    .get_result::<i64>(&mut conn)
+            })
+            .transpose()?;
+
+        let items = users
+            .into_boxed()
+            .filter(deleted_at.is_null())
+            .filter(email_domain_is(domain))
+            .order(id.asc())
+            .limit(pagination.per_page())
+            .offset(pagination.offset())
+            .load::<models::User>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users Fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Syntax check of an email address, with an optional MX lookup of its domain
+// through DNS_RESOLVER
+pub async fn validate_email(
+    config: web::Data<AppConfig>,
+    resolver: Option<web::Data<TokioAsyncResolver>>,
+    query: web::Query<models::ValidateEmailQuery>,
+) -> Result<HttpResponse, UserError> {
+    let check_mx = query.check_mx.unwrap_or(false);
+    let resolver = match (check_mx, resolver) {
+        (true, None) => {
+            return Err(UserError::BadRequest("MX checks need DNS_RESOLVER".to_string()));
+        }
+        (_, resolver) => resolver,
+    };
+
+    let domain = validation::email_domain(&query.email);
+    let domain_has_mx = match (&domain, resolver) {
+        (Some(domain), Some(resolver)) if check_mx => Some(
+            mx::has_mx(&resolver, domain, config.dns_timeout())
+                .await
+                .map_err(UserError::Unavailable)?,
+        ),
+        _ => None,
+    };
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Email checked".to_string(),
+        data: Some(models::EmailCheck {
+            syntax_valid: domain.is_some(),
+            domain_has_mx,
+        }),
+        warnings: Vec::new(),
+    }))
+}
+
+// Bound on each `/users/email-regex` query, on top of the pattern limits
+const EMAIL_REGEX_TIMEOUT: &str = "2s";
+
+fn email_matches(pattern: String) -> UserPredicate {
+    Box::new(diesel::dsl::sql::<Bool>("email ~* ").bind::<Text, _>(pattern))
+}
+
+#[derive(QueryableByName)]
+struct EmailRegexCount {
+    #[diesel(sql_type = Nullable<Text>)]
+    state: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    detail: Option<String>,
+    #[diesel(sql_type = Nullable<diesel::sql_types::BigInt>)]
+    total: Option<i64>,
+}
+
+// The active users whose email matches `pattern`. Runs first, so a pattern
+// that doesn't compile (SQLSTATE 2201B) or runs past the statement timeout
+// (57014) is refused before the page is loaded.
+fn count_email_matches(conn: &mut PgConnection, pattern: &str) -> Result<i64, UserError> {
+    let count = diesel::sql_query("SELECT state, detail, total FROM users_email_regex_count($1)")
+        .bind::<Text, _>(pattern)
+        .get_result::<EmailRegexCount>(conn)?;
+
+    match count.state.as_deref() {
+        Some("2201B") => Err(UserError::BadRequest(count.detail.unwrap_or_default())),
+        Some("57014") => {
+            Err(UserError::BadRequest("pattern is too expensive to match".to_string()))
+        }
+        _ => Ok(count.total.unwrap_or_default()),
+    }
+}
+
+// Admin search over active users' emails with a case-insensitive regex
+pub async fn get_users_by_email_regex(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+    query: web::Query<models::EmailRegexQuery>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    admin::require_admin(&req, &config)?;
+
+    let pattern = validation::validate_email_pattern(&query.pattern)?;
+    let first_page_only = config.total_on_first_page_only;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        conn.transaction(|conn| {
+            diesel::sql_query(format!("SET LOCAL statement_timeout = '{}'", EMAIL_REGEX_TIMEOUT))
+                .execute(conn)?;
+
+            let matching = count_email_matches(conn, &pattern)?;
+            let total = pagination.wants_total(first_page_only).then_some(matching);
+
+            let items = users
+                .into_boxed()
+                .filter(deleted_at.is_null())
+                .filter(email_matches(pattern))
+                .order(id.asc())
+                .limit(pagination.per_page())
+                .offset(pagination.offset())
+                .load::<models::User>(conn)?;
+
+            Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+        })
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users Fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// Most users `/users/anonymize` takes at once
+const MAX_ANONYMIZE_USERS: usize = 1000;
+
+// Replaces the PII of the given users, deleted ones included, keeping the
+// rows. The placeholder email is derived from the user_id, so anonymized
+// emails stay unique without a uniqueness check.
+pub async fn anonymize_users(
+    req: HttpRequest,
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    ids: Json<Vec<Uuid>>,
+) -> Result<HttpResponse, UserError> {
+    admin::require_admin(&req, &config)?;
+
+    let ids = ids.into_inner();
+    if ids.is_empty() {
+        return Err(UserError::BadRequest("at least one user_id is required".to_string()));
+    }
+    if ids.len() > MAX_ANONYMIZE_USERS {
+        return Err(UserError::BadRequest(format!(
+            "at most {} users can be anonymized at once",
+            MAX_ANONYMIZE_USERS
+        )));
+    }
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            use crate::schema::users::dsl::*;
+
+            Ok(diesel::update(users.filter(user_id.eq_any(&ids)))
+                .set((
+                    first_name.eq("Deleted"),
+                    last_name.eq("User"),
+                    email.eq(diesel::dsl::sql::<Text>(
+                        "'deleted-' || md5(user_id::text) || '@example.invalid'",
+                    )),
+                ))
+                .get_results::<models::User>(conn)?)
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(anonymized) => {
+            let count = anonymized.len();
+            anonymized
+                .into_iter()
+                .for_each(|user| tx.publish_on_commit(ChangeEvent::upsert(user)));
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Users anonymized successfully".to_string(),
+                data: Some(models::Anonymized { anonymized: count }),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Applies one changeset to every active user matching the filter. Emails
+// are unique per user, so only names can be reassigned.
+pub async fn reassign_users(
+    req: HttpRequest,
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    query: web::Query<models::ReassignQuery>,
+    body: Json<models::Reassign>,
+) -> Result<HttpResponse, UserError> {
+    admin::require_admin(&req, &config)?;
+
+    let models::Reassign { filter, changes } = body.into_inner();
+    let changes = validation::validate_update_user(changes)?;
+    if changes.email.is_some() {
+        return Err(UserError::BadRequest("email can't be reassigned in bulk".to_string()));
+    }
+    if !changes.has_changes() {
+        return Err(UserError::BadRequest("no fields to update".to_string()));
+    }
+    if filter.is_empty() && !query.allow_all.unwrap_or(false) {
+        return Err(UserError::BadRequest(
+            "an empty filter matches every user, pass allow_all=true to confirm".to_string(),
+        ));
+    }
+    let domain = filter.domain.as_deref().map(validation::validate_domain).transpose()?;
+    let stale = filter.is_stale;
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            use crate::schema::users::dsl::*;
+
+            let mut matching: UserPredicate = Box::new(deleted_at.is_null());
+            if let Some(domain) = domain.clone() {
+                matching = Box::new(matching.and(email_domain_is(domain)));
+            }
+            if let Some(stale) = stale {
+                matching = Box::new(matching.and(is_stale.eq(stale)));
+            }
+
+            Ok(diesel::update(users.filter(matching))
+                .set(&changes)
+                .get_results::<models::User>(conn)?)
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(reassigned) => {
+            let updated = reassigned.len();
+            reassigned
+                .into_iter()
+                .for_each(|user| tx.publish_on_commit(ChangeEvent::upsert(user)));
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Users reassigned successfully".to_string(),
+                data: Some(models::Reassigned { updated }),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Exchanges the emails of two active users in one transaction. The first
+// user is parked on a placeholder email while the second takes over its old
+// one, so each step passes the uniqueness check on its own.
+pub async fn swap_emails(
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    swap: Json<models::EmailSwap>,
+) -> Result<HttpResponse, UserError> {
+    let models::EmailSwap { first, second } = swap.into_inner();
+    if first == second {
+        return Err(UserError::BadRequest("the two user_ids must differ".to_string()));
+    }
+    let rules = config.uniqueness();
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            use crate::schema::users::dsl::*;
+
+            // Locked in id order so two opposite swaps can't deadlock
+            let locked = users
+                .filter(user_id.eq_any([first, second]))
+                .filter(deleted_at.is_null())
+                .order(id.asc())
+                .for_update()
+                .load::<models::User>(conn)?;
+
+            let find = |wanted: Uuid| {
+                locked
+                    .iter()
+                    .find(|user| user.user_id == wanted)
+                    .cloned()
+                    .ok_or(UserError::NotFound)
+            };
+            let (first_user, second_user) = (find(first)?, find(second)?);
+
+            diesel::update(users.filter(user_id.eq(first)))
+                .set(email.eq(format!("swap-{}@example.invalid", first)))
+                .execute(conn)?;
+
+            let mut swapped = Vec::with_capacity(2);
+            for (target, new_email) in [(second, first_user.email), (first, second_user.email)] {
+                let changes = models::UpdateUser {
+                    first_name: None,
+                    last_name: None,
+                    email: Some(new_email),
+                };
+                swapped.push(
+                    update_active_user(conn, rules, target, &changes)?.ok_or(UserError::NotFound)?,
+                );
+            }
+            swapped.reverse();
+
+            Ok(swapped)
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(swapped) => {
+            swapped
+                .iter()
+                .for_each(|user| tx.publish_on_commit(ChangeEvent::upsert(user.clone())));
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Emails swapped successfully".to_string(),
+                data: Some(swapped),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Shallow merge: top-level keys of the body replace those of the stored
+// metadata, other keys are kept. A null value is stored as null, not removed.
+pub async fn merge_user_metadata(
+    tx: TxConn,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+    body: Json<serde_json::Value>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+    let changes = validation::validate_metadata(body.into_inner())?;
+
+    let user_result = tx
+        .run_retrying(config.tx_retries, move |conn| {
+            use crate::schema::users::dsl::*;
+
+            // `||` on jsonb objects is the shallow merge, done in the UPDATE so
+            // concurrent merges can't lose each other's keys
+            let merged = diesel::dsl::sql::<Nullable<Jsonb>>("COALESCE(metadata, '{}'::jsonb) || ")
+                .bind::<Jsonb, _>(serde_json::Value::Object(changes.clone()));
+
+            diesel::update(users)
+                .filter(user_ref_is(user_ref))
+                .filter(deleted_at.is_null())
+                .set(metadata.eq(merged))
+                .get_result::<models::User>(conn)
+                .optional()?
+                .ok_or(UserError::NotFound)
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(user) => {
+            tx.publish_on_commit(ChangeEvent::upsert(user.clone()));
+
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Metadata updated successfully".to_string(),
+                data: Some(user),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Most emails `/users/by-emails` looks up at once
+const MAX_LOOKUP_EMAILS: usize = 1000;
+
+// Emails are compared lowercased, via the generated email_normalized column,
+// and trimmed unless EMAIL_TRIM is off
+pub async fn get_users_by_emails(
+    pool: web::Data<DbPool>,
+    emails: Json<Vec<String>>,
+) -> Result<HttpResponse, UserError> {
+    if emails.len() > MAX_LOOKUP_EMAILS {
+        return Err(UserError::BadRequest(format!(
+            "at most {} emails can be looked up at once",
+            MAX_LOOKUP_EMAILS
+        )));
+    }
+    let emails = validation::normalize_emails(emails.into_inner())?;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let items = users
+            .filter(deleted_at.is_null())
+            .filter(email_normalized.eq_any(&emails))
+            .order(id.asc())
+            .load::<models::User>(&mut conn)?;
+
+        let not_found = emails
+            .into_iter()
+            .filter(|lookup| !items.iter().any(|user| &user.email_normalized == lookup))
+            .collect();
+
+        Ok::<_, UserError>(models::EmailLookup { items, not_found })
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(lookup) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users Fetched successfully".to_string(),
+            data: Some(lookup),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// What `/users/sync-stream` writes, one JSON object per line
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SyncLine {
+    Snapshot { user: models::User },
+    SnapshotEnd,
+    Change { kind: ChangeKind, user: models::User },
+    // The consumer fell too far behind the feed, it has to start over
+    Lagged,
+}
+
+fn ndjson(line: &SyncLine) -> Result<web::Bytes, actix_web::Error> {
+    let mut bytes = serde_json::to_vec(line)?;
+    bytes.push(b'\n');
+    Ok(web::Bytes::from(bytes))
+}
+
+// Streams every active user as NDJSON, then the changes made after. The feed
+// is subscribed to before the snapshot is read, so no change is missed; a
+// change already contained in the snapshot is recognized by its updated_at
+// and skipped.
+pub async fn sync_stream(
+    pool: web::Data<DbPool>,
+    feed: web::Data<ChangeFeed>,
+) -> Result<HttpResponse, UserError> {
+    let receiver = feed.subscribe();
+
+    let snapshot = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        users
+            .filter(deleted_at.is_null())
+            .order(id.asc())
+            .load::<models::User>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)??;
+
+    let seen: HashMap<Uuid, NaiveDateTime> = snapshot
+        .iter()
+        .map(|user| (user.user_id, user.updated_at))
+        .collect();
+
+    let initial = snapshot
+        .into_iter()
+        .map(|user| SyncLine::Snapshot { user })
+        .chain(std::iter::once(SyncLine::SnapshotEnd))
+        .map(|line| ndjson(&line));
+
+    let changes = stream::unfold(Some((receiver, seen)), |state| async move {
+        let (mut receiver, seen) = state?;
+
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let in_snapshot = seen
+                        .get(&event.user.user_id)
+                        .is_some_and(|snapshot_at| event.user.updated_at <= *snapshot_at);
+                    if in_snapshot {
+                        continue;
+                    }
+
+                    let line = SyncLine::Change {
+                        kind: event.kind,
+                        user: event.user,
+                    };
+                    return Some((ndjson(&line), Some((receiver, seen))));
+                }
+                Err(RecvError::Lagged(_)) => return Some((ndjson(&SyncLine::Lagged), None)),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(stream::iter(initial).chain(changes)))
+}
+
+fn user_ref_is(user_ref: validation::UserRef) -> UserPredicate {
+    use crate::schema::users::dsl::*;
+
+    match user_ref {
+        validation::UserRef::UserId(parsed_user_id) => Box::new(user_id.eq(parsed_user_id)),
+        validation::UserRef::Id(parsed_id) => Box::new(id.eq(parsed_id)),
+    }
+}
+
+// The user_id a path refers to, for the helpers keyed by user_id. An integer
+// id no row has is a 404.
+fn resolve_user_id(
+    conn: &mut PgConnection,
+    user_ref: validation::UserRef,
+) -> Result<Uuid, UserError> {
+    use crate::schema::users::dsl::*;
+
+    match user_ref {
+        validation::UserRef::UserId(parsed_user_id) => Ok(parsed_user_id),
+        validation::UserRef::Id(parsed_id) => users
+            .filter(id.eq(parsed_id))
+            .select(user_id)
+            .first::<Uuid>(conn)
+            .optional()?
+            .ok_or(UserError::NotFound),
+    }
+}
+
+// Whether the If-None-Match header matches `etag`, with the weak
+// comparison RFC 7232 requires for it
+fn none_match(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+// Sends the user's ETag, and 304 without a body if If-None-Match has it.
+// The ETag only covers the stored user, so there is none with
+// `?check_deliverable=true`.
+pub async fn get_user(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+    deliverable: web::Query<models::DeliverableQuery>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+    let resolver = deliverability_resolver(&req, deliverable.check_deliverable)?;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        users
+            .into_boxed()
+            .filter(user_ref_is(user_ref))
+            .filter(deleted_at.is_null())
+            .first::<models::User>(&mut conn)
+            .optional()
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(Some(user)) => {
+            let mut response = HttpResponse::Ok();
+            if resolver.is_none() {
+                let etag = user.etag();
+                if none_match(&req, &etag) {
+                    return Ok(HttpResponse::NotModified().insert_header(header::ETag(etag)).finish());
+                }
+                response.insert_header(header::ETag(etag));
+            }
+            let found = deliverability(&req, std::slice::from_ref(&user), resolver, &config).await;
+            Ok(response.json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "User Fetched successfully".to_string(),
+                data: Some(annotate(user, &found)),
+                warnings: Vec::new(),
+            }))
+        }
+        Ok(None) => Err(UserError::NotFound),
+        Err(e) => Err(e),
+    }
+}
+
+// Most users `/users/batch-get-conditional` revalidates at once
+const MAX_CONDITIONAL_GETS: usize = 1000;
+
+// Revalidates many cached users in one round trip. Each entry gets the
+// fresh user with its ETag, `not_modified` when the cached ETag still
+// matches (compared as `get_user` does), or `not_found`. Entries are
+// answered in the order sent.
+pub async fn batch_get_conditional(
+    pool: web::Data<DbPool>,
+    items: Json<Vec<models::ConditionalGet>>,
+) -> Result<HttpResponse, UserError> {
+    let items = items.into_inner();
+    if items.len() > MAX_CONDITIONAL_GETS {
+        return Err(UserError::BadRequest(format!(
+            "at most {} users can be fetched at once",
+            MAX_CONDITIONAL_GETS
+        )));
+    }
+    let cached = items
+        .iter()
+        .enumerate()
+        .map(|(index, item)| match &item.etag {
+            Some(etag) => etag.parse::<EntityTag>().map(Some).map_err(|_| {
+                UserError::BadRequest(format!("etag of item {} is not a valid entity tag", index))
+            }),
+            None => Ok(None),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let ids: Vec<Uuid> = items.iter().map(|item| item.user_id).collect();
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        users
+            .filter(user_id.eq_any(ids))
+            .filter(deleted_at.is_null())
+            .load::<models::User>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    let found: HashMap<Uuid, models::User> = user_result?
+        .into_iter()
+        .map(|user| (user.user_id, user))
+        .collect();
+
+    let results: Vec<models::ConditionalUser> = items
+        .into_iter()
+        .zip(cached)
+        .map(|(item, cached)| {
+            let user = match found.get(&item.user_id) {
+                Some(user) => user,
+                None => {
+                    return models::ConditionalUser::NotFound {
+                        user_id: item.user_id,
+                        not_found: true,
+                    }
+                }
+            };
+            let etag = user.etag();
+            if cached.is_some_and(|cached| cached.weak_eq(&etag)) {
+                return models::ConditionalUser::NotModified {
+                    user_id: item.user_id,
+                    not_modified: true,
+                };
+            }
+            models::ConditionalUser::Modified {
+                etag: etag.to_string(),
+                user: Box::new(user.clone()),
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Users revalidated".to_string(),
+        data: Some(results),
+        warnings: Vec::new(),
+    }))
+}
+
+// Ties on created_at are broken by id, so every user has a distinct rank
+pub async fn get_user_rank(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+
+    let rank_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        let target = {
+            use crate::schema::users::dsl::*;
+
+            users
+                .into_boxed()
+                .filter(user_ref_is(user_ref))
+                .filter(deleted_at.is_null())
+                .select(id)
+                .first::<i32>(&mut conn)
+                .optional()?
+        };
+
+        match target {
+            Some(target) => diesel::sql_query(
+                "SELECT user_id, rank FROM ( \
+                     SELECT id, user_id, row_number() OVER (ORDER BY created_at, id) AS rank \
+                     FROM users WHERE deleted_at IS NULL \
+                 ) ranked WHERE id = $1",
+            )
+            .bind::<diesel::sql_types::Integer, _>(target)
+            .get_result::<models::UserRank>(&mut conn)
+            .optional(),
+            None => Ok(None),
+        }
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match rank_result {
+        Ok(Some(rank)) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "User rank fetched successfully".to_string(),
+            data: Some(rank),
+            warnings: Vec::new(),
+        })),
+        Ok(None) => Err(UserError::NotFound),
+        Err(e) => Err(e),
+    }
+}
+
+// Ordered by (created_at, id) like the rank and the feed
+pub async fn get_user_neighbors(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+
+    let neighbors_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let anchor = users
+            .into_boxed()
+            .filter(user_ref_is(user_ref))
+            .filter(deleted_at.is_null())
+            .first::<models::User>(&mut conn)
+            .optional()?;
+
+        let anchor = match anchor {
+            Some(anchor) => anchor,
+            None => return Ok(None),
+        };
+
+        let previous = users
+            .filter(deleted_at.is_null())
+            .filter(
+                created_at
+                    .lt(anchor.created_at)
+                    .or(created_at.eq(anchor.created_at).and(id.lt(anchor.id))),
+            )
+            .order((created_at.desc(), id.desc()))
+            .first::<models::User>(&mut conn)
+            .optional()?;
+
+        let next = users
+            .filter(deleted_at.is_null())
+            .filter(
+                created_at
+                    .gt(anchor.created_at)
+                    .or(created_at.eq(anchor.created_at).and(id.gt(anchor.id))),
+            )
+            .order((created_at.asc(), id.asc()))
+            .first::<models::User>(&mut conn)
+            .optional()?;
+
+        Ok::<_, UserError>(Some(models::Neighbors { previous, next }))
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match neighbors_result {
+        Ok(Some(neighbors)) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Neighbors fetched successfully".to_string(),
+            data: Some(neighbors),
+            warnings: Vec::new(),
+        })),
+        Ok(None) => Err(UserError::NotFound),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn get_user_vcard(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+) -> Result<HttpResponse, UserError> {
+    let user_ref = validation::parse_user_ref(&path.into_inner().0, config.accept_integer_ids)?;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        users
+            .into_boxed()
+            .filter(user_ref_is(user_ref))
+            .filter(deleted_at.is_null())
+            .first::<models::User>(&mut conn)
+            .optional()
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(Some(user)) => Ok(HttpResponse::Ok()
+            .content_type("text/vcard; charset=utf-8")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.vcf\"", user.user_id),
+            ))
+            .body(vcard::to_vcard(&user))),
+        Ok(None) => Err(UserError::NotFound),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn get_stale_users(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    let first_page_only = config.total_on_first_page_only;
+
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| {
+                users
+                    .filter(deleted_at.is_null())
+                    .filter(is_stale.eq(true))
+                    .count()
+                    .get_result::<i64>(&mut conn)
+            })
+            .transpose()?;
+
+        let items = users
+            .filter(deleted_at.is_null())
+            .filter(is_stale.eq(true))
+            .order(id.asc())
+            .limit(pagination.per_page())
+            .offset(pagination.offset())
+            .load::<models::User>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Stale users fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn get_user_bookends(pool: web::Data<DbPool>) -> Result<HttpResponse, UserError> {
+    let user_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let first = users
+            .filter(deleted_at.is_null())
+            .order((created_at.asc(), id.asc()))
+            .first::<models::User>(&mut conn)
+            .optional()?;
+
+        let last = users
+            .filter(deleted_at.is_null())
+            .order((created_at.desc(), id.desc()))
+            .first::<models::User>(&mut conn)
+            .optional()?;
+
+        Ok::<_, UserError>(models::Bookends { first, last })
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match user_result {
+        Ok(bookends) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users Fetched successfully".to_string(),
+            data: Some(bookends),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn get_name_stats(pool: web::Data<DbPool>) -> Result<HttpResponse, UserError> {
+    let stats_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+        use diesel::dsl::{avg, count_star, max, min};
+
+        users
+            .filter(deleted_at.is_null())
+            .select((
+                count_star(),
+                min(char_length(first_name)),
+                max(char_length(first_name)),
+                avg(float8(char_length(first_name))),
+                min(char_length(last_name)),
+                max(char_length(last_name)),
+                avg(float8(char_length(last_name))),
+            ))
+            .first::<(
+                i64,
+                Option<i32>,
+                Option<i32>,
+                Option<f64>,
+                Option<i32>,
+                Option<i32>,
+                Option<f64>,
+            )>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match stats_result {
+        Ok((total, first_min, first_max, first_avg, last_min, last_max, last_avg)) => {
+            Ok(HttpResponse::Ok().json(models::GenericResponse {
+                status: "OK".to_string(),
+                message: "Name statistics computed successfully".to_string(),
+                data: Some(models::NameStats {
+                    users: total,
+                    first_name: models::LengthStats {
+                        min: first_min,
+                        max: first_max,
+                        avg: first_avg,
+                    },
+                    last_name: models::LengthStats {
+                        min: last_min,
+                        max: last_max,
+                        avg: last_avg,
+                    },
+                }),
+                warnings: Vec::new(),
+            }))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Names are compared exactly, the same way UNIQUENESS_POLICY=name_email
+// compares them
+const NAME_DUPLICATE_GROUPS: &str = "SELECT first_name, last_name FROM users \
+     WHERE deleted_at IS NULL \
+     GROUP BY first_name, last_name HAVING count(*) > 1";
+
+// Groups of active users with the same first and last name, largest first
+pub async fn get_name_duplicates(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    let first_page_only = config.total_on_first_page_only;
+
+    let groups_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| {
+                diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(&format!(
+                    "(SELECT count(*) FROM ({}) AS groups)",
+                    NAME_DUPLICATE_GROUPS
+                )))
+                .get_result::<i64>(&mut conn)
+            })
+            .transpose()?;
+
+        let items = diesel::sql_query(
+            "SELECT first_name, last_name, count(*) AS count, \
+             array_agg(user_id ORDER BY created_at, id) AS user_ids \
+             FROM users WHERE deleted_at IS NULL \
+             GROUP BY first_name, last_name HAVING count(*) > 1 \
+             ORDER BY count DESC, first_name, last_name LIMIT $1 OFFSET $2",
+        )
+        .bind::<diesel::sql_types::BigInt, _>(pagination.per_page())
+        .bind::<diesel::sql_types::BigInt, _>(pagination.offset())
+        .load::<models::NameDuplicateGroup>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match groups_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Name duplicates fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+// The columns `/users/distinct/{column}` may group by and the SQL for each.
+// Only these fixed strings ever reach the query.
+const DISTINCT_COLUMNS: &[(&str, &str)] = &[
+    ("first_name", "first_name"),
+    ("last_name", "last_name"),
+    ("email_domain", "lower(split_part(email, '@', 2))"),
+];
+
+// Distinct values of a column among active users with how many users have
+// each, most common first
+pub async fn get_distinct_values(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    path: web::Path<(String,)>,
+    pagination: web::Query<models::Pagination>,
+) -> Result<HttpResponse, UserError> {
+    let column = path.into_inner().0;
+    let expression = DISTINCT_COLUMNS
+        .iter()
+        .find(|(name, _)| *name == column)
+        .map(|(_, expression)| *expression)
+        .ok_or_else(|| {
+            UserError::BadRequest(format!(
+                "unknown column {:?}, expected one of {}",
+                column,
+                DISTINCT_COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+            ))
+        })?;
+    let first_page_only = config.total_on_first_page_only;
+
+    let values_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        let total = pagination
+            .wants_total(first_page_only)
+            .then(|| {
+                users
+                    .filter(deleted_at.is_null())
+                    .select(diesel::dsl::sql::<diesel::sql_types::BigInt>(&format!(
+                        "count(DISTINCT {})",
+                        expression
+                    )))
+                    .get_result::<i64>(&mut conn)
+            })
+            .transpose()?;
+
+        let items = diesel::sql_query(format!(
+            "SELECT {} AS value, count(*) AS count FROM users \
+             WHERE deleted_at IS NULL \
+             GROUP BY 1 ORDER BY count DESC, value ASC LIMIT $1 OFFSET $2",
+            expression
+        ))
+        .bind::<diesel::sql_types::BigInt, _>(pagination.per_page())
+        .bind::<diesel::sql_types::BigInt, _>(pagination.offset())
+        .load::<models::DistinctValue>(&mut conn)?;
+
+        Ok::<_, UserError>(models::Paginated::new(items, &pagination, total))
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match values_result {
+        Ok(page) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Distinct values fetched successfully".to_string(),
+            data: Some(page.with_links(&req)),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn get_user_counts(pool: web::Data<DbPool>) -> Result<HttpResponse, UserError> {
+    let counts_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        diesel::sql_query(
+            "SELECT count(*) FILTER (WHERE deleted_at IS NULL) AS active, \
+             count(*) FILTER (WHERE deleted_at IS NOT NULL) AS deleted, \
+             count(*) AS total \
+             FROM users",
+        )
+        .get_result::<models::UserCounts>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match counts_result {
+        Ok(counts) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "User counts fetched successfully".to_string(),
+            data: Some(counts),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+const DEFAULT_LABELS_PER_SHEET: i64 = 30;
+const MAX_LABELS_PER_SHEET: i64 = 100;
+const DEFAULT_LABEL_SHEETS: i64 = 10;
+const MAX_LABEL_SHEETS: i64 = 50;
+
+// Sheets of `per_sheet` labels each, the last one padded with nulls to a
+// full sheet. No labels are no sheets.
+fn label_sheets(labels: Vec<models::Label>, per_sheet: usize) -> Vec<Vec<Option<models::Label>>> {
+    let mut sheets: Vec<Vec<Option<models::Label>>> = Vec::new();
+    for label in labels {
+        match sheets.last_mut() {
+            Some(sheet) if sheet.len() < per_sheet => sheet.push(Some(label)),
+            _ => sheets.push(vec![Some(label)]),
+        }
+    }
+    if let Some(sheet) = sheets.last_mut() {
+        sheet.resize_with(per_sheet, || None);
+    }
+    sheets
+}
+
+// Active users as address labels, by last name, then first name
+pub async fn get_user_labels(
+    pool: web::Data<DbPool>,
+    query: web::Query<models::LabelsQuery>,
+) -> Result<HttpResponse, UserError> {
+    let per_sheet = query.per_page.unwrap_or(DEFAULT_LABELS_PER_SHEET);
+    if !(1..=MAX_LABELS_PER_SHEET).contains(&per_sheet) {
+        return Err(UserError::BadRequest(format!(
+            "per_page must be between 1 and {}",
+            MAX_LABELS_PER_SHEET
+        )));
+    }
+    let sheets = query.sheets.unwrap_or(DEFAULT_LABEL_SHEETS);
+    if !(1..=MAX_LABEL_SHEETS).contains(&sheets) {
+        return Err(UserError::BadRequest(format!(
+            "sheets must be between 1 and {}",
+            MAX_LABEL_SHEETS
+        )));
+    }
+    let page = query.page.unwrap_or(1);
+    if page < 1 {
+        return Err(UserError::BadRequest("page must be at least 1".to_string()));
+    }
+    let limit = per_sheet * sheets;
+    let offset = (page - 1).checked_mul(limit).ok_or_else(|| {
+        UserError::BadRequest("page is too large".to_string())
+    })?;
+
+    let labels_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        use crate::schema::users::dsl::*;
+
+        users
+            .filter(deleted_at.is_null())
+            .order((last_name.asc(), first_name.asc(), id.asc()))
+            .select((first_name, last_name, email))
+            .limit(limit + 1)
+            .offset(offset)
+            .load::<models::Label>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    let mut labels = labels_result?;
+    let has_more = labels.len() as i64 > limit;
+    labels.truncate(limit as usize);
+
+    let sheets = label_sheets(labels, per_sheet as usize);
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Labels fetched successfully".to_string(),
+        data: Some(models::LabelSheets {
+            per_page: per_sheet,
+            page,
+            sheets,
+            has_more,
+        }),
+        warnings: Vec::new(),
+    }))
+}
+
+// Upper bound on the buckets of one `/users/histogram`
+const MAX_HISTOGRAM_BUCKETS: i64 = 1000;
+
+#[derive(QueryableByName)]
+struct CreatedBounds {
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    first: Option<NaiveDateTime>,
+    #[diesel(sql_type = Nullable<Timestamp>)]
+    last: Option<NaiveDateTime>,
+}
+
+// Active users by creation time, in buckets from the one holding `from` to
+// the one holding `to`, both inclusive and defaulting to the oldest and
+// newest user. Empty buckets are included with a count of 0.
+pub async fn get_user_histogram(
+    pool: web::Data<DbPool>,
+    query: web::Query<models::HistogramQuery>,
+) -> Result<HttpResponse, UserError> {
+    let bucket = query.bucket.unwrap_or_default();
+    let from = query
+        .from
+        .as_deref()
+        .map(|value| validation::parse_timestamp("from", value))
+        .transpose()?;
+    let to = query
+        .to
+        .as_deref()
+        .map(|value| validation::parse_timestamp("to", value))
+        .transpose()?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(UserError::BadRequest("from must not be after to".to_string()));
+        }
+    }
+
+    let histogram_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        let bounds = diesel::sql_query(
+            "SELECT date_trunc($1, COALESCE($2, min(created_at))) AS first, \
+             date_trunc($1, COALESCE($3, max(created_at))) AS last \
+             FROM users WHERE deleted_at IS NULL",
+        )
+        .bind::<Text, _>(bucket.unit())
+        .bind::<Nullable<Timestamp>, _>(from)
+        .bind::<Nullable<Timestamp>, _>(to)
+        .get_result::<CreatedBounds>(&mut conn)?;
+
+        let (first, last) = match (bounds.first, bounds.last) {
+            (Some(first), Some(last)) if first <= last => (first, last),
+            // No users, or a range before the first one
+            _ => return Ok(Vec::new()),
+        };
+        if (last - first).num_seconds() / bucket.seconds() >= MAX_HISTOGRAM_BUCKETS {
+            return Err(UserError::BadRequest(format!(
+                "the range spans more than {} buckets, narrow it or use a larger bucket",
+                MAX_HISTOGRAM_BUCKETS
+            )));
+        }
+
+        Ok(diesel::sql_query(
+            "SELECT buckets.bucket_start, count(users.id) AS count \
+             FROM generate_series($2, $3, ('1 ' || $1)::interval) AS buckets(bucket_start) \
+             LEFT JOIN users ON users.deleted_at IS NULL \
+                AND date_trunc($1, users.created_at) = buckets.bucket_start \
+                AND ($4::timestamp IS NULL OR users.created_at >= $4) \
+                AND ($5::timestamp IS NULL OR users.created_at <= $5) \
+             GROUP BY buckets.bucket_start \
+             ORDER BY buckets.bucket_start",
+        )
+        .bind::<Text, _>(bucket.unit())
+        .bind::<Timestamp, _>(first)
+        .bind::<Timestamp, _>(last)
+        .bind::<Nullable<Timestamp>, _>(from)
+        .bind::<Nullable<Timestamp>, _>(to)
+        .load::<models::HistogramBucket>(&mut conn)?)
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    match histogram_result {
+        Ok(buckets) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Histogram fetched successfully".to_string(),
+            data: Some(buckets),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::uniqueness::UniquenessPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn pool_wait_is_recorded_under_contention() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let pool = web::Data::new(pool);
+        POOL_METRICS.reset_waits();
+
+        let held = get_conn_from_db(pool.clone()).unwrap();
+        let waiter = {
+            let pool = pool.clone();
+            std::thread::spawn(move || get_conn_from_db(pool).map(drop))
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        drop(held);
+        waiter.join().unwrap().unwrap();
+
+        // Two waits, the uncontended one and the one behind `held`
+        assert!(POOL_METRICS.summary().wait_p95_ms >= 150.0);
+    }
+
+    #[test]
+    fn timed_out_acquire_is_service_unavailable() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_millis(100)) else { return };
+        let pool = web::Data::new(pool);
+        let timeouts = POOL_METRICS.summary().timeouts;
+
+        let _held = get_conn_from_db(pool.clone()).unwrap();
+        let error = get_conn_from_db(pool).map(drop).unwrap_err();
+
+        assert!(matches!(error, UserError::PoolExhausted(_)));
+        assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(POOL_METRICS.summary().timeouts, timeouts + 1);
+    }
+
+    #[test]
+    fn if_unmodified_since_is_read_as_utc() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::IF_UNMODIFIED_SINCE, "Wed, 14 Oct 2026 06:30:00 GMT"))
+            .to_http_request();
+        assert_eq!(if_unmodified_since(&req).unwrap().to_string(), "2026-10-14 06:30:00");
+
+        let invalid = actix_web::test::TestRequest::default()
+            .insert_header((header::IF_UNMODIFIED_SINCE, "yesterday"))
+            .to_http_request();
+        assert_eq!(if_unmodified_since(&invalid), None);
+    }
+
+    #[test]
+    fn unmodified_since_fails_only_for_newer_rows() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let user = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let second = chrono::Duration::seconds(1);
+
+        // HTTP dates have no fractions, the same second still counts as unmodified
+        let same_second = user.updated_at.with_nanosecond(0).unwrap();
+        assert!(check_unmodified_since(&mut conn, user.user_id, Some(same_second)).is_ok());
+        assert!(check_unmodified_since(&mut conn, user.user_id, Some(same_second + second)).is_ok());
+        assert!(check_unmodified_since(&mut conn, user.user_id, None).is_ok());
+
+        let error = check_unmodified_since(&mut conn, user.user_id, Some(same_second - second));
+        assert!(matches!(error, Err(UserError::PreconditionFailed)));
+    }
+
+    fn new_user(first_name: &str, last_name: &str, email: &str) -> models::NewUser {
+        models::NewUser {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    #[test]
+    fn case_insensitive_emails_keep_their_casing_and_block_duplicates() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: true,
+        };
+
+        let user = conn
+            .transaction(|conn| {
+                let form = new_user("Ada", "Lovelace", "Ada@Example.COM");
+                insert_user(conn, rules, form, None).map(|inserted| inserted.user)
+            })
+            .unwrap();
+        assert_eq!(user.email, "Ada@Example.COM");
+        assert_eq!(user.email_normalized, "ada@example.com");
+
+        let duplicate = conn.transaction(|conn| {
+            insert_user(conn, rules, new_user("Grace", "Hopper", "ada@example.com"), None)
+        });
+        assert!(matches!(duplicate, Err(UserError::Conflict(_))));
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn explain_returns_the_generated_sql_only_when_allowed() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        testing::reset(&mut pool.get().unwrap());
+        let explain = || actix_web::test::TestRequest::get().uri("/get?explain=true&plan=true");
+
+        let config = testing::config(&[("ALLOW_EXPLAIN", "true")]);
+        let (status, body) = testing::json(testing::call(&pool, &config, explain()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let sql = body["data"]["explain"]["sql"].as_str().unwrap();
+        assert!(sql.starts_with("SELECT") && sql.contains("\"deleted_at\" IS NULL"), "{}", sql);
+        assert!(!body["data"]["explain"]["plan"].as_array().unwrap().is_empty());
+
+        let response = testing::call(&pool, &testing::config(&[]), explain()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn unknown_params_are_refused_only_with_strict_query() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let typo = || actix_web::test::TestRequest::get().uri("/get?pageSize=10");
+
+        let lenient = testing::call(&pool, &testing::config(&[]), typo()).await;
+        assert_eq!(lenient.status(), StatusCode::OK);
+        let strict = testing::config(&[("STRICT_QUERY", "true")]);
+        let (status, body) = testing::json(testing::call(&pool, &strict, typo()).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("pageSize"), "{}", body);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn update_and_delete_take_either_id_form() {
+        use actix_web::test::TestRequest;
+
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let config = testing::config(&[("ACCEPT_INTEGER_IDS", "true")]);
+        let rename = |path: String| {
+            TestRequest::post()
+                .uri(&format!("/update/{}", path))
+                .set_json(serde_json::json!({ "first_name": "Augusta" }))
+        };
+
+        let response = testing::call(&pool, &config, rename(ada.user_id.to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = testing::call(&pool, &config, rename(grace.id.to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let delete = TestRequest::get().uri(&format!("/delete/{}", grace.id));
+        assert_eq!(testing::call(&pool, &config, delete).await.status(), StatusCode::OK);
+
+        use crate::schema::users::dsl::*;
+        let renamed = users.filter(first_name.eq("Augusta")).count().get_result::<i64>(&mut conn);
+        assert_eq!(renamed.unwrap(), 2);
+        let deleted = users.filter(deleted_at.is_not_null()).select(id).load::<i32>(&mut conn);
+        assert_eq!(deleted.unwrap(), vec![grace.id]);
+
+        // Malformed or unknown ids are refused instead of panicking
+        let malformed = TestRequest::get().uri("/delete/not-an-id");
+        assert_eq!(testing::call(&pool, &config, malformed).await.status(), StatusCode::BAD_REQUEST);
+        let unknown = TestRequest::get().uri("/delete/999");
+        assert_eq!(testing::call(&pool, &config, unknown).await.status(), StatusCode::NOT_FOUND);
+        let integer = rename(ada.id.to_string());
+        let strict = testing::call(&pool, &testing::config(&[]), integer).await;
+        assert_eq!(strict.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn feed_pages_stay_stable_across_inserts() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for name in ["Ada", "Grace", "Edsger"] {
+            testing::insert(&mut conn, name, "Test", &format!("{}@example.com", name)).unwrap();
+        }
+        let config = testing::config(&[]);
+        let page = |cursor: Option<&str>| {
+            let uri = match cursor {
+                Some(cursor) => format!("/users/feed?limit=2&cursor={}", cursor),
+                None => "/users/feed?limit=2".to_string(),
+            };
+            actix_web::test::TestRequest::get().uri(&uri)
+        };
+        let names = |body: &serde_json::Value| -> Vec<String> {
+            let items = body["data"]["items"].as_array().unwrap();
+            items.iter().map(|user| user["first_name"].as_str().unwrap().to_string()).collect()
+        };
+
+        let response = testing::call(&pool, &config, page(None)).await;
+        let (_, first) = testing::json(response).await;
+        assert_eq!(names(&first), ["Ada", "Grace"]);
+        testing::insert(&mut conn, "Barbara", "Test", "barbara@example.com").unwrap();
+
+        let cursor = first["data"]["next_cursor"].as_str().unwrap();
+        let response = testing::call(&pool, &config, page(Some(cursor))).await;
+        let (_, second) = testing::json(response).await;
+        assert_eq!(names(&second), ["Edsger", "Barbara"]);
+        let cursor = second["data"]["next_cursor"].as_str().unwrap();
+        let response = testing::call(&pool, &config, page(Some(cursor))).await;
+        let (_, last) = testing::json(response).await;
+        assert_eq!(names(&last), Vec::<String>::new());
+        assert!(last["data"]["next_cursor"].is_null());
+    }
+
+    #[test]
+    fn user_id_collisions_retry_with_a_fresh_id() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let taken = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let taken = taken.user_id;
+        let fresh = Uuid::from_u128(7);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+
+        let mut ids = vec![fresh, taken, taken];
+        let user = conn
+            .transaction(|conn| {
+                let form = new_user("Grace", "Hopper", "grace@example.com");
+                insert_user_with(conn, rules, form, None, || ids.pop().unwrap())
+                    .map(|inserted| inserted.user)
+            })
+            .unwrap();
+        assert_eq!(user.user_id, fresh);
+
+        // Every attempt collides: a 500, and the transaction is still usable
+        let error = conn.transaction(|conn| {
+            let form = new_user("Edsger", "Dijkstra", "edsger@example.com");
+            let error = insert_user_with(conn, rules, form, None, || taken).unwrap_err();
+            crate::schema::users::table.count().get_result::<i64>(conn)?;
+            Ok::<_, UserError>(error)
+        });
+        let error = error.unwrap();
+        assert!(matches!(error, UserError::AddingUser));
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn email_regex_failures_are_told_apart_by_sqlstate() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        diesel::sql_query(
+            "INSERT INTO users (user_id, first_name, last_name, email) \
+             SELECT gen_random_uuid(), 'Test', 'User', 'user' || n || '@example.com' \
+             FROM generate_series(1, 50000) AS n",
+        )
+        .execute(&mut conn)
+        .unwrap();
+
+        assert_eq!(count_email_matches(&mut conn, "^user1[0-9]*@").unwrap(), 11111);
+        match count_email_matches(&mut conn, "(user") {
+            Err(UserError::BadRequest(message)) => assert!(message.contains("parentheses")),
+            other => panic!("expected a 400, got {:?}", other),
+        }
+
+        let timed_out = conn.transaction(|conn| {
+            diesel::sql_query("SET LOCAL statement_timeout = '1ms'").execute(conn)?;
+            Ok::<_, UserError>(count_email_matches(conn, "(a|aa)*b"))
+        });
+        match timed_out.unwrap() {
+            Err(UserError::BadRequest(message)) => {
+                assert_eq!(message, "pattern is too expensive to match")
+            }
+            other => panic!("expected a 400, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn empty_updates_follow_the_empty_update_policy() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let empty = || {
+            actix_web::test::TestRequest::post()
+                .uri(&format!("/update/{}", ada.user_id))
+                .set_json(serde_json::json!({}))
+        };
+
+        let config = testing::config(&[]);
+        let (status, body) = testing::json(testing::call(&pool, &config, empty()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["first_name"], "Ada");
+        let unchanged = crate::schema::users::table
+            .find(ada.id)
+            .get_result::<models::User>(&mut conn)
+            .unwrap();
+        assert_eq!(unchanged.updated_at, ada.updated_at);
+
+        let config = testing::config(&[("EMPTY_UPDATE_POLICY", "reject")]);
+        let (status, body) = testing::json(testing::call(&pool, &config, empty()).await).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "no fields to update");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn swapping_emails_passes_the_unique_index() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let config = testing::config(&[]);
+        let swap = |first: Uuid, second: Uuid| {
+            actix_web::test::TestRequest::post()
+                .uri("/users/swap-email")
+                .set_json(serde_json::json!({ "first": first, "second": second }))
+        };
+        let emails = |conn: &mut PgConnection| {
+            use crate::schema::users::dsl::*;
+            users.order(id.asc()).select(email).load::<String>(conn).unwrap()
+        };
+
+        let response = testing::call(&pool, &config, swap(ada.user_id, grace.user_id)).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][0]["email"], "grace@example.com");
+        assert_eq!(body["data"][1]["email"], "ada@example.com");
+        assert_eq!(emails(&mut conn), ["grace@example.com", "ada@example.com"]);
+
+        // A missing user is a 404 and nothing changes
+        let response = testing::call(&pool, &config, swap(ada.user_id, Uuid::from_u128(7))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(emails(&mut conn), ["grace@example.com", "ada@example.com"]);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn rank_follows_creation_order_among_active_users() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let mut inserted = Vec::new();
+        for name in ["Ada", "Grace", "Edsger", "Barbara"] {
+            let email = format!("{}@example.com", name);
+            inserted.push(testing::insert(&mut conn, name, "Test", &email).unwrap());
+        }
+        soft_delete_user(&mut conn, inserted[1].user_id).unwrap();
+        let config = testing::config(&[]);
+        let rank = |user: &models::User| {
+            let uri = format!("/users/{}/rank", user.user_id);
+            actix_web::test::TestRequest::get().uri(&uri)
+        };
+
+        for (user, expected) in [(&inserted[0], 1), (&inserted[2], 2), (&inserted[3], 3)] {
+            let response = testing::call(&pool, &config, rank(user)).await;
+            let (status, body) = testing::json(response).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["data"]["rank"], expected, "{}", user.first_name);
+        }
+        let deleted = testing::call(&pool, &config, rank(&inserted[1])).await;
+        assert_eq!(deleted.status(), StatusCode::NOT_FOUND);
+    }
+
+    fn policy_pool(policy: &str) -> Option<web::Data<DbPool>> {
+        let config = testing::config(&[("POOL_EXHAUSTION_POLICY", policy)]);
+        testing::pool(1, config.pool_get_timeout()).map(web::Data::new)
+    }
+
+    #[test]
+    fn wait_policy_queues_for_a_connection() {
+        let _shared = testing::lock();
+        let Some(pool) = policy_pool("wait") else { return };
+
+        let held = get_conn_from_db(pool.clone()).unwrap();
+        let waiter = {
+            let pool = pool.clone();
+            std::thread::spawn(move || get_conn_from_db(pool).map(drop))
+        };
+        std::thread::sleep(Duration::from_millis(200));
+        drop(held);
+        assert!(waiter.join().unwrap().is_ok());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn fail_fast_policy_answers_503_under_contention() {
+        let _shared = testing::lock();
+        let Some(pool) = policy_pool("fail_fast") else { return };
+
+        let held = get_conn_from_db(pool.clone()).unwrap();
+        let started = std::time::Instant::now();
+        let config = testing::config(&[("POOL_EXHAUSTION_POLICY", "fail_fast")]);
+        let get = actix_web::test::TestRequest::get().uri("/get");
+        let response = testing::call(&pool, &config, get).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        assert_eq!(response.headers().get("X-RateLimit-Limit").unwrap(), "1");
+        assert_eq!(response.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+
+        drop(held);
+        let get = actix_web::test::TestRequest::get().uri("/get");
+        assert_eq!(testing::call(&pool, &config, get).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn estimated_totals_are_flagged() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for name in ["Ada", "Grace", "Edsger"] {
+            testing::insert(&mut conn, name, "Test", &format!("{}@example.com", name)).unwrap();
+        }
+        diesel::sql_query("ANALYZE users").execute(&mut conn).unwrap();
+        let config = testing::config(&[]);
+        let list = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+
+        let response = testing::call(&pool, &config, list("/get?estimate_total=true")).await;
+        let (status, estimated) = testing::json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(estimated["data"]["total_estimated"], true);
+        assert!(estimated["data"]["total"].as_i64().unwrap() >= 1);
+
+        let (_, exact) = testing::json(testing::call(&pool, &config, list("/get")).await).await;
+        assert!(exact["data"].get("total_estimated").is_none());
+        assert_eq!(exact["data"]["total"], 3);
+    }
+
+    fn create_with(
+        conn: &mut PgConnection,
+        rules: UniquenessRules,
+        conflict: models::BatchConflict,
+        user: models::NewUser,
+    ) -> Result<BatchOpApplied, UserError> {
+        let settings = BatchSettings {
+            rules,
+            mode: models::BatchMode::Atomic,
+            conflict,
+            retries: 0,
+        };
+        conn.transaction(|conn| apply_batch_op(conn, settings, models::BatchOp::Create { user }))
+    }
+
+    #[test]
+    fn batch_create_conflicts_follow_the_strategy() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let again = || new_user("Augusta", "King", "ada@example.com");
+
+        let error = create_with(&mut conn, rules, models::BatchConflict::Error, again());
+        assert!(matches!(error, Err(UserError::Conflict(_))));
+        let skipped = create_with(&mut conn, rules, models::BatchConflict::Skip, again()).unwrap();
+        assert!(matches!(skipped, (None, Some(models::CreateOutcome::Skipped), _)));
+
+        let (updated, outcome, _) =
+            create_with(&mut conn, rules, models::BatchConflict::Update, again()).unwrap();
+        assert!(matches!(outcome, Some(models::CreateOutcome::Updated)));
+        let updated = updated.unwrap();
+        assert_eq!((updated.id, updated.first_name.as_str()), (ada.id, "Augusta"));
+    }
+
+    #[test]
+    fn batch_update_picks_the_duplicate_by_the_policys_key() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let king = testing::insert(&mut conn, "Ada", "King", "ada@example.com").unwrap();
+
+        let (updated, _, _) = create_with(
+            &mut conn,
+            rules,
+            models::BatchConflict::Update,
+            new_user("Ada", "King", "ada@example.com"),
+        )
+        .unwrap();
+        assert_eq!(updated.unwrap().id, king.id);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn metadata_is_merged_key_by_key() {
+        use actix_web::test::TestRequest;
+
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let config = testing::config(&[]);
+        let merge = |body: serde_json::Value| {
+            TestRequest::post().uri(&format!("/users/{}/metadata", ada.user_id)).set_json(body)
+        };
+
+        let first = merge(serde_json::json!({ "team": "core", "level": 2 }));
+        assert_eq!(testing::call(&pool, &config, first).await.status(), StatusCode::OK);
+        let second = merge(serde_json::json!({ "level": null, "lang": "de" }));
+        let (status, body) = testing::json(testing::call(&pool, &config, second).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let merged = serde_json::json!({ "team": "core", "level": null, "lang": "de" });
+        assert_eq!(body["data"]["metadata"], merged);
+
+        let get = TestRequest::get().uri(&format!("/get/{}", ada.user_id));
+        let (_, body) = testing::json(testing::call(&pool, &config, get).await).await;
+        assert_eq!(body["data"]["metadata"], merged);
+
+        for invalid in [serde_json::json!(["team"]), serde_json::json!("core")] {
+            let response = testing::call(&pool, &config, merge(invalid)).await;
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn meta_filters_are_combined_with_and() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for (name, metadata) in [
+            ("Ada", r#"{"team": "core", "lang": "de"}"#),
+            ("Grace", r#"{"team": "core", "lang": "en"}"#),
+            ("Edsger", r#"{"team": "web", "lang": "de"}"#),
+        ] {
+            let user = testing::insert(&mut conn, name, "Test", &format!("{}@example.com", name));
+            diesel::sql_query("UPDATE users SET metadata = $1::jsonb WHERE id = $2")
+                .bind::<Text, _>(metadata)
+                .bind::<Integer, _>(user.unwrap().id)
+                .execute(&mut conn)
+                .unwrap();
+        }
+        let config = testing::config(&[]);
+        let names = |body: serde_json::Value| -> Vec<String> {
+            let items = body["data"]["items"].as_array().unwrap().clone();
+            items.iter().map(|user| user["first_name"].as_str().unwrap().to_string()).collect()
+        };
+        let list = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+
+        let response = testing::call(&pool, &config, list("/get?meta.team=core")).await;
+        assert_eq!(names(testing::json(response).await.1), ["Ada", "Grace"]);
+        let both = list("/get?meta.team=core&meta.lang=de");
+        let response = testing::call(&pool, &config, both).await;
+        assert_eq!(names(testing::json(response).await.1), ["Ada"]);
+        let response = testing::call(&pool, &config, list("/get?meta.=core")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn display_name_is_maintained_and_searchable() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let cher = testing::insert(&mut conn, "Cher", "", "cher@example.com").unwrap();
+        testing::insert(&mut conn, "Ada", "100% Lovelace", "ada@example.com").unwrap();
+        assert_eq!(grace.display_name, "Grace Hopper");
+        assert_eq!(cher.display_name, "Cher");
+
+        let changes = models::UpdateUser {
+            first_name: Some("Amazing".to_string()),
+            last_name: None,
+            email: None,
+        };
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        let renamed = conn
+            .transaction(|conn| update_active_user(conn, rules, grace.user_id, &changes))
+            .unwrap();
+        assert_eq!(renamed.unwrap().display_name, "Amazing Hopper");
+
+        let config = testing::config(&[]);
+        let names = |body: serde_json::Value| -> Vec<String> {
+            let items = body["data"]["items"].as_array().unwrap().clone();
+            items.iter().map(|user| user["display_name"].as_str().unwrap().to_string()).collect()
+        };
+        let list = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+
+        let response = testing::call(&pool, &config, list("/get?sort=display_name")).await;
+        let sorted = names(testing::json(response).await.1);
+        assert_eq!(sorted, ["Ada 100% Lovelace", "Amazing Hopper", "Cher"]);
+        let response = testing::call(&pool, &config, list("/get?name=%25%20love")).await;
+        assert_eq!(names(testing::json(response).await.1), ["Ada 100% Lovelace"]);
+        let response = testing::call(&pool, &config, list("/get?name=a%25")).await;
+        assert_eq!(names(testing::json(response).await.1), Vec::<String>::new());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn get_or_create_returns_the_user_with_the_same_name_and_email() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let byron = testing::insert(&mut conn, "Ada", "Byron", "ada@example.com").unwrap();
+
+        let config = testing::config(&[("UNIQUENESS_POLICY", "name_email")]);
+        let get_or_create = |first: &str, last: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/users/get-or-create")
+                .set_json(serde_json::json!({
+                    "first_name": first,
+                    "last_name": last,
+                    "email": "ada@example.com",
+                }))
+        };
+
+        let response = testing::call(&pool, &config, get_or_create("Ada", "Byron")).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["user_id"], byron.user_id.to_string());
+
+        let response = testing::call(&pool, &config, get_or_create("Ada", "King")).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["data"]["last_name"], "King");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn concurrent_get_or_create_inserts_one_user() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(3, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        let config = testing::config(&[]);
+        let get_or_create = |email: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/users/get-or-create")
+                .set_json(serde_json::json!({
+                    "first_name": "Ada",
+                    "last_name": "Lovelace",
+                    "email": email,
+                }))
+        };
+
+        let (first, second) = futures_util::future::join(
+            testing::call(&pool, &config, get_or_create("ada@example.com")),
+            testing::call(&pool, &config, get_or_create("ada@example.com")),
+        )
+        .await;
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::CREATED]);
+        let stored: i64 = crate::schema::users::table.count().get_result(&mut conn).unwrap();
+        assert_eq!(stored, 1);
+
+        // Under UNIQUENESS_POLICY=email a deleted user keeps its email
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        diesel::sql_query("UPDATE users SET deleted_at = now() WHERE id = $1")
+            .bind::<Integer, _>(grace.id)
+            .execute(&mut conn)
+            .unwrap();
+        let response = testing::call(&pool, &config, get_or_create("grace@example.com")).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["message"], "A user with this email already exists");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn item_ranges_answer_206_or_416() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for n in 0..3 {
+            let address = format!("ada{}@example.com", n);
+            testing::insert(&mut conn, "Ada", "Lovelace", &address).unwrap();
+        }
+
+        let config = testing::config(&[]);
+        let ranged = |range: &str| {
+            actix_web::test::TestRequest::get()
+                .uri("/get")
+                .insert_header((header::RANGE, range.to_string()))
+        };
+        let content_range = |response: &actix_web::dev::ServiceResponse| {
+            response.headers().get(header::CONTENT_RANGE).unwrap().to_str().unwrap().to_string()
+        };
+
+        let response = testing::call(&pool, &config, ranged("items=1-")).await;
+        assert_eq!(content_range(&response), "items 1-2/3");
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body["data"][0]["email"], "ada1@example.com");
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+
+        let response = testing::call(&pool, &config, ranged("items=3-9")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(content_range(&response), "items */3");
+        let response = testing::call(&pool, &config, ranged("items=2-1")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn precheck_lists_every_conflicting_email_before_inserting() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        let create = |first: &str, email: &str| {
+            serde_json::json!({
+                "op": "create",
+                "user": {"first_name": first, "last_name": "Doe", "email": email},
+            })
+        };
+        let ops = serde_json::json!([
+            create("Bob", "bob@example.com"),
+            create("Grace", "grace@example.com"),
+            create("Grace", "grace@example.com"),
+            create("Ada", "ada@example.com"),
+        ]);
+        let config = testing::config(&[]);
+        let batch = |uri: &str| actix_web::test::TestRequest::post().uri(uri).set_json(ops.clone());
+
+        let response = testing::call(&pool, &config, batch("/users/batch-ops?precheck=true")).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let message = body["message"].as_str().unwrap();
+        assert!(message.contains("grace@example.com"), "{}", message);
+        assert!(message.contains("ada@example.com"), "{}", message);
+        assert!(!message.contains("bob@example.com"), "{}", message);
+        let stored = crate::schema::users::table.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(stored, 1);
+
+        let uri = "/users/batch-ops?precheck=true&conflict=skip";
+        let response = testing::call(&pool, &config, batch(uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn created_by_names_the_principal_of_the_request() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+
+        let config = testing::config(&[("ADMIN_KEY", "s3cret")]);
+        let add = |email: &str, key: Option<&str>| {
+            let request = actix_web::test::TestRequest::post().uri("/add").set_json(
+                serde_json::json!({"first_name": "Ada", "last_name": "Lovelace", "email": email}),
+            );
+            match key {
+                Some(key) => request.insert_header(("X-Admin-Key", key.to_string())),
+                None => request,
+            }
+        };
+
+        for (email, key, creator) in [
+            ("admin@example.com", Some("s3cret"), serde_json::json!("admin")),
+            ("wrong@example.com", Some("guess"), serde_json::Value::Null),
+            ("anonymous@example.com", None, serde_json::Value::Null),
+        ] {
+            let response = testing::call(&pool, &config, add(email, key)).await;
+            let (status, body) = testing::json(response).await;
+            assert_eq!(status, StatusCode::OK, "{}", email);
+            assert_eq!(body["data"][0]["created_by"], creator, "{}", email);
+        }
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn search_ranks_exact_matches_first() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        testing::insert(&mut conn, "Joanna", "Ray", "joanna@example.com").unwrap();
+        testing::insert(&mut conn, "Bob", "Stone", "annex@example.com").unwrap();
+        testing::insert(&mut conn, "Annie", "Hall", "annie@example.com").unwrap();
+        testing::insert(&mut conn, "Ann", "Lee", "lee@example.com").unwrap();
+        testing::insert(&mut conn, "Carl", "Berg", "carl@example.com").unwrap();
+
+        let config = testing::config(&[]);
+        let search = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+        let names = |body: serde_json::Value| -> Vec<String> {
+            let items = body["data"]["items"].as_array().unwrap().clone();
+            items.iter().map(|user| user["display_name"].as_str().unwrap().to_string()).collect()
+        };
+
+        let response = testing::call(&pool, &config, search("/users/search?q=ANN")).await;
+        let ranked = names(testing::json(response).await.1);
+        assert_eq!(ranked, ["Ann Lee", "Annie Hall", "Bob Stone", "Joanna Ray"]);
+
+        let uri = "/users/search?q=ann&page=2&per_page=2";
+        let response = testing::call(&pool, &config, search(uri)).await;
+        assert_eq!(names(testing::json(response).await.1), ["Bob Stone", "Joanna Ray"]);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn best_effort_batches_report_a_status_per_operation() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        let grace = serde_json::json!({
+            "first_name": "Grace",
+            "last_name": "Hopper",
+            "email": "grace@example.com",
+        });
+        let ops = serde_json::json!([
+            {"op": "create", "user": grace},
+            {"op": "update", "user_id": Uuid::new_v4(), "changes": {"first_name": "Nobody"}},
+            {
+                "op": "create",
+                "user": {"first_name": " ", "last_name": "Turing", "email": "alan@example.com"},
+            },
+            {"op": "delete", "user_id": ada.user_id},
+        ]);
+        let config = testing::config(&[]);
+        let batch = |uri: &str| actix_web::test::TestRequest::post().uri(uri).set_json(ops.clone());
+        let active = |conn: &mut PgConnection| {
+            use crate::schema::users::dsl::*;
+            users.filter(deleted_at.is_null()).select(email).load::<String>(conn).unwrap()
+        };
+
+        let response = testing::call(&pool, &config, batch("/users/batch-ops?mode=atomic")).await;
+        assert!(response.status().is_client_error());
+        assert_eq!(active(&mut conn), ["ada@example.com"]);
+
+        let uri = "/users/batch-ops?mode=best_effort";
+        let (status, body) = testing::json(testing::call(&pool, &config, batch(uri)).await).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        let statuses: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| (result["ok"].as_bool().unwrap(), result["status"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(statuses, [(true, 201), (false, 404), (false, 422), (true, 200)]);
+        assert!(body["data"][1]["error"].is_string());
+        assert_eq!(active(&mut conn), ["grace@example.com"]);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn histogram_fills_empty_buckets() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for (n, created) in ["10:00:10", "10:00:50", "10:02:30", "11:40:00"].iter().enumerate() {
+            let address = format!("ada{}@example.com", n);
+            let user = testing::insert(&mut conn, "Ada", "Lovelace", &address).unwrap();
+            diesel::sql_query("UPDATE users SET created_at = $2::timestamp WHERE id = $1")
+                .bind::<Integer, _>(user.id)
+                .bind::<Text, _>(format!("2026-01-01 {}", created))
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        let config = testing::config(&[]);
+        let histogram = |query: &str| {
+            actix_web::test::TestRequest::get().uri(&format!("/users/histogram?{}", query))
+        };
+        let buckets = |body: serde_json::Value| -> Vec<(String, i64)> {
+            let buckets = body["data"].as_array().unwrap().clone();
+            buckets
+                .iter()
+                .map(|bucket| {
+                    let start = bucket["bucket_start"].as_str().unwrap();
+                    (start[11..16].to_string(), bucket["count"].as_i64().unwrap())
+                })
+                .collect()
+        };
+
+        let request = histogram("bucket=minute&to=2026-01-01T10:04:00Z");
+        let (status, body) = testing::json(testing::call(&pool, &config, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        let expected = [("10:00", 2), ("10:01", 0), ("10:02", 1), ("10:03", 0), ("10:04", 0)];
+        assert_eq!(buckets(body), expected.map(|(start, count)| (start.to_string(), count)));
+
+        let request = histogram("bucket=hour");
+        let (_, body) = testing::json(testing::call(&pool, &config, request).await).await;
+        assert_eq!(buckets(body), [("10:00".to_string(), 3), ("11:00".to_string(), 1)]);
+
+        let response = testing::call(&pool, &config, histogram("bucket=week")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Renames the user in a transaction that holds its row for a while, and
+    // updates the last name through the API meanwhile. Returns the changed
+    // fields the API reported.
+    async fn update_during_rename(row_lock: &str) -> Option<Vec<String>> {
+        let pool = testing::pool(3, Duration::from_secs(5))?;
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        let renaming = {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                let mut conn = pool.get().unwrap();
+                conn.transaction(|conn| {
+                    diesel::sql_query("UPDATE users SET first_name = 'Augusta' WHERE id = $1")
+                        .bind::<Integer, _>(ada.id)
+                        .execute(conn)?;
+                    std::thread::sleep(Duration::from_millis(500));
+                    Ok::<_, DieselError>(())
+                })
+                .unwrap();
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+
+        let config = testing::config(&[("UPDATE_ROW_LOCK", row_lock)]);
+        let request = actix_web::test::TestRequest::post()
+            .uri(&format!("/update/{}?changed_only=true", ada.user_id))
+            .set_json(serde_json::json!({ "last_name": "King" }));
+        let (status, body) = testing::json(testing::call(&pool, &config, request).await).await;
+        renaming.join().unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let stored = users_by_id(&mut conn, ada.id);
+        assert_eq!((stored.first_name.as_str(), stored.last_name.as_str()), ("Augusta", "King"));
+        let mut changed: Vec<String> = body["data"].as_object().unwrap().keys().cloned().collect();
+        changed.sort();
+        Some(changed)
+    }
+
+    fn users_by_id(conn: &mut PgConnection, user: i32) -> models::User {
+        use crate::schema::users::dsl::*;
+        users.filter(id.eq(user)).first(conn).unwrap()
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn row_lock_makes_updates_apply_one_after_the_other() {
+        let _shared = testing::lock();
+
+        // The update waits for the rename and only reports its own change
+        let Some(locked) = update_during_rename("true").await else { return };
+        assert_eq!(locked, ["display_name", "last_name", "updated_at", "user_id"]);
+
+        // Without the lock the update read the user before the rename committed
+        let unlocked = update_during_rename("false").await.unwrap();
+        let expected = ["display_name", "first_name", "last_name", "updated_at", "user_id"];
+        assert_eq!(unlocked, expected);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn lookup_finds_exactly_one_active_user() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        testing::insert(&mut conn, "Grace", "Brewster", "grace@example.com").unwrap();
+
+        let config = testing::config(&[("UNIQUENESS_POLICY", "name_email")]);
+        let lookup = |query: &str| {
+            actix_web::test::TestRequest::get().uri(&format!("/users/lookup?{}", query))
+        };
+
+        let request = lookup("key=email&value=%20ada@example.com");
+        let (status, body) = testing::json(testing::call(&pool, &config, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["user_id"], ada.user_id.to_string());
+
+        for (query, status) in [
+            ("key=email&value=grace@example.com", StatusCode::CONFLICT),
+            ("key=email&value=nobody@example.com", StatusCode::NOT_FOUND),
+            ("key=first_name&value=Ada", StatusCode::BAD_REQUEST),
+        ] {
+            let response = testing::call(&pool, &config, lookup(query)).await;
+            assert_eq!(response.status(), status, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn conditional_batch_gets_send_only_changed_users() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let config = testing::config(&[]);
+
+        let get = actix_web::test::TestRequest::get().uri(&format!("/get/{}", ada.user_id));
+        let response = testing::call(&pool, &config, get).await;
+        let ada_etag = response.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+        let grace_etag = grace.etag().to_string();
+        diesel::sql_query("UPDATE users SET last_name = 'Brewster' WHERE id = $1")
+            .bind::<Integer, _>(grace.id)
+            .execute(&mut conn)
+            .unwrap();
+
+        let missing = Uuid::new_v4();
+        let request = actix_web::test::TestRequest::post()
+            .uri("/users/batch-get-conditional")
+            .set_json(serde_json::json!([
+                {"user_id": ada.user_id, "etag": ada_etag},
+                {"user_id": grace.user_id, "etag": grace_etag},
+                {"user_id": missing},
+            ]));
+        let (status, body) = testing::json(testing::call(&pool, &config, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["data"][0],
+            serde_json::json!({"user_id": ada.user_id, "not_modified": true})
+        );
+        assert_eq!(body["data"][1]["user"]["last_name"], "Brewster");
+        assert_ne!(body["data"][1]["etag"], grace_etag);
+        assert_eq!(body["data"][2], serde_json::json!({"user_id": missing, "not_found": true}));
+    }
+
+    #[test]
+    fn users_are_annotated_with_the_mx_status_of_their_domain() {
+        let with_email = |id, email: &str| models::User {
+            email: email.to_string(),
+            ..testing::user(id)
+        };
+        let found = Some(HashMap::from([
+            ("example.com".to_string(), true),
+            ("bounced.example".to_string(), false),
+        ]));
+        let annotated = |user| serde_json::to_value(annotate(user, &found)).unwrap();
+
+        assert_eq!(annotated(with_email(1, "ada@example.com"))["email_deliverable"], true);
+        assert_eq!(annotated(with_email(2, "grace@bounced.example"))["email_deliverable"], false);
+        let unknown = annotated(with_email(3, "edsger@unknown.example"));
+        assert!(unknown["email_deliverable"].is_null());
+        assert!(unknown.as_object().unwrap().contains_key("email_deliverable"));
+        assert_eq!(annotated(with_email(4, "no-domain"))["email_deliverable"], false);
+
+        let unchecked = serde_json::to_value(annotate(with_email(5, "ada@example.com"), &None));
+        assert!(!unchecked.unwrap().as_object().unwrap().contains_key("email_deliverable"));
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn input_failing_both_checks_reports_them_as_configured() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let codes = |body: &serde_json::Value| -> Vec<String> {
+            body["errors"]
+                .as_array()
+                .map(|errors| errors.iter().map(|e| e["code"].as_str().unwrap().to_string()))
+                .into_iter()
+                .flatten()
+                .collect()
+        };
+
+        // The user added has two malformed fields, the update one
+        for (order, errors, status, added_codes, updated_codes) in [
+            ("format_first", "first", StatusCode::UNPROCESSABLE_ENTITY, vec![], vec![]),
+            ("uniqueness_first", "first", StatusCode::CONFLICT, vec![], vec![]),
+            (
+                "format_first",
+                "all",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                vec!["validation_failed", "validation_failed", "conflict"],
+                vec!["validation_failed", "conflict"],
+            ),
+            (
+                "uniqueness_first",
+                "all",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                vec!["conflict", "validation_failed", "validation_failed"],
+                vec!["conflict", "validation_failed"],
+            ),
+        ] {
+            let config =
+                testing::config(&[("VALIDATION_ORDER", order), ("VALIDATION_ERRORS", errors)]);
+            let add = actix_web::test::TestRequest::post().uri("/add").set_json(serde_json::json!({
+                "first_name": " ",
+                "last_name": "Love\tlace",
+                "email": "ada@example.com",
+            }));
+            let (added, body) = testing::json(testing::call(&pool, &config, add).await).await;
+            assert_eq!(added, status, "{} {}", order, errors);
+            assert_eq!(codes(&body), added_codes, "{} {}", order, errors);
+
+            let update = actix_web::test::TestRequest::post()
+                .uri(&format!("/update/{}", grace.user_id))
+                .set_json(serde_json::json!({"first_name": "Gr\tace", "email": "ada@example.com"}));
+            let (updated, body) = testing::json(testing::call(&pool, &config, update).await).await;
+            assert_eq!(updated, status, "{} {}", order, errors);
+            assert_eq!(codes(&body), updated_codes, "{} {}", order, errors);
+        }
+
+        // A single failure is reported as it is, even with VALIDATION_ERRORS=all
+        let config = testing::config(&[("VALIDATION_ERRORS", "all")]);
+        let add = actix_web::test::TestRequest::post().uri("/add").set_json(serde_json::json!({
+            "first_name": "Augusta",
+            "last_name": "King",
+            "email": "ada@example.com",
+        }));
+        let (status, body) = testing::json(testing::call(&pool, &config, add).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.get("errors").is_none());
+        let stored: i64 = crate::schema::users::table.count().get_result(&mut conn).unwrap();
+        assert_eq!(stored, 2);
+    }
+
+    #[test]
+    fn the_last_label_sheet_is_padded_to_a_full_sheet() {
+        let labels = |count: usize| -> Vec<models::Label> {
+            (0..count)
+                .map(|n| models::Label {
+                    first_name: "Ada".to_string(),
+                    last_name: "Lovelace".to_string(),
+                    email: format!("ada{}@example.com", n),
+                })
+                .collect()
+        };
+        let emails = |sheets: Vec<Vec<Option<models::Label>>>| -> Vec<Vec<Option<String>>> {
+            sheets
+                .into_iter()
+                .map(|sheet| sheet.into_iter().map(|label| label.map(|l| l.email)).collect())
+                .collect()
+        };
+        let email = |n: usize| Some(format!("ada{}@example.com", n));
+
+        assert_eq!(
+            emails(label_sheets(labels(5), 3)),
+            [vec![email(0), email(1), email(2)], vec![email(3), email(4), None]]
+        );
+        assert_eq!(emails(label_sheets(labels(1), 4)), [vec![email(0), None, None, None]]);
+        assert_eq!(
+            emails(label_sheets(labels(6), 3)),
+            [vec![email(0), email(1), email(2)], vec![email(3), email(4), email(5)]]
+        );
+        assert!(label_sheets(labels(0), 3).is_empty());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn nearest_email_needs_fuzzystrmatch() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let installed = has_levenshtein(&mut conn).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let config = testing::config(&[]);
+        let nearest = || {
+            actix_web::test::TestRequest::get().uri("/users/nearest-email?email=ada@exmaple.com")
+        };
+
+        diesel::sql_query("DROP EXTENSION IF EXISTS fuzzystrmatch").execute(&mut conn).unwrap();
+        let (status, body) = testing::json(testing::call(&pool, &config, nearest()).await).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["code"], "unavailable");
+
+        diesel::sql_query("CREATE EXTENSION fuzzystrmatch").execute(&mut conn).unwrap();
+        let (status, body) = testing::json(testing::call(&pool, &config, nearest()).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["user"]["email"], "ada@example.com");
+        assert_eq!(body["data"]["distance"], 2);
+
+        if !installed {
+            diesel::sql_query("DROP EXTENSION fuzzystrmatch").execute(&mut conn).unwrap();
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, AcceptLanguage, Header, HeaderValue, Preference};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+use crate::config::ErrorEnvelope;
+use crate::user_error::{UserError, ERROR_ENVELOPE};
+
+// Languages error messages are available in. English is the fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    De,
+}
+
+impl Locale {
+    fn tag(self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+        }
+    }
+}
+
+// The highest ranked supported language of an Accept-Language header
+pub fn negotiate(req: &ServiceRequest) -> Locale {
+    let ranked = match AcceptLanguage::parse(req) {
+        Ok(accept) => accept.ranked(),
+        Err(_) => return Locale::En,
+    };
+
+    ranked
+        .iter()
+        .find_map(|preference| match preference {
+            Preference::Any => Some(Locale::En),
+            Preference::Specific(tag) => match tag.primary_language() {
+                "en" => Some(Locale::En),
+                "de" => Some(Locale::De),
+                _ => None,
+            },
+        })
+        .unwrap_or(Locale::En)
+}
+
+// The message catalog. Messages that carry details built by the handler
+// (validation failures, conflicts) keep those details untranslated.
+pub fn message(error: &UserError, locale: Locale) -> String {
+    match locale {
+        Locale::En => error.to_string(),
+        Locale::De => match error {
+            UserError::NotFound => "Benutzer nicht gefunden".to_string(),
+            UserError::JobNotFound => "Auftrag nicht gefunden".to_string(),
+            UserError::AddingUser => "Fehler beim Anlegen des Benutzers".to_string(),
+            UserError::Blocking(_) => {
+                "Die Anfrage ist unerwartet fehlgeschlagen, bitte erneut versuchen".to_string()
+            }
+            UserError::Forbidden => "Zugriff verweigert".to_string(),
+            UserError::TooManyRequests(_) => "Zu viele gleichzeitige Anfragen".to_string(),
+            UserError::PoolExhausted(_) => {
+                "Keine Datenbankverbindung verfügbar, bitte erneut versuchen".to_string()
+            }
+            UserError::RangeNotSatisfiable(_) => {
+                "Der angeforderte Bereich ist nicht verfügbar".to_string()
+            }
+            UserError::ResponseTooLarge(max) => format!(
+                "Die Antwort wäre größer als {} Bytes, bitte kleinere Seiten mit per_page anfordern oder /users/sync-stream verwenden",
+                max
+            ),
+            UserError::PreconditionFailed => {
+                "Der Benutzer wurde nach dem If-Unmodified-Since-Datum geändert".to_string()
+            }
+            UserError::BatchOperation(index, e) => {
+                format!("Operation {} fehlgeschlagen: {}", index, message(e, locale))
+            }
+            UserError::DieselError(diesel_error) => format!("Datenbankfehler: {}", diesel_error),
+            UserError::ValidationMany(failures) => {
+                let messages: Vec<String> = failures.iter().map(|e| message(e, locale)).collect();
+                messages.join("; ")
+            }
+            UserError::BadRequest(detail)
+            | UserError::Validation(detail)
+            | UserError::Conflict(detail)
+            | UserError::Unavailable(detail) => detail.clone(),
+        },
+    }
+}
+
+// Renders `UserError` responses in the language asked for with
+// Accept-Language. The status code is the same in every language, so clients
+// should branch on it rather than on the message. With ERROR_ENVELOPE=problem
+// every error is rendered here, since problem+json needs the request path.
+pub async fn localize_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let locale = negotiate(&req);
+    let problem = ERROR_ENVELOPE.get() == Some(&ErrorEnvelope::Problem);
+    let res = next.call(req).await?;
+
+    let rendered = match (locale, problem) {
+        (Locale::En, false) => None,
+        _ => res
+            .response()
+            .error()
+            .and_then(|e| e.as_error::<UserError>())
+            .map(|e| e.render(message(e, locale), Some(res.request().path()))),
+    };
+
+    match rendered {
+        Some(mut response) => {
+            if locale != Locale::En {
+                let headers = response.headers_mut();
+                headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag()));
+                headers.insert(header::VARY, HeaderValue::from_static("Accept-Language"));
+            }
+            Ok(res.into_response(response).map_into_right_body())
+        }
+        None => Ok(res.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App};
+
+    async fn not_found(accept_language: &str) -> (Option<String>, serde_json::Value) {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(localize_errors))
+                .route("/", web::get().to(|| async { Err::<String, _>(UserError::NotFound) })),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((header::ACCEPT_LANGUAGE, accept_language))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let language = res
+            .headers()
+            .get(header::CONTENT_LANGUAGE)
+            .map(|value| value.to_str().unwrap().to_string());
+        (language, test::read_body_json(res).await)
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn errors_are_in_german_when_asked_for() {
+        let _shared = crate::testing::lock();
+        let (language, body) = not_found("de-DE, en;q=0.5").await;
+
+        assert_eq!(language.as_deref(), Some("de"));
+        assert_eq!(body["message"], "Benutzer nicht gefunden");
+        assert_eq!(body["code"], "not_found");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn errors_fall_back_to_english() {
+        let _shared = crate::testing::lock();
+        let (language, body) = not_found("fr").await;
+
+        assert_eq!(language, None);
+        assert_eq!(body["message"], "User not found");
+        assert_eq!(body["code"], "not_found");
+    }
+}
@@ -0,0 +1,452 @@
+use std::io::{self, Read};
+
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+use futures_util::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::config::AppConfig;
+use crate::events::{ChangeEvent, ChangeFeed};
+use crate::handler::{find_active_by_email, get_conn_from_db, insert_user, update_active_user};
+use crate::uniqueness::UniquenessRules;
+use crate::{models, user_error::UserError, validation, DbPool};
+
+// Rows inserted per transaction
+const IMPORT_BATCH_SIZE: usize = 500;
+// Row errors listed in the report, later ones are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+// Row outcomes listed with `on_duplicate=merge`
+const MAX_REPORTED_ROWS: usize = 1000;
+// Body chunks buffered between the request and the parser
+const CHUNK_BUFFER: usize = 16;
+// The columns read from each row, in their default order without a header
+const IMPORT_COLUMNS: [&str; 3] = ["first_name", "last_name", "email"];
+// The example row of `/users/import-template.csv`, matching IMPORT_COLUMNS
+const TEMPLATE_EXAMPLE: [&str; 3] = ["Jane", "Doe", "jane.doe@example.com"];
+
+// The request body as read by the blocking CSV parser, chunk by chunk as it
+// arrives, so only CHUNK_BUFFER chunks are held at a time
+struct BodyReader {
+    chunks: mpsc::Receiver<Result<web::Bytes, String>>,
+    current: web::Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(Ok(chunk)) => self.current = chunk,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current.split_to(n));
+        Ok(n)
+    }
+}
+
+// A validated row. With `on_duplicate=merge` the names may be missing, as
+// long as the email belongs to an existing user.
+enum ImportRow {
+    Insert(models::NewUser),
+    Merge(models::UpdateUser),
+}
+
+// Imports users from a CSV body, either raw (`text/csv`) or as the file of a
+// multipart upload. Rows are validated one by one and inserted in
+// transactions of IMPORT_BATCH_SIZE rows; a failing row is reported and
+// skipped without failing the rest of its batch.
+pub async fn import_csv(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    feed: web::Data<ChangeFeed>,
+    query: web::Query<models::CsvImportQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, UserError> {
+    let (sender, chunks) = mpsc::channel(CHUNK_BUFFER);
+    let reader = BodyReader {
+        chunks,
+        current: web::Bytes::new(),
+    };
+    let rules = config.uniqueness();
+    let query = query.into_inner();
+
+    // Runs while the body is forwarded below
+    let import = web::block(move || import_rows(pool, &feed, rules, &query, reader));
+
+    let multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    if multipart {
+        let mut fields = Multipart::new(req.headers(), payload);
+        let file = loop {
+            match fields.next().await {
+                Some(Ok(field)) if is_file(&field) => break Some(field),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    let _ = sender.send(Err(e.to_string())).await;
+                    break None;
+                }
+                None => break None,
+            }
+        };
+        match file {
+            Some(file) => forward(file, &sender).await,
+            None => {
+                drop(sender);
+                let _ = import.await;
+                return Err(UserError::BadRequest("no CSV file in the upload".to_string()));
+            }
+        }
+    } else {
+        forward(payload, &sender).await;
+    }
+    drop(sender);
+
+    let report = import.await.map_err(UserError::from)??;
+    let message = match report.merged + report.unchanged {
+        0 => format!("Imported {} users, {} rows failed", report.inserted, report.failed),
+        _ => format!(
+            "Imported {} users, merged {}, {} unchanged, {} rows failed",
+            report.inserted, report.merged, report.unchanged, report.failed
+        ),
+    };
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message,
+        data: Some(report),
+        warnings: Vec::new(),
+    }))
+}
+
+// The upload field holding the CSV: the first one with a filename, or one
+// named `file`
+fn is_file(field: &actix_multipart::Field) -> bool {
+    field.name() == Some("file")
+        || field
+            .content_disposition()
+            .is_some_and(|disposition| disposition.get_filename().is_some())
+}
+
+// Stops early if the parser has given up
+async fn forward<S, B, E>(mut body: S, sender: &mpsc::Sender<Result<web::Bytes, String>>)
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Into<web::Bytes>,
+    E: std::fmt::Display,
+{
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map(Into::into).map_err(|e| e.to_string());
+        let failed = chunk.is_err();
+        if sender.send(chunk).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+// A CSV with the header row `/users/import.csv` expects and one example row
+pub async fn import_template() -> Result<HttpResponse, UserError> {
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    csv.write_record(IMPORT_COLUMNS)
+        .and_then(|_| csv.write_record(TEMPLATE_EXAMPLE))
+        .map_err(|e| UserError::Unavailable(format!("Error writing the template: {}", e)))?;
+    let body = csv
+        .into_inner()
+        .map_err(|e| UserError::Unavailable(format!("Error writing the template: {}", e)))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"import-template.csv\"",
+        ))
+        .body(body))
+}
+
+// Index of a field's column. With a header row `column` is a header name,
+// compared case-insensitively; without one it is a 0-based index.
+fn column_index(
+    headers: Option<&csv::StringRecord>,
+    column: Option<&str>,
+    field: &str,
+    default_index: usize,
+) -> Result<usize, UserError> {
+    match headers {
+        Some(headers) => {
+            let name = column.unwrap_or(field);
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(|| UserError::BadRequest(format!("no {:?} column for {}", name, field)))
+        }
+        None => match column {
+            Some(index) => index.trim().parse().map_err(|_| {
+                UserError::BadRequest(format!("{}_column must be a column index", field))
+            }),
+            None => Ok(default_index),
+        },
+    }
+}
+
+fn import_rows(
+    pool: web::Data<DbPool>,
+    feed: &ChangeFeed,
+    rules: UniquenessRules,
+    query: &models::CsvImportQuery,
+    reader: BodyReader,
+) -> Result<models::CsvImportReport, UserError> {
+    let has_header = query.header.unwrap_or(true);
+    let mut csv = csv::ReaderBuilder::new()
+        .has_headers(has_header)
+        .flexible(true)
+        .from_reader(reader);
+
+    let headers = match has_header {
+        true => Some(
+            csv.headers()
+                .map_err(|e| UserError::BadRequest(format!("Error reading the CSV header: {}", e)))?
+                .clone(),
+        ),
+        false => None,
+    };
+    let columns = [
+        column_index(headers.as_ref(), query.first_name_column.as_deref(), IMPORT_COLUMNS[0], 0)?,
+        column_index(headers.as_ref(), query.last_name_column.as_deref(), IMPORT_COLUMNS[1], 1)?,
+        column_index(headers.as_ref(), query.email_column.as_deref(), IMPORT_COLUMNS[2], 2)?,
+    ];
+
+    let mut conn = get_conn_from_db(pool)?;
+    let mut report = models::CsvImportReport {
+        inserted: 0,
+        merged: 0,
+        unchanged: 0,
+        failed: 0,
+        errors: Vec::new(),
+        rows: Vec::new(),
+    };
+    let merge = query.on_duplicate.unwrap_or_default() == models::ImportDuplicate::Merge;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+
+    for record in csv.records() {
+        let record = match record {
+            Ok(record) => record,
+            // The body couldn't be read, nothing after this point will parse
+            Err(e) if e.is_io_error() => {
+                return Err(UserError::BadRequest(format!("Error reading the CSV: {}", e)))
+            }
+            Err(e) => {
+                let row = e.position().map_or(0, |position| position.line());
+                report.fail(row, e.to_string());
+                continue;
+            }
+        };
+        let row = record.position().map_or(0, |position| position.line());
+        let field = |index: usize| record.get(index).unwrap_or_default().to_string();
+
+        let validated = if merge {
+            validation::validate_update_user(models::UpdateUser {
+                first_name: Some(field(columns[0])),
+                last_name: Some(field(columns[1])),
+                email: Some(field(columns[2])),
+            })
+            .and_then(|user| match user.email {
+                Some(_) => Ok(ImportRow::Merge(user)),
+                None => Err(UserError::Validation("email must not be empty".to_string())),
+            })
+        } else {
+            validation::validate_new_user(models::NewUser {
+                first_name: field(columns[0]),
+                last_name: field(columns[1]),
+                email: field(columns[2]),
+            })
+            .map(ImportRow::Insert)
+        };
+        match validated {
+            Ok(user) => batch.push((row, user)),
+            Err(e) => report.fail(row, e.to_string()),
+        }
+
+        if batch.len() == IMPORT_BATCH_SIZE {
+            insert_batch(&mut conn, feed, rules, &mut batch, &mut report)?;
+        }
+    }
+    insert_batch(&mut conn, feed, rules, &mut batch, &mut report)?;
+
+    // Insert failures are only known once their batch ran
+    report.errors.sort_by_key(|error| error.row);
+    Ok(report)
+}
+
+// Each row gets a savepoint so a failing insert only rolls back itself
+fn insert_batch(
+    conn: &mut PgConnection,
+    feed: &ChangeFeed,
+    rules: UniquenessRules,
+    batch: &mut Vec<(u64, ImportRow)>,
+    report: &mut models::CsvImportReport,
+) -> Result<(), UserError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let mut outcomes = Vec::with_capacity(batch.len());
+    conn.transaction(|conn| {
+        for (row, user) in batch.drain(..) {
+            let merge = matches!(user, ImportRow::Merge(_));
+            outcomes.push((row, merge, conn.transaction(|conn| import_row(conn, rules, user))));
+        }
+        Ok::<_, UserError>(())
+    })?;
+
+    for (row, merge, outcome) in outcomes {
+        match outcome {
+            Ok((outcome, events)) => {
+                match outcome {
+                    models::ImportOutcome::Inserted => report.inserted += 1,
+                    models::ImportOutcome::Merged => report.merged += 1,
+                    models::ImportOutcome::Unchanged => report.unchanged += 1,
+                }
+                if merge && report.rows.len() < MAX_REPORTED_ROWS {
+                    report.rows.push(models::CsvRowOutcome { row, outcome });
+                }
+                events.into_iter().for_each(|event| feed.publish(event));
+            }
+            Err(e) => report.fail(row, e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+// The outcome and the changes to publish once committed
+fn import_row(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    user: ImportRow,
+) -> Result<(models::ImportOutcome, Vec<ChangeEvent>), UserError> {
+    let incoming = match user {
+        ImportRow::Insert(user) => {
+            let inserted = insert_user(conn, rules, user, None)?;
+            return Ok((models::ImportOutcome::Inserted, inserted.events().collect()));
+        }
+        ImportRow::Merge(incoming) => incoming,
+    };
+    let address = incoming.email.clone().unwrap_or_default();
+
+    if let (Some(first_name), Some(last_name)) = (&incoming.first_name, &incoming.last_name) {
+        let user = models::NewUser {
+            first_name: first_name.clone(),
+            last_name: last_name.clone(),
+            email: address.clone(),
+        };
+        match insert_user(conn, rules, user, None) {
+            Ok(inserted) => {
+                return Ok((models::ImportOutcome::Inserted, inserted.events().collect()))
+            }
+            Err(UserError::Conflict(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    // A conflict with a deleted user under UNIQUENESS_POLICY=email has no
+    // active user to merge into and stays a conflict
+    let existing = match find_active_by_email(conn, rules, &address)? {
+        Some(existing) => existing,
+        None if incoming.first_name.is_none() => {
+            return Err(UserError::Validation("first_name must not be empty".to_string()))
+        }
+        None if incoming.last_name.is_none() => {
+            return Err(UserError::Validation("last_name must not be empty".to_string()))
+        }
+        None => {
+            return Err(UserError::Conflict("A user with this email already exists".to_string()))
+        }
+    };
+
+    let changes = models::UpdateUser {
+        first_name: incoming.first_name.filter(|name| *name != existing.first_name),
+        last_name: incoming.last_name.filter(|name| *name != existing.last_name),
+        email: None,
+    };
+    if !changes.has_changes() {
+        return Ok((models::ImportOutcome::Unchanged, Vec::new()));
+    }
+
+    let merged = update_active_user(conn, rules, existing.user_id, &changes)?;
+    Ok((models::ImportOutcome::Merged, merged.into_iter().map(ChangeEvent::upsert).collect()))
+}
+
+impl models::CsvImportReport {
+    fn fail(&mut self, row: u64, error: String) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(models::CsvRowError { row, error });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::uniqueness::UniquenessPolicy;
+    use actix_web::http::StatusCode;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn merge_fills_in_existing_users_by_email() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        let csv = "first_name,last_name,email\n\
+                   ,King,ada@example.com\n\
+                   ,,ada@example.com\n\
+                   Grace,Hopper,grace@example.com\n\
+                   ,Nobody,new@example.com\n";
+        let request = actix_web::test::TestRequest::post()
+            .uri("/users/import.csv?on_duplicate=merge")
+            .insert_header((header::CONTENT_TYPE, "text/csv"))
+            .set_payload(csv);
+        let response = testing::call(&pool, &testing::config(&[]), request).await;
+        let (status, body) = testing::json(response).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let report = &body["data"];
+        assert_eq!(
+            (&report["inserted"], &report["merged"], &report["unchanged"], &report["failed"]),
+            (&1.into(), &1.into(), &1.into(), &1.into())
+        );
+        assert_eq!(
+            report["rows"],
+            serde_json::json!([
+                {"row": 2, "outcome": "merged"},
+                {"row": 3, "outcome": "unchanged"},
+                {"row": 4, "outcome": "inserted"},
+            ])
+        );
+        assert_eq!(report["errors"][0]["row"], 5);
+        assert_eq!(report["errors"][0]["error"], "first_name must not be empty");
+
+        let merged = crate::schema::users::table
+            .filter(crate::schema::users::user_id.eq(ada.user_id))
+            .first::<models::User>(&mut conn)
+            .unwrap();
+        assert_eq!((merged.first_name.as_str(), merged.last_name.as_str()), ("Ada", "King"));
+    }
+}
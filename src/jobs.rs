@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, NaiveDateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{models, user_error::UserError};
+
+// Finished jobs are dropped this long after they finish
+const JOB_RETENTION_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct JobProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub status: JobStatus,
+    pub progress: JobProgress,
+    pub created_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    // The response data the synchronous request would have returned
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+// Background jobs started with `?async=true`, kept in memory and shared by
+// every worker. Jobs are lost on restart.
+#[derive(Clone, Default)]
+pub struct JobStore {
+    jobs: Arc<DashMap<Uuid, Job>>,
+}
+
+impl JobStore {
+    // A pending job of `total` items
+    pub fn start(&self, total: usize) -> Uuid {
+        self.prune();
+
+        let id = Uuid::new_v4();
+        self.jobs.insert(
+            id,
+            Job {
+                id,
+                status: JobStatus::Pending,
+                progress: JobProgress { processed: 0, total },
+                created_at: Utc::now().naive_utc(),
+                finished_at: None,
+                result: None,
+                error: None,
+            },
+        );
+        id
+    }
+
+    // Marks the job running with `processed` items done. A retried job can
+    // go back to fewer processed items.
+    pub fn progress(&self, id: Uuid, processed: usize) {
+        if let Some(mut job) = self.jobs.get_mut(&id) {
+            job.status = JobStatus::Running;
+            job.progress.processed = processed;
+        }
+    }
+
+    pub fn finish(&self, id: Uuid, outcome: Result<serde_json::Value, String>) {
+        if let Some(mut job) = self.jobs.get_mut(&id) {
+            job.finished_at = Some(Utc::now().naive_utc());
+            match outcome {
+                Ok(result) => {
+                    job.status = JobStatus::Succeeded;
+                    job.result = Some(result);
+                }
+                Err(error) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(error);
+                }
+            }
+        }
+    }
+
+    pub fn counts(&self) -> models::JobCounts {
+        let mut counts = models::JobCounts::default();
+        for job in self.jobs.iter() {
+            match job.status {
+                JobStatus::Pending => counts.pending += 1,
+                JobStatus::Running => counts.running += 1,
+                JobStatus::Succeeded => counts.succeeded += 1,
+                JobStatus::Failed => counts.failed += 1,
+            }
+        }
+        counts
+    }
+
+    fn get(&self, id: Uuid) -> Option<Job> {
+        self.jobs.get(&id).map(|job| job.clone())
+    }
+
+    fn prune(&self) {
+        let cutoff = Utc::now().naive_utc() - Duration::minutes(JOB_RETENTION_MINUTES);
+        self.jobs
+            .retain(|_, job| job.finished_at.is_none_or(|finished_at| finished_at > cutoff));
+    }
+}
+
+pub async fn get_job(
+    jobs: web::Data<JobStore>,
+    path: web::Path<(Uuid,)>,
+) -> Result<HttpResponse, UserError> {
+    let job = jobs.get(path.into_inner().0).ok_or(UserError::JobNotFound)?;
+
+    Ok(HttpResponse::Ok().json(models::GenericResponse {
+        status: "OK".to_string(),
+        message: "Job fetched successfully".to_string(),
+        data: Some(job),
+        warnings: Vec::new(),
+    }))
+}
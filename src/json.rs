@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::error::JsonPayloadError;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use serde::de::DeserializeOwned;
+
+use crate::digest;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Drop-in for `web::Json` that also accepts a body starting with a UTF-8 BOM,
+// which some clients prepend and serde_json rejects. Leading whitespace is
+// already fine for serde_json. The buffered body is checked against the
+// Content-MD5 and Digest headers when present.
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Json<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req.content_type().to_string();
+        let headers = req.headers().clone();
+        let body = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            if content_type != "application/json" && !content_type.ends_with("+json") {
+                return Err(JsonPayloadError::ContentType.into());
+            }
+
+            let body = body.await?;
+            digest::verify_body(&headers, &body)?;
+            let body = body.strip_prefix(UTF8_BOM).unwrap_or(&body);
+
+            serde_json::from_slice(body)
+                .map(Json)
+                .map_err(|e| JsonPayloadError::Deserialize(e).into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+
+    async fn post(body: &'static [u8]) -> (StatusCode, String) {
+        let app = test::init_service(App::new().route(
+            "/",
+            web::post().to(|user: Json<crate::models::NewUser>| async move {
+                HttpResponse::Ok().body(user.into_inner().first_name)
+            }),
+        ))
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let status = res.status();
+        (status, String::from_utf8(test::read_body(res).await.to_vec()).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn body_with_a_bom_is_accepted() {
+        let body = b"\xEF\xBB\xBF {\"first_name\":\"Ada\",\"last_name\":\"Lovelace\",\"email\":\"ada@example.com\"}";
+        assert_eq!(post(body).await, (StatusCode::OK, "Ada".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn body_without_a_bom_is_unchanged() {
+        let body = b"{\"first_name\":\"Ada\",\"last_name\":\"Lovelace\",\"email\":\"ada@example.com\"}";
+        assert_eq!(post(body).await, (StatusCode::OK, "Ada".to_string()));
+        assert_eq!(post(b"\xEF\xBB\xBF").await.0, StatusCode::BAD_REQUEST);
+    }
+}
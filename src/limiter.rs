@@ -0,0 +1,383 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Data;
+use actix_web::Error;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::sync::Semaphore;
+
+use crate::user_error::{Throttle, UserError};
+
+// Routes that don't take a pool connection
+const POOL_FREE_PATHS: &[&str] = &["/", "/healthz", "/readyz"];
+
+// Bounds the number of requests handled at once. Requests over the limit wait
+// for a permit instead of piling onto the database pool.
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+}
+
+impl ConcurrencyLimiter {
+    // With a ramp the limiter starts at a tenth of `max` and is raised to the
+    // full limit over `ramp`, so a cold database and pool can warm up first.
+    pub fn new(max: usize, ramp: Duration) -> ConcurrencyLimiter {
+        let initial = if ramp.is_zero() { max } else { (max / 10).max(1) };
+
+        let limiter = ConcurrencyLimiter {
+            semaphore: Arc::new(Semaphore::new(initial)),
+            max,
+        };
+
+        if initial < max {
+            actix_rt::spawn(limiter.clone().ramp_up(initial, ramp));
+        }
+
+        limiter
+    }
+
+    async fn ramp_up(self, initial: usize, ramp: Duration) {
+        let steps = ramp.as_secs().max(1) as usize;
+        let mut interval = actix_rt::time::interval(ramp / steps as u32);
+        let mut current = initial;
+
+        // The first tick completes immediately
+        interval.tick().await;
+
+        for step in 1..=steps {
+            interval.tick().await;
+
+            let target = initial + (self.max - initial) * step / steps;
+            self.semaphore.add_permits(target - current);
+            current = target;
+        }
+    }
+}
+
+pub async fn limit_concurrency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limiter = req.app_data::<Data<ConcurrencyLimiter>>().cloned();
+
+    let _permit = match &limiter {
+        Some(limiter) => Some(
+            limiter
+                .semaphore
+                .acquire()
+                .await
+                .map_err(actix_web::error::ErrorServiceUnavailable)?,
+        ),
+        None => None,
+    };
+
+    next.call(req).await
+}
+
+// Routes the method doesn't classify: GET /delete/{id} writes, these POSTs
+// only read
+const WRITE_GETS: &[&str] = &["/delete/{id}"];
+const READ_POSTS: &[&str] = &["/users/by-emails", "/users/batch-get-conditional"];
+
+// Reserves part of the pool for writes. Reads hold a permit while they run,
+// and there are only as many as the pool has connections minus the
+// reservation, so a flood of reads leaves those connections to writes.
+// Writes take no permit and can use the whole pool. The split starts from
+// POOL_SIZE and follows the pool as `spawn_pool_scaling` grows it.
+#[derive(Clone)]
+pub struct ReadShare {
+    semaphore: Arc<Semaphore>,
+    write_reserve: f64,
+    // Permits handed out in total, held or not
+    reads: Arc<AtomicUsize>,
+}
+
+impl ReadShare {
+    pub fn new(pool_size: u32, write_reserve: f64) -> ReadShare {
+        let reads = read_permits(pool_size, write_reserve);
+
+        ReadShare {
+            semaphore: Arc::new(Semaphore::new(reads)),
+            write_reserve,
+            reads: Arc::new(AtomicUsize::new(reads)),
+        }
+    }
+
+    // Adds the read permits of a grown pool, keeping the same share reserved.
+    // The pool is never shrunk, so a smaller size leaves the permits as they are.
+    pub fn resize(&self, pool_size: u32) {
+        let reads = read_permits(pool_size, self.write_reserve);
+        let before = self.reads.fetch_max(reads, Ordering::SeqCst);
+        if reads > before {
+            self.semaphore.add_permits(reads - before);
+        }
+    }
+}
+
+// At least one connection is left to reads
+fn read_permits(pool_size: u32, write_reserve: f64) -> usize {
+    let pool_size = pool_size as usize;
+    let reserved = ((pool_size as f64 * write_reserve).ceil() as usize).min(pool_size - 1);
+    pool_size - reserved
+}
+
+fn is_read(req: &ServiceRequest) -> bool {
+    let pattern = req.match_pattern();
+    let listed = |routes: &[&str]| pattern.as_deref().is_some_and(|pattern| routes.contains(&pattern));
+
+    match *req.method() {
+        Method::GET | Method::HEAD => !listed(WRITE_GETS),
+        Method::POST => listed(READ_POSTS),
+        _ => false,
+    }
+}
+
+pub async fn reserve_for_writes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let share = req.app_data::<Data<ReadShare>>().cloned();
+
+    let _permit = match &share {
+        Some(share) if is_read(&req) && !POOL_FREE_PATHS.contains(&req.path()) => Some(
+            share
+                .semaphore
+                .acquire()
+                .await
+                .map_err(actix_web::error::ErrorServiceUnavailable)?,
+        ),
+        _ => None,
+    };
+
+    next.call(req).await
+}
+
+// Bounds the requests in flight from a single client IP, so one client can't
+// hold every permit and pool connection. Unlike a rate limit this doesn't
+// look at how many requests were made over time, only at how many are
+// running. Behind a reverse proxy every request has the proxy's address.
+#[derive(Clone)]
+pub struct InflightPerIp {
+    counts: Arc<DashMap<IpAddr, usize>>,
+    max: usize,
+    // Suggested to refused clients, see RETRY_AFTER_SECS
+    retry_after_secs: u64,
+}
+
+impl InflightPerIp {
+    pub fn new(max: usize, retry_after_secs: u64) -> InflightPerIp {
+        InflightPerIp {
+            counts: Arc::new(DashMap::new()),
+            max,
+            retry_after_secs,
+        }
+    }
+
+    fn acquire(&self, ip: IpAddr) -> Result<InflightGuard, Throttle> {
+        let mut count = self.counts.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return Err(Throttle {
+                limit: self.max,
+                remaining: 0,
+                retry_after_secs: self.retry_after_secs,
+            });
+        }
+        *count += 1;
+
+        Ok(InflightGuard {
+            counts: self.counts.clone(),
+            ip,
+        })
+    }
+}
+
+// Releases the slot when the request finishes or is dropped
+struct InflightGuard {
+    counts: Arc<DashMap<IpAddr, usize>>,
+    ip: IpAddr,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut entry) = self.counts.entry(self.ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+pub async fn limit_inflight_per_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let inflight = req.app_data::<Data<InflightPerIp>>().cloned();
+
+    let _guard = match (&inflight, req.peer_addr()) {
+        (Some(inflight), Some(addr)) => match inflight.acquire(addr.ip()) {
+            Ok(guard) => Some(guard),
+            // A response rather than an error, so `i18n::localize_errors` sees it
+            Err(throttle) => {
+                let e = UserError::TooManyRequests(throttle);
+                return Ok(req.error_response(e).map_into_right_body());
+            }
+        },
+        _ => None,
+    };
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, web, App, HttpResponse};
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    const MAX_INFLIGHT: usize = 2;
+
+    #[actix_web::test]
+    async fn inflight_requests_from_one_ip_are_bounded() {
+        let config = crate::testing::config(&[]);
+        let inflight = InflightPerIp::new(MAX_INFLIGHT, 3);
+        // Held requests wait here until permits are added
+        let gate = Arc::new(Semaphore::new(0));
+        let app = Rc::new(
+            test::init_service(
+                crate::with_middleware(
+                    App::new()
+                        .app_data(Data::new(inflight.clone()))
+                        .app_data(Data::new(gate.clone())),
+                    &config,
+                )
+                .route(
+                    "/held",
+                    web::get().to(|gate: Data<Arc<Semaphore>>| async move {
+                        gate.acquire().await.unwrap().forget();
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+            )
+            .await,
+        );
+        let client: SocketAddr = "192.0.2.7:40000".parse().unwrap();
+        let request = move || {
+            test::TestRequest::get()
+                .uri("/held")
+                .peer_addr(client)
+                .insert_header((header::ACCEPT_LANGUAGE, "de"))
+                .to_request()
+        };
+
+        let held: Vec<_> = (0..MAX_INFLIGHT)
+            .map(|_| {
+                let app = app.clone();
+                actix_rt::spawn(async move { test::call_service(&*app, request()).await.status() })
+            })
+            .collect();
+        while inflight.counts.get(&client.ip()).map(|count| *count) != Some(MAX_INFLIGHT) {
+            actix_rt::task::yield_now().await;
+        }
+
+        let refused = test::call_service(&*app, request()).await;
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers().get(header::RETRY_AFTER).unwrap(), "3");
+        assert_eq!(refused.headers().get("X-RateLimit-Limit").unwrap(), "2");
+        assert_eq!(refused.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+        assert_eq!(refused.headers().get("X-RateLimit-Reset").unwrap(), "3");
+        let body: serde_json::Value = test::read_body_json(refused).await;
+        assert_eq!(body["code"], "too_many_requests");
+        assert_eq!(body["message"], "Zu viele gleichzeitige Anfragen");
+
+        gate.add_permits(MAX_INFLIGHT + 1);
+        for held in held {
+            assert_eq!(held.await.unwrap(), StatusCode::OK);
+        }
+        assert!(inflight.counts.is_empty());
+        assert_eq!(test::call_service(&*app, request()).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn reads_get_the_pool_minus_the_reservation() {
+        let permits = |pool_size, reserve| {
+            ReadShare::new(pool_size, reserve).semaphore.available_permits()
+        };
+        assert_eq!(permits(10, 0.25), 7);
+        assert_eq!(permits(10, 0.0), 10);
+        assert_eq!(permits(2, 0.9), 1);
+    }
+
+    #[actix_web::test]
+    async fn a_grown_pool_gives_reads_their_share() {
+        let share = ReadShare::new(4, 0.5);
+        let held = share.semaphore.clone().acquire_many_owned(2).await.unwrap();
+
+        share.resize(8);
+        assert_eq!(share.semaphore.available_permits(), 2);
+        drop(held);
+        assert_eq!(share.semaphore.available_permits(), 4);
+
+        share.resize(4);
+        assert_eq!(share.semaphore.available_permits(), 4);
+    }
+
+    #[actix_web::test]
+    async fn a_write_gets_through_a_flood_of_reads() {
+        let config = crate::testing::config(&[]);
+        let share = ReadShare::new(4, 0.5);
+        let gate = Arc::new(Semaphore::new(0));
+        let held_route = |gate: Data<Arc<Semaphore>>| async move {
+            gate.acquire().await.unwrap().forget();
+            HttpResponse::Ok().finish()
+        };
+        let app = Rc::new(
+            test::init_service(
+                crate::with_middleware(
+                    App::new()
+                        .app_data(Data::new(share.clone()))
+                        .app_data(Data::new(gate.clone())),
+                    &config,
+                )
+                .route("/read", web::get().to(held_route))
+                .route("/write", web::post().to(HttpResponse::Ok)),
+            )
+            .await,
+        );
+
+        let reads: Vec<_> = (0..5)
+            .map(|_| {
+                let app = app.clone();
+                actix_rt::spawn(async move {
+                    let request = test::TestRequest::get().uri("/read").to_request();
+                    test::call_service(&*app, request).await.status()
+                })
+            })
+            .collect();
+        while share.semaphore.available_permits() > 0 {
+            actix_rt::task::yield_now().await;
+        }
+
+        let write = test::TestRequest::post().uri("/write").to_request();
+        let written = tokio::time::timeout(Duration::from_secs(1), test::call_service(&*app, write))
+            .await
+            .expect("the write waited for a read permit");
+        assert_eq!(written.status(), StatusCode::OK);
+
+        gate.add_permits(5);
+        for read in reads {
+            assert_eq!(read.await.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(share.semaphore.available_permits(), 2);
+    }
+}
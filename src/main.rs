@@ -1,58 +1,401 @@
+mod access_log;
+mod admin;
+mod cache_control;
+mod coalesce;
+mod compression;
+mod config;
+mod digest;
+mod events;
+mod explain;
 mod models;
+mod mx;
+mod readiness;
+mod response_limit;
+mod retention;
 mod handler;
+mod i18n;
+mod import;
+mod jobs;
+mod json;
+mod limiter;
+mod metrics;
+mod pool;
 mod user_error;
+mod validation;
+mod vcard;
 
-use actix_web::middleware::Logger;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Compress, Condition};
 use actix_web::web::Data;
 use actix_web::{App, HttpServer, web};
 
 mod schema;
+mod security;
+mod tasks;
+mod test_support;
+#[cfg(test)]
+mod testing;
+mod tx;
+mod uniqueness;
+mod uuid_format;
 
 use diesel::pg::PgConnection;
-use diesel::{prelude::*, r2d2};
-use diesel::r2d2::ConnectionManager;
+use diesel::prelude::*;
 use dotenvy::dotenv;
 use std::env;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
+use crate::config::{AppConfig, PaginationOutOfRange, PoolMode, SanitizePolicy};
+use crate::coalesce::UpdateCoalescer;
+use crate::events::ChangeFeed;
+use crate::jobs::JobStore;
+use crate::access_log::{LogSampler, RecentProblems};
+use crate::metrics::UserMetricsCache;
+use crate::limiter::{ConcurrencyLimiter, InflightPerIp, ReadShare};
+use crate::readiness::Readiness;
+use crate::tasks::Background;
 
-// Custom type for the connection pool
-pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
-pub fn establish_connection() -> DbPool {
-    dotenv().ok();
+pub use crate::pool::DbPool;
+
+// Size of the pool left to startup checks and background tasks when every
+// worker has its own pool
+const BACKGROUND_POOL_SIZE: u32 = 2;
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    
-    let manager = ConnectionManager::<PgConnection>::new(database_url.clone());
+pub fn establish_connection(config: &AppConfig, max_size: u32) -> DbPool {
+    let database_url = &config.database_url;
 
     // Establish a connection to the database
-    let _connection = PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+    let _connection = PgConnection::establish(database_url)
+        .unwrap_or_else(|e| panic!("Error connecting to {}: {}", database_url, e));
 
     // Create a connection pool
-    let pool: DbPool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
+    DbPool::build(database_url, max_size, config.pool_min_idle, config.pool_get_timeout())
+        .expect("Failed to create pool.")
+}
+
+// Write routes can be switched off with ENABLE_WRITES / ENABLE_DELETE for a
+// read-only deployment. Disabled routes aren't mounted at all, so they get
+// the same 404 as any unknown path.
+fn configure_routes(cfg: &mut web::ServiceConfig, config: &AppConfig) {
+    cfg.route("/", web::get().to(handler::health_checker))
+        .route("/healthz", web::get().to(handler::healthz))
+        .route("/readyz", web::get().to(handler::readyz))
+        .route("/get", web::get().to(handler::get_users))
+        .route("/get/{id}", web::get().to(handler::get_user))
+        .route("/users/changes", web::get().to(handler::get_user_changes))
+        .route("/users/feed", web::get().to(handler::get_user_feed))
+        .route("/users/sync-stream", web::get().to(handler::sync_stream))
+        .route("/users/domain/{domain}", web::get().to(handler::get_users_by_domain))
+        .route("/users/email-regex", web::get().to(handler::get_users_by_email_regex))
+        .route("/users/validate-email", web::get().to(handler::validate_email))
+        .route("/users/search", web::get().to(handler::search_users))
+        .route("/users/lookup", web::get().to(handler::lookup_user))
+        .route("/users/nearest-email", web::get().to(handler::get_nearest_email))
+        .route("/users/by-emails", web::post().to(handler::get_users_by_emails))
+        .route("/users/batch-get-conditional", web::post().to(handler::batch_get_conditional))
+        .route("/users/stale", web::get().to(handler::get_stale_users))
+        .route("/users/name-stats", web::get().to(handler::get_name_stats))
+        .route("/users/bookends", web::get().to(handler::get_user_bookends))
+        .route("/users/counts", web::get().to(handler::get_user_counts))
+        .route("/users/histogram", web::get().to(handler::get_user_histogram))
+        .route("/users/labels", web::get().to(handler::get_user_labels))
+        .route("/users/distinct/{column}", web::get().to(handler::get_distinct_values))
+        .route("/users/name-duplicates", web::get().to(handler::get_name_duplicates))
+        .route("/metrics/users", web::get().to(metrics::user_metrics))
+        .route("/jobs/{id}", web::get().to(jobs::get_job))
+        .route("/users/{id}.vcf", web::get().to(handler::get_user_vcard))
+        .route("/users/{id}/rank", web::get().to(handler::get_user_rank))
+        .route("/users/{id}/neighbors", web::get().to(handler::get_user_neighbors))
+        .route("/admin/schema-check", web::get().to(admin::schema_check))
+        .route("/admin/config", web::get().to(admin::effective_config))
+        .route("/admin/status", web::get().to(admin::system_status))
+        .route("/admin/migrations", web::get().to(admin::applied_migrations))
+        .route("/admin/recent-problems", web::get().to(admin::recent_problems))
+        .route("/admin/table-checksum", web::get().to(admin::table_checksum))
+        .route("/admin/normalization-preview", web::get().to(admin::preview_normalization))
+        .route("/admin/pool/recycle", web::post().to(admin::recycle_pool))
+        .route("/admin/repair-timestamps", web::post().to(admin::repair_timestamps));
+
+    if config.enable_writes {
+        cfg.route("/add", web::post().to(handler::add_user))
+            .route("/update/{id}", web::post().to(handler::update_user))
+            .route("/users/batch-ops", web::post().to(handler::batch_ops))
+            .route("/users/get-or-create", web::post().to(handler::get_or_create_user))
+            .route("/users/import.csv", web::post().to(import::import_csv))
+            .route("/users/import-template.csv", web::get().to(import::import_template))
+            .route("/users/swap-email", web::post().to(handler::swap_emails))
+            .route("/users/reassign", web::post().to(handler::reassign_users))
+            .route("/users/anonymize", web::post().to(handler::anonymize_users))
+            .route("/users/{id}/metadata", web::post().to(handler::merge_user_metadata))
+            .route("/users/{id}/restore", web::post().to(handler::restore_user));
+    }
+
+    if config.enable_writes && config.enable_delete {
+        cfg.route("/delete/{id}", web::get().to(handler::delete_user));
+    }
 
-    pool
+    if config.test_mode {
+        cfg.route("/test/reset", web::post().to(test_support::reset));
+    }
+}
+
+// The middleware of every app, innermost first. The limiters sit inside
+// `localize_errors` so their 429 and 503 responses are localized too.
+fn with_middleware<T, B>(
+    app: App<T>,
+    config: &AppConfig,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+>
+where
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    app.wrap(from_fn(uuid_format::format_uuids))
+        .wrap(from_fn(tx::finish_transaction))
+        .wrap(from_fn(response_limit::cap_response_size))
+        .wrap(from_fn(security::require_headers))
+        .wrap(from_fn(limiter::reserve_for_writes))
+        .wrap(from_fn(limiter::limit_concurrency))
+        .wrap(from_fn(limiter::limit_inflight_per_ip))
+        .wrap(from_fn(i18n::localize_errors))
+        .wrap(from_fn(cache_control::cache_headers))
+        .wrap(security::security_headers(config))
+        .wrap(from_fn(compression::skip_small))
+        .wrap(Condition::new(config.compress_min_bytes.is_some(), Compress::default()))
+        .wrap(from_fn(compression::strip_identity))
+        .wrap(from_fn(access_log::log_requests))
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    let pool = establish_connection();
-    HttpServer::new(move || {
-        App::new()
-
-            .app_data(Data::new(pool.clone()))
-            .wrap(Logger::default())
-            .route("/", web::get().to(handler::health_checker))
-            .route("/get", web::get().to(handler::get_users))
-            .route("/add", web::post().to(handler::add_user))
-            .route("/update/{id}", web::post().to(handler::update_user))
-            .route("/delete/{id}", web::get().to(handler::delete_user))
-            
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    dotenv().ok();
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let config = AppConfig::load().unwrap_or_else(|e| panic!("{}", e));
+
+    // `--print-config` reports where each config key was read from
+    if env::args().any(|arg| arg == "--print-config") {
+        print!("{}", config.sources_report());
+        return Ok(());
+    }
+
+    models::OMIT_NULL_FIELDS.store(config.omit_null_fields, Ordering::Relaxed);
+    user_error::VERBOSE_ERRORS.store(config.verbose_errors, Ordering::Relaxed);
+    let _ = user_error::ERROR_ENVELOPE.set(config.error_envelope);
+    models::REJECT_OUT_OF_RANGE_PAGES
+        .store(config.pagination_oor == PaginationOutOfRange::Reject, Ordering::Relaxed);
+    models::PAGINATION_LINKS.store(config.pagination_links, Ordering::Relaxed);
+    validation::STRIP_CONTROL_CHARS
+        .store(config.sanitize_input == SanitizePolicy::Strip, Ordering::Relaxed);
+    validation::TRIM_EMAILS.store(config.email_trim, Ordering::Relaxed);
+    validation::LOWERCASE_EMAILS.store(config.email_lowercase, Ordering::Relaxed);
+    if config.max_users > 0 {
+        let _ = retention::USER_CAP.set(retention::UserCap {
+            max_users: config.max_users,
+            trim: config.max_users_trim,
+        });
+    }
+
+    // With POOL_MODE=shared all workers share this pool, so the server holds
+    // at most POOL_SIZE connections and a busy worker can use connections
+    // another worker leaves idle. With per_worker each worker builds its own
+    // pool inside the factory below, which avoids contention on the shared
+    // pool but holds up to workers * POOL_SIZE connections, plus this pool
+    // kept small for the startup checks and background tasks. Size POOL_SIZE
+    // down accordingly to stay under the server's max_connections; /healthz
+    // then reports the pool of the worker that served it.
+    let pool = match config.pool_mode {
+        PoolMode::Shared => establish_connection(&config, config.pool_size),
+        PoolMode::PerWorker => establish_connection(&config, BACKGROUND_POOL_SIZE),
+    };
+
+    let readiness = Data::new(Readiness::default());
+    let feed = Data::new(ChangeFeed::default());
+    let jobs = Data::new(JobStore::default());
+    readiness::check_extensions(
+        &pool,
+        &config.required_extensions,
+        config.create_extensions,
+        &readiness,
+    );
+    readiness::check_uniqueness_index(&pool, config.uniqueness(), &readiness);
+    readiness::check_message_locale(&pool, &readiness);
+    let bind_addr = (config.host.clone(), config.port);
+    let workers = config.workers;
+
+    let background = Data::new(Background::new());
+    let drain_background = {
+        let background = background.clone();
+        let grace = Duration::from_secs(config.shutdown_grace_secs);
+        async move { background.shutdown(grace).await }
+    };
+    let read_share = (config.pool_write_reserve > 0.0)
+        .then(|| ReadShare::new(config.pool_size, config.pool_write_reserve));
+
+    if config.pool_wait_threshold_ms > 0 {
+        tasks::spawn_pool_scaling(
+            &background,
+            (config.pool_mode == PoolMode::Shared).then(|| pool.clone()),
+            read_share.clone(),
+            config.pool_size,
+            config.database_url.clone(),
+            Duration::from_millis(config.pool_wait_threshold_ms),
+            config.auto_scale_pool.then_some(config.pool_max_size_cap),
+        );
+    }
+    if config.stale_days > 0 {
+        tasks::spawn_stale_account_check(
+            &background,
+            pool.clone(),
+            config.stale_days,
+            Duration::from_secs(config.stale_check_interval_secs),
+        );
+    }
+    if let Some(grace_days) = config.soft_delete_grace_days {
+        tasks::spawn_soft_delete_purge(
+            &background,
+            pool.clone(),
+            grace_days,
+            Duration::from_secs(config.purge_interval_secs),
+        );
+    }
+
+    let coalescer = (config.update_coalesce_ms > 0).then(|| {
+        let coalescer = UpdateCoalescer::new(Duration::from_millis(config.update_coalesce_ms));
+        tasks::spawn_coalescer_invalidation(&background, coalescer.clone(), &feed);
+        coalescer
+    });
+
+    let limiter = (config.max_concurrency > 0).then(|| {
+        ConcurrencyLimiter::new(config.max_concurrency, Duration::from_secs(config.ramp_secs))
+    });
+
+    let inflight_per_ip =
+        (config.max_inflight_per_ip > 0).then(|| InflightPerIp::new(config.max_inflight_per_ip, config.retry_after_secs));
+
+    let log_sampler = (config.log_sample_rate > 1).then(|| LogSampler::new(config.log_sample_rate));
+    let user_metrics = Data::new(UserMetricsCache::default());
+    let mx_cache = Data::new(mx::MxCache::new(Duration::from_secs(config.mx_cache_ttl_secs)));
+    let recent_problems = Data::new(RecentProblems::new(
+        (config.slow_request_ms > 0).then(|| Duration::from_millis(config.slow_request_ms)),
+    ));
+
+    let server = HttpServer::new(move || {
+        let worker_pool = match config.pool_mode {
+            PoolMode::Shared => pool.clone(),
+            PoolMode::PerWorker => establish_connection(&config, config.pool_size),
+        };
+
+        let mut app = App::new()
+            .app_data(Data::new(worker_pool))
+            .app_data(Data::new(config.clone()))
+            .app_data(readiness.clone())
+            .app_data(feed.clone())
+            .app_data(jobs.clone())
+            .app_data(background.clone())
+            .app_data(recent_problems.clone())
+            .app_data(mx_cache.clone())
+            .app_data(user_metrics.clone());
+
+        if let Some(coalescer) = &coalescer {
+            app = app.app_data(Data::new(coalescer.clone()));
+        }
+        if let Some(limiter) = &limiter {
+            app = app.app_data(Data::new(limiter.clone()));
+        }
+        // One per pool, so per worker with POOL_MODE=per_worker
+        let worker_read_share = match config.pool_mode {
+            PoolMode::Shared => read_share.clone(),
+            PoolMode::PerWorker => (config.pool_write_reserve > 0.0)
+                .then(|| ReadShare::new(config.pool_size, config.pool_write_reserve)),
+        };
+        if let Some(read_share) = worker_read_share {
+            app = app.app_data(Data::new(read_share));
+        }
+        if let Some(inflight_per_ip) = &inflight_per_ip {
+            app = app.app_data(Data::new(inflight_per_ip.clone()));
+        }
+        // Built per worker so lookups run on the worker's own runtime
+        if let Some(spec) = &config.dns_resolver {
+            let resolver = mx::build_resolver(spec, config.dns_timeout())
+                .unwrap_or_else(|e| panic!("Error building the DNS resolver: {}", e));
+            app = app.app_data(Data::new(resolver));
+        }
+        if let Some(log_sampler) = &log_sampler {
+            app = app.app_data(Data::new(log_sampler.clone()));
+        }
+
+        with_middleware(app, &config).configure(|cfg| configure_routes(cfg, &config))
+    });
+
+    let server = if workers > 0 { server.workers(workers) } else { server };
+
+    let served = server.bind(bind_addr)?.run().await;
+
+    // The HTTP server has drained its requests, now the background work
+    log::info!("Waiting for background tasks");
+    if !drain_background.await {
+        log::warn!("Background tasks still running after the grace period, stopping anyway");
+    }
+
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    async fn status(settings: &[(&str, &str)], req: test::TestRequest) -> StatusCode {
+        let config = testing::config(settings);
+        let app = test::init_service(App::new().configure(|cfg| configure_routes(cfg, &config))).await;
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    fn add() -> test::TestRequest {
+        test::TestRequest::post().uri("/add")
+    }
+
+    fn delete() -> test::TestRequest {
+        test::TestRequest::get().uri("/delete/1")
+    }
+
+    #[actix_web::test]
+    async fn write_routes_are_mounted_by_default() {
+        assert_ne!(status(&[], add()).await, StatusCode::NOT_FOUND);
+        assert_ne!(status(&[], delete()).await, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn disabled_write_routes_are_absent() {
+        let read_only = [("ENABLE_WRITES", "false")];
+        assert_eq!(status(&read_only, add()).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&read_only, delete()).await, StatusCode::NOT_FOUND);
+        let get = test::TestRequest::get().uri("/");
+        assert_eq!(status(&read_only, get).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn disabled_delete_leaves_other_writes() {
+        let no_delete = [("ENABLE_DELETE", "false")];
+        assert_eq!(status(&no_delete, delete()).await, StatusCode::NOT_FOUND);
+        assert_ne!(status(&no_delete, add()).await, StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::{handler::get_conn_from_db, models, user_error::UserError, DbPool};
+
+// Number of recent acquisitions the percentiles are computed over
+const WAIT_SAMPLES: usize = 1024;
+
+// How long handlers wait on `pool.get()`. A high wait with a fast database
+// means the pool is too small rather than the database being slow.
+pub struct PoolMetrics {
+    waits_us: Mutex<VecDeque<u64>>,
+    acquired: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+pub static POOL_METRICS: PoolMetrics = PoolMetrics {
+    waits_us: Mutex::new(VecDeque::new()),
+    acquired: AtomicU64::new(0),
+    timeouts: AtomicU64::new(0),
+};
+
+#[derive(Serialize)]
+pub struct PoolWaitSummary {
+    pub acquired: u64,
+    pub timeouts: u64,
+    pub wait_p50_ms: f64,
+    pub wait_p95_ms: f64,
+}
+
+impl PoolMetrics {
+    pub fn record_wait(&self, wait: Duration) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+
+        let mut waits = self.waits_us.lock().unwrap();
+        if waits.len() == WAIT_SAMPLES {
+            waits.pop_front();
+        }
+        waits.push_back(wait.as_micros() as u64);
+    }
+
+    // Drops the recorded waits, e.g. after the pool was resized so the old
+    // waits no longer say anything about it
+    pub fn reset_waits(&self) {
+        self.waits_us.lock().unwrap().clear();
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> PoolWaitSummary {
+        let mut waits: Vec<u64> = self.waits_us.lock().unwrap().iter().copied().collect();
+        waits.sort_unstable();
+
+        PoolWaitSummary {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            wait_p50_ms: percentile_ms(&waits, 50),
+            wait_p95_ms: percentile_ms(&waits, 95),
+        }
+    }
+}
+
+fn percentile_ms(sorted: &[u64], pct: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = (sorted.len() * pct).div_ceil(100).saturating_sub(1);
+    sorted[index] as f64 / 1000.0
+}
+
+// How long `/metrics/users` serves the same counts. They take a scan of the
+// table, which scrapers every few seconds shouldn't each pay for.
+const USER_METRICS_TTL: Duration = Duration::from_secs(15);
+
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// The last rendered `/metrics/users`, shared by the workers
+#[derive(Clone, Default)]
+pub struct UserMetricsCache(Arc<Mutex<Option<(Instant, String)>>>);
+
+impl UserMetricsCache {
+    fn get(&self) -> Option<String> {
+        match &*self.0.lock().unwrap() {
+            Some((rendered_at, text)) if rendered_at.elapsed() < USER_METRICS_TTL => {
+                Some(text.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn set(&self, text: String) {
+        *self.0.lock().unwrap() = Some((Instant::now(), text));
+    }
+}
+
+// Counts of users for dashboards, in the Prometheus text format. Duplicate
+// emails are normalized emails held by more than one active user, which
+// only happens with uniqueness checks off or data from before them.
+pub async fn user_metrics(
+    pool: web::Data<DbPool>,
+    cache: web::Data<UserMetricsCache>,
+) -> Result<HttpResponse, UserError> {
+    if let Some(text) = cache.get() {
+        return Ok(HttpResponse::Ok().content_type(PROMETHEUS_TEXT).body(text));
+    }
+
+    let metrics = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        diesel::sql_query(
+            "SELECT count(*) FILTER (WHERE deleted_at IS NULL) AS active, \
+             count(*) FILTER (WHERE deleted_at IS NOT NULL) AS deleted, \
+             count(*) FILTER (WHERE created_at > now() - interval '1 hour') \
+              AS created_last_hour, \
+             count(*) FILTER (WHERE created_at > now() - interval '1 day') \
+              AS created_last_day, \
+             (SELECT count(*) FROM (SELECT 1 FROM users WHERE deleted_at IS NULL \
+              GROUP BY email_normalized HAVING count(*) > 1) AS shared) AS duplicate_emails \
+             FROM users",
+        )
+        .get_result::<models::UserMetrics>(&mut conn)
+        .map_err(UserError::from)
+    })
+    .await
+    .map_err(UserError::from)??;
+
+    let text = render_user_metrics(&metrics);
+    cache.set(text.clone());
+    Ok(HttpResponse::Ok().content_type(PROMETHEUS_TEXT).body(text))
+}
+
+fn render_user_metrics(metrics: &models::UserMetrics) -> String {
+    let gauges = [
+        ("users_total", "Users not soft-deleted", metrics.active),
+        ("users_soft_deleted", "Soft-deleted users not yet purged", metrics.deleted),
+        (
+            "users_created_last_hour",
+            "Users created in the last hour, including since deleted ones",
+            metrics.created_last_hour,
+        ),
+        (
+            "users_created_last_day",
+            "Users created in the last 24 hours, including since deleted ones",
+            metrics.created_last_day,
+        ),
+        (
+            "users_duplicate_emails",
+            "Normalized emails held by more than one active user",
+            metrics.duplicate_emails,
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::uniqueness::{UniquenessPolicy, UniquenessRules};
+    use actix_web::http::{header, StatusCode};
+
+    #[test]
+    fn user_counts_are_prometheus_gauges() {
+        let text = render_user_metrics(&models::UserMetrics {
+            active: 3,
+            deleted: 1,
+            created_last_hour: 2,
+            created_last_day: 3,
+            duplicate_emails: 0,
+        });
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 15);
+        assert_eq!(lines[0], "# HELP users_total Users not soft-deleted");
+        assert_eq!(lines[1], "# TYPE users_total gauge");
+        assert_eq!(lines[2], "users_total 3");
+        for gauge in lines.chunks(3) {
+            let name = gauge[2].split(' ').next().unwrap();
+            assert!(gauge[0].starts_with(&format!("# HELP {} ", name)));
+            assert_eq!(gauge[1], format!("# TYPE {} gauge", name));
+        }
+        assert!(text.ends_with("users_duplicate_emails 0\n"));
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn users_are_counted_with_the_database_clock() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        crate::uniqueness::ensure_index(&mut conn, rules).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        testing::insert(&mut conn, "Augusta", "King", "ada@example.com").unwrap();
+        let grace = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let edsger = testing::insert(&mut conn, "Edsger", "Dijkstra", "ed@example.com").unwrap();
+        diesel::sql_query("UPDATE users SET created_at = now() - interval '2 hours' WHERE id = $1")
+            .bind::<diesel::sql_types::Integer, _>(grace.id)
+            .execute(&mut conn)
+            .unwrap();
+        diesel::sql_query(
+            "UPDATE users SET created_at = now() - interval '2 days', deleted_at = now() \
+             WHERE id = $1",
+        )
+        .bind::<diesel::sql_types::Integer, _>(edsger.id)
+        .execute(&mut conn)
+        .unwrap();
+
+        let config = testing::config(&[]);
+        let get = actix_web::test::TestRequest::get().uri("/metrics/users");
+        let response = testing::call(&pool, &config, get).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), PROMETHEUS_TEXT);
+        let body = actix_web::test::read_body(response).await;
+        let values: Vec<&str> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                "users_total 3",
+                "users_soft_deleted 1",
+                "users_created_last_hour 2",
+                "users_created_last_day 3",
+                "users_duplicate_emails 1",
+            ]
+        );
+    }
+}
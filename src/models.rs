@@ -1,13 +1,37 @@
+use actix_web::http::header::EntityTag;
+use actix_web::HttpRequest;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use crate::metrics::PoolWaitSummary;
 use crate::schema::users;
+use crate::uuid_format;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
 use uuid::Uuid;
+
+// Set once at startup from OMIT_NULL_FIELDS. When on, None fields of `User`
+// are left out of responses instead of being sent as explicit nulls.
+pub static OMIT_NULL_FIELDS: AtomicBool = AtomicBool::new(false);
+
+// Set once at startup from PAGINATION_OOR. When on, out of range `page` and
+// `per_page` values fail the query with 400 instead of being clamped.
+pub static REJECT_OUT_OF_RANGE_PAGES: AtomicBool = AtomicBool::new(false);
+
+// Set once at startup from PAGINATION_LINKS, see `Paginated::with_links`
+pub static PAGINATION_LINKS: AtomicBool = AtomicBool::new(false);
+
+fn omit_if_null<T>(value: &Option<T>) -> bool {
+    value.is_none() && OMIT_NULL_FIELDS.load(Ordering::Relaxed)
+}
 #[derive(Serialize)]
 pub struct GenericResponse<T> {
     pub status: String,
     pub message: String,
     pub data: Option<T>,
+    // Things about the input worth a look that didn't stop the request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Insertable, Queryable)]
@@ -18,20 +42,69 @@ pub struct Users {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
-    pub created_at: NaiveDateTime,
+    pub created_by: Option<String>,
 }
 
-#[derive(Queryable, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub id: i32,
+    #[serde(serialize_with = "uuid_format::serialize")]
     pub user_id: Uuid,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    #[serde(skip_serializing_if = "omit_if_null")]
+    pub deleted_at: Option<NaiveDateTime>,
+    #[serde(skip_serializing_if = "omit_if_null")]
+    pub last_login: Option<NaiveDateTime>,
+    pub is_stale: bool,
+    // Generated by Postgres, only used for comparisons
+    #[serde(skip)]
+    pub email_normalized: String,
+    // Always a JSON object, see `/users/{id}/metadata`
+    #[serde(skip_serializing_if = "omit_if_null")]
+    pub metadata: Option<serde_json::Value>,
+    // Generated by Postgres from the first and last name
+    pub display_name: String,
+    // The principal that added the user, see `admin::principal`
+    #[serde(skip_serializing_if = "omit_if_null")]
+    pub created_by: Option<String>,
+}
+
+impl User {
+    // Every column as a JSON array in a fixed order, independent of the
+    // response settings. Hashed by `/admin/table-checksum` and `etag`.
+    pub fn canonical(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            self.id,
+            self.user_id.hyphenated().to_string(),
+            &self.first_name,
+            &self.last_name,
+            &self.email,
+            self.created_at,
+            self.updated_at,
+            self.deleted_at,
+            self.last_login,
+            self.is_stale,
+            &self.email_normalized,
+            &self.metadata,
+            &self.display_name,
+            &self.created_by,
+        ))
+        .expect("a user row always serializes")
+    }
+
+    // Weak, since the JSON differs with OMIT_NULL_FIELDS and ?uuid_format
+    // while the user is the same
+    pub fn etag(&self) -> EntityTag {
+        let digest = Sha256::digest(self.canonical());
+        EntityTag::new_weak(format!("{:x}", digest)[..32].to_string())
+    }
 }
 
-#[derive(Insertable, Deserialize)]
+#[derive(Insertable, Deserialize, Clone, Debug)]
 #[diesel(table_name = users)]
 pub struct NewUser {
     pub first_name: String,
@@ -39,10 +112,1062 @@ pub struct NewUser {
     pub email: String,
 }
 
-#[derive(AsChangeset, Debug, Deserialize)]
+// `id`, `user_id` and `created_at` are immutable. They are deliberately left
+// out of the changeset, and unknown fields are rejected so a request trying to
+// set them fails with 400 instead of being silently ignored.
+#[derive(AsChangeset, Debug, Deserialize, Clone)]
 #[diesel(table_name = users)]
+#[serde(deny_unknown_fields)]
 pub struct UpdateUser {
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub email: Option<String>,
-}
\ No newline at end of file
+}
+
+impl UpdateUser {
+    pub fn has_changes(&self) -> bool {
+        self.first_name.is_some() || self.last_name.is_some() || self.email.is_some()
+    }
+}
+#[derive(Deserialize)]
+pub struct UpdateQuery {
+    // Respond with only the fields the update changed
+    pub changed_only: Option<bool>,
+}
+
+// `user_id` plus every field whose value differs between `before` and
+// `after`. `updated_at` is included whenever anything changed.
+pub fn changed_fields(before: &User, after: &User) -> serde_json::Map<String, serde_json::Value> {
+    let as_map = |user: &User| match serde_json::to_value(user) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    let before = as_map(before);
+
+    let mut changed: serde_json::Map<_, _> = as_map(after)
+        .into_iter()
+        .filter(|(key, value)| before.get(key) != Some(value))
+        .collect();
+    changed.insert("user_id".to_string(), after.user_id.to_string().into());
+    changed
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    // RFC 3339 timestamp, exclusive
+    pub since: String,
+    // Tie-breaker for rows sharing the `since` timestamp, taken from `next`
+    pub after_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct Tombstone {
+    #[serde(serialize_with = "uuid_format::serialize")]
+    pub user_id: Uuid,
+    pub deleted_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+pub struct ChangesCursor {
+    pub since: NaiveDateTime,
+    pub after_id: i32,
+}
+
+#[derive(Serialize)]
+pub struct Changes {
+    pub updated: Vec<User>,
+    pub deleted: Vec<Tombstone>,
+    // Pass back as `since`/`after_id` to fetch the next page, None when caught up
+    pub next: Option<ChangesCursor>,
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    // Opaque, taken from `next_cursor` of the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+// Position in the creation feed: the last user of a page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeedCursor {
+    pub created_at: NaiveDateTime,
+    pub id: i32,
+}
+
+impl FeedCursor {
+    // URL-safe base64 of `<created_at in microseconds>:<id>`, see
+    // `validation::parse_feed_cursor`
+    pub fn encode(&self) -> String {
+        use base64::Engine;
+
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("{}:{}", self.created_at.and_utc().timestamp_micros(), self.id))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Feed {
+    pub items: Vec<User>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PoolHealth {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_size: u32,
+    pub wait: PoolWaitSummary,
+}
+
+// `/admin/status`
+#[derive(Serialize)]
+pub struct SystemStatus {
+    pub healthy: bool,
+    pub database: DatabaseStatus,
+    pub pool: PoolHealth,
+    // Failed startup checks, see `/readyz`
+    pub readiness: Vec<String>,
+    pub background: BackgroundStatus,
+    pub jobs: JobCounts,
+}
+
+#[derive(Serialize)]
+pub struct DatabaseStatus {
+    pub ok: bool,
+    pub latency_ms: Option<f64>,
+    // The newest migration diesel recorded, None if it recorded none
+    pub last_migration: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BackgroundStatus {
+    // Tasks and jobs still running on the background arbiter
+    pub tasks: usize,
+    pub shutting_down: bool,
+}
+
+// Jobs kept by the job store, finished ones until they are pruned
+#[derive(Serialize, Default)]
+pub struct JobCounts {
+    pub pending: usize,
+    pub running: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize)]
+pub struct PoolStats {
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_size: u32,
+}
+
+#[derive(Serialize)]
+pub struct PoolRecycle {
+    pub before: PoolStats,
+    pub after: PoolStats,
+}
+
+// A row of diesel's `__diesel_schema_migrations`, see `/admin/migrations`
+#[derive(QueryableByName, Serialize)]
+pub struct AppliedMigration {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub version: String,
+    #[diesel(sql_type = diesel::sql_types::Timestamp)]
+    pub run_on: NaiveDateTime,
+}
+
+// `/admin/normalization-preview?limit=`
+#[derive(Deserialize)]
+pub struct NormalizationPreviewQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Serialize)]
+pub struct NormalizationSample {
+    #[serde(serialize_with = "uuid_format::serialize")]
+    pub user_id: Uuid,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize)]
+pub struct NormalizationRejection {
+    #[serde(serialize_with = "uuid_format::serialize")]
+    pub user_id: Uuid,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+pub struct NormalizationPreview {
+    pub scanned: usize,
+    // Rows the current normalization would store differently
+    pub changed: usize,
+    // Rows it would refuse, e.g. control characters with SANITIZE_INPUT=reject
+    pub rejected: usize,
+    // More rows exist past `limit`
+    pub truncated: bool,
+    pub samples: Vec<NormalizationSample>,
+    pub rejections: Vec<NormalizationRejection>,
+}
+
+#[derive(Serialize)]
+pub struct TableChecksum {
+    pub algorithm: &'static str,
+    // Lowercase hex
+    pub digest: String,
+    pub rows: usize,
+}
+
+#[derive(Serialize)]
+pub struct TimestampRepair {
+    pub fixed: usize,
+    pub batches: usize,
+}
+
+#[derive(Serialize)]
+pub struct ColumnMismatch {
+    pub column: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Serialize)]
+pub struct SchemaReport {
+    pub ok: bool,
+    pub missing: Vec<String>,
+    pub mismatched: Vec<ColumnMismatch>,
+    pub unexpected: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    #[default]
+    Atomic,
+    BestEffort,
+}
+
+// What a create op does when the user already exists under the uniqueness
+// policy
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchConflict {
+    // The op fails with 409
+    #[default]
+    Error,
+    // The op succeeds without changing anything
+    Skip,
+    // The existing active user gets the op's names
+    Update,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CreateOutcome {
+    Inserted,
+    Updated,
+    Skipped,
+}
+
+#[derive(Deserialize)]
+pub struct BatchOpsQuery {
+    pub mode: Option<BatchMode>,
+    pub conflict: Option<BatchConflict>,
+    // Apply the batch in a transaction that is rolled back
+    pub dry_run: Option<bool>,
+    // Fail with 409 listing every create op email that duplicates another
+    // op's or an existing user's, before applying anything
+    pub precheck: Option<bool>,
+    // Run the batch as a background job, see `/jobs/{id}`
+    #[serde(rename = "async")]
+    pub run_async: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct JobAccepted {
+    pub job_id: Uuid,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOp {
+    Create { user: NewUser },
+    Update { user_id: Uuid, changes: UpdateUser },
+    Delete { user_id: Uuid },
+}
+
+impl BatchOp {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BatchOp::Create { .. } => "create",
+            BatchOp::Update { .. } => "update",
+            BatchOp::Delete { .. } => "delete",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BatchOpResult {
+    pub index: usize,
+    pub op: &'static str,
+    pub ok: bool,
+    // What the op would have gotten as its own request: 201 for an insert,
+    // 200 for other successes, the error's status otherwise
+    pub status: u16,
+    // Only in dry runs, for updates and deletes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<User>,
+    // Only for successful creates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<CreateOutcome>,
+    // None for a skipped create
+    pub user: Option<User>,
+    pub error: Option<String>,
+    // Removed to stay within MAX_USERS, only published to the change feed
+    #[serde(skip)]
+    pub trimmed: Vec<User>,
+}
+
+#[derive(Serialize)]
+pub struct BatchDryRun {
+    // Operations that would succeed
+    pub would_apply: usize,
+    pub samples: Vec<BatchOpResult>,
+}
+
+// Default and maximum page size for paginated lists
+pub const DEFAULT_PER_PAGE: i64 = 20;
+pub const MAX_PER_PAGE: i64 = 100;
+
+// `?page=&per_page=` with 1-based pages. Out of range values are clamped,
+// or rejected with REJECT_OUT_OF_RANGE_PAGES.
+#[derive(Deserialize)]
+#[serde(try_from = "PaginationParams")]
+pub struct Pagination {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    // Count the total even when TOTAL_ON_FIRST_PAGE_ONLY would skip it
+    pub with_total: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct PaginationParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    with_total: Option<bool>,
+}
+
+impl TryFrom<PaginationParams> for Pagination {
+    type Error = String;
+
+    fn try_from(params: PaginationParams) -> Result<Self, Self::Error> {
+        if REJECT_OUT_OF_RANGE_PAGES.load(Ordering::Relaxed) {
+            if let Some(page) = params.page.filter(|page| *page < 1) {
+                return Err(format!("page must be at least 1, got {}", page));
+            }
+            let out_of_range = |per_page: &i64| !(1..=MAX_PER_PAGE).contains(per_page);
+            if let Some(per_page) = params.per_page.filter(out_of_range) {
+                return Err(format!(
+                    "per_page must be between 1 and {}, got {}",
+                    MAX_PER_PAGE, per_page
+                ));
+            }
+        }
+
+        Ok(Pagination {
+            page: params.page,
+            per_page: params.per_page,
+            with_total: params.with_total,
+        })
+    }
+}
+
+impl Pagination {
+    pub fn page(&self) -> i64 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> i64 {
+        self.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page() - 1) * self.per_page()
+    }
+
+    // Whether to run the count query. With `first_page_only` later pages
+    // skip it unless `?with_total=true` asks for it.
+    pub fn wants_total(&self, first_page_only: bool) -> bool {
+        self.with_total.unwrap_or(!first_page_only || self.page() == 1)
+    }
+}
+
+// `/users/lookup?key=&value=`
+#[derive(Deserialize)]
+pub struct LookupQuery {
+    pub key: String,
+    pub value: String,
+}
+
+// `/users/labels?per_page=&sheets=&page=`. `per_page` is labels per sheet,
+// `page` counts pages of `sheets` sheets.
+#[derive(Deserialize)]
+pub struct LabelsQuery {
+    pub per_page: Option<i64>,
+    pub sheets: Option<i64>,
+    pub page: Option<i64>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct Label {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+// Full sheets of labels. The last sheet of the last page is padded with
+// nulls to `per_page` labels, so every sheet lays out as the same grid.
+#[derive(Serialize)]
+pub struct LabelSheets {
+    pub per_page: i64,
+    pub page: i64,
+    pub sheets: Vec<Vec<Option<Label>>>,
+    pub has_more: bool,
+}
+
+// `/users/nearest-email?email=&max_distance=`
+#[derive(Deserialize)]
+pub struct NearestEmailQuery {
+    pub email: String,
+    pub max_distance: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct NearestEmail {
+    pub user: User,
+    // Levenshtein distance between the lowercased emails
+    pub distance: i32,
+}
+
+// `/users/search?q=`
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    #[default]
+    Id,
+    DisplayName,
+}
+
+// A `Range: items=` slice of get_users, answered with 206
+pub struct ItemsRange {
+    pub items: Vec<User>,
+    pub first: i64,
+    pub total: i64,
+}
+
+// `?sort=id|display_name&name=&check_deliverable=` of get_users
+#[derive(Deserialize)]
+pub struct UserListQuery {
+    pub sort: Option<UserSort>,
+    // Case-insensitive substring of the display name
+    pub name: Option<String>,
+    pub check_deliverable: Option<bool>,
+}
+
+// `/users/import.csv` options. A column is a header name, or a 0-based index
+// with `header=false`; by default the header names match the fields, or the
+// columns are first_name, last_name, email in that order.
+#[derive(Deserialize)]
+pub struct CsvImportQuery {
+    pub header: Option<bool>,
+    pub on_duplicate: Option<ImportDuplicate>,
+    pub first_name_column: Option<String>,
+    pub last_name_column: Option<String>,
+    pub email_column: Option<String>,
+}
+
+// What an imported row with the email of an active user does
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportDuplicate {
+    // Fails the row with the uniqueness conflict
+    #[default]
+    Error,
+    // Its non-empty names are written to the existing user
+    Merge,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportOutcome {
+    Inserted,
+    Merged,
+    Unchanged,
+}
+
+#[derive(Serialize)]
+pub struct CsvImportReport {
+    pub inserted: usize,
+    pub merged: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    // The first failures, `failed` counts them all
+    pub errors: Vec<CsvRowError>,
+    // With `on_duplicate=merge`, the first rows written and how
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rows: Vec<CsvRowOutcome>,
+}
+
+#[derive(Serialize)]
+pub struct CsvRowOutcome {
+    pub row: u64,
+    pub outcome: ImportOutcome,
+}
+
+#[derive(Serialize)]
+pub struct CsvRowError {
+    // Line in the CSV, 1-based
+    pub row: u64,
+    pub error: String,
+}
+
+#[derive(Deserialize)]
+pub struct ValidateEmailQuery {
+    pub email: String,
+    pub check_mx: Option<bool>,
+}
+
+// `?check_deliverable=true` of get_user, get_users takes it in `UserListQuery`
+#[derive(Deserialize)]
+pub struct DeliverableQuery {
+    pub check_deliverable: Option<bool>,
+}
+
+// A user with what was looked up about it on request
+#[derive(Serialize)]
+pub struct AnnotatedUser {
+    #[serde(flatten)]
+    pub user: User,
+    // Only with `?check_deliverable=true`: whether the email's domain has MX
+    // records, null when unknown (lookup failed or timed out)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_deliverable: Option<Option<bool>>,
+}
+
+#[derive(Serialize)]
+pub struct EmailCheck {
+    pub syntax_valid: bool,
+    // None unless `check_mx=true` and the syntax is valid
+    pub domain_has_mx: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct EmailRegexQuery {
+    // POSIX regex matched case-insensitively against the whole email
+    pub pattern: String,
+}
+
+#[derive(Deserialize)]
+pub struct EstimateQuery {
+    // Report the planner's estimate as the total instead of counting
+    pub estimate_total: Option<bool>,
+}
+
+// `?explain=true&plan=true`, see ALLOW_EXPLAIN
+#[derive(Deserialize)]
+pub struct ExplainQuery {
+    pub explain: Option<bool>,
+    pub plan: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct QueryExplain {
+    pub sql: String,
+    pub plan: Option<Vec<String>>,
+}
+
+// The list envelope used as `data` by paginated endpoints
+#[derive(Serialize)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub page: i64,
+    pub per_page: i64,
+    // Null when the count was skipped, see `Pagination::wants_total`
+    pub total: Option<i64>,
+    pub total_pages: Option<i64>,
+    // Set when `total` is the planner's estimate, see `?estimate_total=true`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub total_estimated: bool,
+    // Only with `?explain=true`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explain: Option<QueryExplain>,
+    // Only with PAGINATION_LINKS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub links: Option<PageLinks>,
+}
+
+impl<T> Paginated<T> {
+    // A page past the last one is just empty, it still reports the totals
+    pub fn new(items: Vec<T>, pagination: &Pagination, total: Option<i64>) -> Paginated<T> {
+        let per_page = pagination.per_page();
+
+        Paginated {
+            items,
+            page: pagination.page(),
+            per_page,
+            total,
+            total_pages: total.map(|total| (total + per_page - 1) / per_page),
+            total_estimated: false,
+            explain: None,
+            links: None,
+        }
+    }
+
+    pub fn map_items<U>(self, f: impl FnMut(T) -> U) -> Paginated<U> {
+        Paginated {
+            items: self.items.into_iter().map(f).collect(),
+            page: self.page,
+            per_page: self.per_page,
+            total: self.total,
+            total_pages: self.total_pages,
+            total_estimated: self.total_estimated,
+            explain: self.explain,
+            links: self.links,
+        }
+    }
+
+    // With PAGINATION_LINKS, adds links to the neighbouring pages: the
+    // request's path and query with only `page` changed, so filters, sort and
+    // every other parameter carry over. Without a total, a full page is
+    // assumed to have a next one.
+    pub fn with_links(mut self, req: &HttpRequest) -> Paginated<T> {
+        if !PAGINATION_LINKS.load(Ordering::Relaxed) {
+            return self;
+        }
+
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+        let link = |page: i64| {
+            let mut params: Vec<(String, String)> =
+                params.iter().filter(|(name, _)| name != "page").cloned().collect();
+            params.push(("page".to_string(), page.to_string()));
+            format!(
+                "{}?{}",
+                req.path(),
+                serde_urlencoded::to_string(params).unwrap_or_default()
+            )
+        };
+
+        let has_next = match self.total_pages {
+            Some(total_pages) => self.page < total_pages,
+            None => self.items.len() as i64 == self.per_page,
+        };
+        self.links = Some(PageLinks {
+            next: has_next.then(|| link(self.page + 1)),
+            prev: (self.page > 1).then(|| link(self.page - 1)),
+        });
+        self
+    }
+}
+
+#[derive(Serialize)]
+pub struct PageLinks {
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+// Which active users `/users/reassign` updates. Set fields are combined
+// with AND.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ReassignFilter {
+    pub domain: Option<String>,
+    pub is_stale: Option<bool>,
+}
+
+impl ReassignFilter {
+    pub fn is_empty(&self) -> bool {
+        self.domain.is_none() && self.is_stale.is_none()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Reassign {
+    #[serde(default)]
+    pub filter: ReassignFilter,
+    pub changes: UpdateUser,
+}
+
+#[derive(Deserialize)]
+pub struct ReassignQuery {
+    // Needed to update every active user with an empty filter
+    pub allow_all: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Reassigned {
+    pub updated: usize,
+}
+
+#[derive(Deserialize)]
+pub struct EmailSwap {
+    pub first: Uuid,
+    pub second: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct Anonymized {
+    pub anonymized: usize,
+}
+
+// One entry of `/users/batch-get-conditional`: the ETag the client has
+// cached for the user, if any
+#[derive(Deserialize)]
+pub struct ConditionalGet {
+    pub user_id: Uuid,
+    pub etag: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum ConditionalUser {
+    Modified {
+        etag: String,
+        user: Box<User>,
+    },
+    NotModified {
+        #[serde(serialize_with = "uuid_format::serialize")]
+        user_id: Uuid,
+        not_modified: bool,
+    },
+    // No active user with this id, drop it from the cache
+    NotFound {
+        #[serde(serialize_with = "uuid_format::serialize")]
+        user_id: Uuid,
+        not_found: bool,
+    },
+}
+
+// `/users/by-emails` result, `not_found` lists the normalized emails
+// without an active user
+#[derive(Serialize)]
+pub struct EmailLookup {
+    pub items: Vec<User>,
+    pub not_found: Vec<String>,
+}
+
+// Earliest and latest created active users, both None without users
+#[derive(Serialize)]
+pub struct Bookends {
+    pub first: Option<User>,
+    pub last: Option<User>,
+}
+
+// The active users created right before and after a user, None at either end
+#[derive(Serialize)]
+pub struct Neighbors {
+    pub previous: Option<User>,
+    pub next: Option<User>,
+}
+
+// Character lengths, all None when there are no users
+#[derive(Serialize)]
+pub struct LengthStats {
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+    pub avg: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct NameStats {
+    pub users: i64,
+    pub first_name: LengthStats,
+    pub last_name: LengthStats,
+}
+
+// A request kept by `access_log::RecentProblems`
+#[derive(Serialize, Clone)]
+pub struct ProblemRequest {
+    pub at: NaiveDateTime,
+    // "error" for a 5xx, otherwise "slow"
+    pub kind: &'static str,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    // The X-Request-Id header, if the client or a proxy sent one
+    pub request_id: Option<String>,
+}
+
+// An entry of `/users/distinct/{column}`
+#[derive(QueryableByName, Serialize)]
+pub struct DistinctValue {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub value: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub count: i64,
+}
+
+// Active users sharing a first and last name, see `/users/name-duplicates`
+#[derive(QueryableByName, Serialize)]
+pub struct NameDuplicateGroup {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub first_name: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub last_name: String,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub count: i64,
+    // Oldest first
+    #[diesel(sql_type = diesel::sql_types::Array<diesel::sql_types::Uuid>)]
+    #[serde(serialize_with = "uuid_format::serialize_all")]
+    pub user_ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HistogramUnit {
+    Minute,
+    Hour,
+    #[default]
+    Day,
+}
+
+impl HistogramUnit {
+    // The field name for date_trunc and the interval
+    pub fn unit(self) -> &'static str {
+        match self {
+            HistogramUnit::Minute => "minute",
+            HistogramUnit::Hour => "hour",
+            HistogramUnit::Day => "day",
+        }
+    }
+
+    pub fn seconds(self) -> i64 {
+        match self {
+            HistogramUnit::Minute => 60,
+            HistogramUnit::Hour => 3600,
+            HistogramUnit::Day => 86400,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistogramQuery {
+    pub bucket: Option<HistogramUnit>,
+    // RFC 3339 timestamps, inclusive
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(QueryableByName, Serialize)]
+pub struct HistogramBucket {
+    #[diesel(sql_type = diesel::sql_types::Timestamp)]
+    pub bucket_start: NaiveDateTime,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub count: i64,
+}
+
+// 1-based position among active users by creation order
+#[derive(QueryableByName, Serialize)]
+pub struct UserRank {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    #[serde(serialize_with = "uuid_format::serialize")]
+    pub user_id: Uuid,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub rank: i64,
+}
+
+// The gauges of `/metrics/users`
+#[derive(QueryableByName)]
+pub struct UserMetrics {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub active: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub deleted: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub created_last_hour: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub created_last_day: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub duplicate_emails: i64,
+}
+
+#[derive(QueryableByName, Serialize)]
+pub struct UserCounts {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub active: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub deleted: i64,
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    pub total: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_user_rejects_immutable_fields() {
+        for field in [
+            r#""user_id": "6f2c1a4e-0d7b-4f0e-9a43-1c2b3d4e5f60""#,
+            r#""id": 7"#,
+            r#""created_at": "2024-01-01T00:00:00""#,
+        ] {
+            let body = format!(r#"{{"first_name": "Ada", {}}}"#, field);
+            let error = serde_json::from_str::<UpdateUser>(&body).unwrap_err();
+            assert!(error.to_string().contains("unknown field"), "{}: {}", field, error);
+        }
+    }
+
+    #[test]
+    fn update_user_accepts_mutable_fields() {
+        let changes: UpdateUser =
+            serde_json::from_str(r#"{"first_name": "Ada", "email": "ada@example.com"}"#).unwrap();
+        assert_eq!(changes.first_name.as_deref(), Some("Ada"));
+        assert_eq!(changes.last_name, None);
+        assert_eq!(changes.email.as_deref(), Some("ada@example.com"));
+    }
+
+    #[test]
+    fn null_fields_are_sent_unless_omitted() {
+        let _shared = crate::testing::lock();
+        let user = crate::testing::user(1);
+
+        let explicit = serde_json::to_value(&user).unwrap();
+        assert_eq!(explicit["deleted_at"], serde_json::Value::Null);
+        assert!(explicit.as_object().unwrap().contains_key("last_login"));
+
+        OMIT_NULL_FIELDS.store(true, Ordering::Relaxed);
+        let omitted = serde_json::to_value(&user).unwrap();
+        OMIT_NULL_FIELDS.store(false, Ordering::Relaxed);
+
+        let omitted = omitted.as_object().unwrap();
+        for field in ["deleted_at", "last_login", "metadata", "created_by"] {
+            assert!(!omitted.contains_key(field), "{} was sent", field);
+        }
+        assert!(omitted.contains_key("email"));
+    }
+
+    fn pagination(query: &str) -> Pagination {
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    #[test]
+    fn page_zero_is_the_first_page() {
+        let _shared = crate::testing::lock();
+        let page_zero = pagination("page=0&per_page=20");
+        assert_eq!((page_zero.page(), page_zero.offset()), (1, 0));
+
+        let page = Paginated::new(vec![1, 2, 3], &page_zero, Some(45));
+        assert_eq!((page.page, page.total_pages), (1, Some(3)));
+    }
+
+    #[test]
+    fn first_page_reports_total_pages() {
+        let _shared = crate::testing::lock();
+        let first = pagination("page=1&per_page=20");
+        assert_eq!(first.offset(), 0);
+
+        let page = Paginated::new(vec![1; 20], &first, Some(40));
+        assert_eq!((page.total, page.total_pages), (Some(40), Some(2)));
+        let empty = Paginated::<i32>::new(Vec::new(), &first, Some(0));
+        assert_eq!(empty.total_pages, Some(0));
+    }
+
+    #[test]
+    fn page_past_the_end_is_empty_with_totals() {
+        let _shared = crate::testing::lock();
+        let past_end = pagination("page=5&per_page=20");
+        assert_eq!(past_end.offset(), 80);
+
+        let page = Paginated::<i32>::new(Vec::new(), &past_end, Some(45));
+        assert!(page.items.is_empty());
+        assert_eq!((page.page, page.total, page.total_pages), (5, Some(45), Some(3)));
+    }
+
+    #[test]
+    fn changed_fields_has_only_the_changed_field_and_the_user_id() {
+        let _shared = crate::testing::lock();
+        let before = crate::testing::user(1);
+        let mut after = before.clone();
+        after.last_name = "King".to_string();
+
+        let changed = changed_fields(&before, &after);
+        let mut keys: Vec<&str> = changed.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["last_name", "user_id"]);
+        assert_eq!(changed["last_name"], "King");
+        assert_eq!(changed["user_id"], before.user_id.to_string());
+    }
+
+    #[test]
+    fn total_is_counted_only_when_requested() {
+        let _shared = crate::testing::lock();
+        assert!(pagination("page=3").wants_total(false));
+
+        assert!(pagination("page=1").wants_total(true));
+        assert!(!pagination("page=2").wants_total(true));
+        assert!(pagination("page=2&with_total=true").wants_total(true));
+        assert!(!pagination("page=1&with_total=false").wants_total(true));
+
+        let page = Paginated::<i32>::new(Vec::new(), &pagination("page=2"), None);
+        assert_eq!((page.total, page.total_pages), (None, None));
+    }
+
+    #[test]
+    fn out_of_range_pages_are_clamped_or_rejected() {
+        let _shared = crate::testing::lock();
+        let clamped = pagination("page=-3&per_page=500");
+        assert_eq!((clamped.page(), clamped.per_page()), (1, MAX_PER_PAGE));
+        assert_eq!(pagination("per_page=0").per_page(), 1);
+
+        REJECT_OUT_OF_RANGE_PAGES.store(true, Ordering::Relaxed);
+        let refused = |query: &str| {
+            serde_urlencoded::from_str::<Pagination>(query).err().map(|error| error.to_string())
+        };
+        let negative_page = refused("page=-3");
+        let large_page_size = refused("per_page=500");
+        let in_range = refused("page=2&per_page=100");
+        REJECT_OUT_OF_RANGE_PAGES.store(false, Ordering::Relaxed);
+
+        assert_eq!(negative_page.as_deref(), Some("page must be at least 1, got -3"));
+        assert_eq!(large_page_size.as_deref(), Some("per_page must be between 1 and 100, got 500"));
+        assert_eq!(in_range, None);
+    }
+
+    #[test]
+    fn page_links_keep_every_other_parameter() {
+        let _shared = crate::testing::lock();
+        let query = "name=ada%20l&sort=display_name&page=2&per_page=1&meta.team=core";
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/get?{}", query))
+            .to_http_request();
+        let page = || Paginated::new(vec![7], &pagination(query), Some(3));
+
+        assert!(page().with_links(&req).links.is_none());
+
+        PAGINATION_LINKS.store(true, Ordering::Relaxed);
+        let links = page().with_links(&req).links.unwrap();
+        PAGINATION_LINKS.store(false, Ordering::Relaxed);
+
+        let rest = "name=ada+l&sort=display_name&per_page=1&meta.team=core";
+        assert_eq!(links.next, Some(format!("/get?{}&page=3", rest)));
+        assert_eq!(links.prev, Some(format!("/get?{}&page=1", rest)));
+    }
+}
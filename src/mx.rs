@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use futures_util::{stream, StreamExt};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+
+// DNS_RESOLVER is `system` for the resolvers of /etc/resolv.conf, or the
+// address of a name server, with port 53 unless given
+pub fn build_resolver(spec: &str, timeout: Duration) -> Result<TokioAsyncResolver, String> {
+    if spec == "system" {
+        return TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string());
+    }
+
+    let server = parse_name_server(spec)?;
+    let config = ResolverConfig::from_parts(
+        None,
+        Vec::new(),
+        NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true),
+    );
+    let mut options = ResolverOpts::default();
+    options.timeout = timeout;
+    options.attempts = 1;
+
+    Ok(TokioAsyncResolver::tokio(config, options))
+}
+
+pub fn parse_name_server(spec: &str) -> Result<SocketAddr, String> {
+    spec.parse::<SocketAddr>()
+        .or_else(|_| spec.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| "expected system or a name server address".to_string())
+}
+
+// Whether the domain publishes MX records. A missing domain or a domain
+// without MX records is false; lookup failures and timeouts are errors.
+pub async fn has_mx(
+    resolver: &TokioAsyncResolver,
+    domain: &str,
+    timeout: Duration,
+) -> Result<bool, String> {
+    // The trailing dot keeps the search domains out of the lookup
+    let lookup = resolver.mx_lookup(format!("{}.", domain));
+
+    match actix_rt::time::timeout(timeout, lookup).await {
+        Ok(Ok(records)) => Ok(records.iter().next().is_some()),
+        Ok(Err(e)) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(false),
+            _ => Err(e.to_string()),
+        },
+        Err(_) => Err("MX lookup timed out".to_string()),
+    }
+}
+
+// Domains cached by `MxCache` at most, and looked up at once by
+// `lookup_domains`
+const MAX_CACHED_DOMAINS: usize = 10_000;
+const LOOKUP_CONCURRENCY: usize = 16;
+
+// `has_mx` results by domain, kept for MX_CACHE_TTL_SECS so annotating
+// listings doesn't query DNS for the same domains on every request. Failed
+// lookups aren't cached.
+pub struct MxCache {
+    entries: DashMap<String, (bool, Instant)>,
+    ttl: Duration,
+}
+
+impl MxCache {
+    pub fn new(ttl: Duration) -> MxCache {
+        MxCache {
+            entries: DashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, domain: &str) -> Option<bool> {
+        let (has_mx, cached_at) = *self.entries.get(domain)?;
+        if cached_at.elapsed() < self.ttl {
+            return Some(has_mx);
+        }
+        self.entries.remove(domain);
+        None
+    }
+
+    // When full, expired entries are dropped first. If it is still full the
+    // result isn't cached.
+    pub fn insert(&self, domain: &str, has_mx: bool) {
+        if self.entries.len() >= MAX_CACHED_DOMAINS {
+            self.entries.retain(|_, (_, cached_at)| cached_at.elapsed() < self.ttl);
+        }
+        if self.entries.len() < MAX_CACHED_DOMAINS {
+            self.entries.insert(domain.to_string(), (has_mx, Instant::now()));
+        }
+    }
+}
+
+// `has_mx` for each domain, from the cache or looked up concurrently, all
+// within one `timeout`. Domains whose lookup failed or didn't finish in time
+// are missing from the result.
+pub async fn lookup_domains(
+    cache: &MxCache,
+    resolver: &TokioAsyncResolver,
+    domains: HashSet<String>,
+    timeout: Duration,
+) -> HashMap<String, bool> {
+    let mut found = HashMap::new();
+    let mut missing = Vec::new();
+    for domain in domains {
+        match cache.get(&domain) {
+            Some(has_mx) => {
+                found.insert(domain, has_mx);
+            }
+            None => missing.push(domain),
+        }
+    }
+
+    let mut lookups = stream::iter(missing)
+        .map(|domain| async move {
+            let result = has_mx(resolver, &domain, timeout).await;
+            (domain, result)
+        })
+        .buffer_unordered(LOOKUP_CONCURRENCY)
+        .take_until(Box::pin(actix_rt::time::sleep(timeout)));
+
+    while let Some((domain, result)) = lookups.next().await {
+        match result {
+            Ok(has_mx) => {
+                cache.insert(&domain, has_mx);
+                found.insert(domain, has_mx);
+            }
+            Err(e) => log::warn!("MX lookup for {} failed: {}", domain, e),
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nothing listens there, so every lookup that reaches DNS fails
+    fn unreachable_resolver() -> TokioAsyncResolver {
+        build_resolver("127.0.0.1:9", Duration::from_millis(200)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn cached_domains_are_answered_without_dns() {
+        let cache = MxCache::new(Duration::from_secs(60));
+        cache.insert("example.com", true);
+        cache.insert("bounced.example", false);
+        let domains = ["example.com", "bounced.example", "unknown.example"]
+            .map(String::from)
+            .into();
+
+        let found =
+            lookup_domains(&cache, &unreachable_resolver(), domains, Duration::from_millis(200))
+                .await;
+        assert_eq!(found.get("example.com"), Some(&true));
+        assert_eq!(found.get("bounced.example"), Some(&false));
+        assert_eq!(found.get("unknown.example"), None);
+        assert_eq!(cache.get("unknown.example"), None);
+    }
+
+    #[actix_web::test]
+    async fn entries_expire_after_the_ttl() {
+        let cache = MxCache::new(Duration::from_millis(50));
+        cache.insert("example.com", true);
+        assert_eq!(cache.get("example.com"), Some(true));
+
+        actix_rt::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.get("example.com"), None);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, PooledConnection, State};
+use diesel::RunQueryDsl;
+
+type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// The connection pool behind a lock so it can be swapped for a fresh one at
+// runtime, see `/admin/pool/recycle`. Cloning shares the same slot.
+#[derive(Clone)]
+pub struct DbPool(Arc<RwLock<Pool>>);
+
+impl DbPool {
+    // `get_timeout` bounds how long `get` waits for a free connection. The
+    // pool keeps `min_idle` connections open, all of `max_size` if None.
+    pub fn build(
+        database_url: &str,
+        max_size: u32,
+        min_idle: Option<u32>,
+        get_timeout: Duration,
+    ) -> Result<DbPool, r2d2::PoolError> {
+        build_pool(database_url, max_size, min_idle, get_timeout)
+            .map(|pool| DbPool(Arc::new(RwLock::new(pool))))
+    }
+
+    fn current(&self) -> Pool {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn get(&self) -> Result<PooledConnection<ConnectionManager<PgConnection>>, r2d2::PoolError> {
+        self.current().get()
+    }
+
+    pub fn state(&self) -> State {
+        self.current().state()
+    }
+
+    pub fn max_size(&self) -> u32 {
+        self.current().max_size()
+    }
+
+    pub fn get_timeout(&self) -> Duration {
+        self.current().connection_timeout()
+    }
+
+    // Builds a pool of the same size and swaps it in. Connections checked out
+    // of the old pool are closed once they are returned, idle ones right away.
+    pub fn recycle(&self, database_url: &str) -> Result<(), r2d2::PoolError> {
+        self.resize(database_url, self.max_size())
+    }
+
+    // Like `recycle`, with a new maximum size. r2d2 pools can't be resized in
+    // place, so this always rebuilds.
+    pub fn resize(&self, database_url: &str, max_size: u32) -> Result<(), r2d2::PoolError> {
+        let current = self.current();
+        let rebuilt = build_pool(
+            database_url,
+            max_size,
+            current.min_idle(),
+            current.connection_timeout(),
+        )?;
+        *self.0.write().unwrap() = rebuilt;
+        Ok(())
+    }
+}
+
+fn build_pool(
+    database_url: &str,
+    max_size: u32,
+    min_idle: Option<u32>,
+    get_timeout: Duration,
+) -> Result<Pool, r2d2::PoolError> {
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+
+    r2d2::Pool::builder()
+        .max_size(max_size)
+        .min_idle(min_idle.map(|min_idle| min_idle.min(max_size)))
+        .connection_timeout(get_timeout)
+        .connection_customizer(Box::new(UtcSession))
+        .build(manager)
+}
+
+// Timestamps are stored without a time zone. Running every session in UTC
+// makes CURRENT_TIMESTAMP, which fills created_at, updated_at and deleted_at,
+// the same clock the handlers compare against, whatever the server's TimeZone.
+#[derive(Debug)]
+struct UtcSession;
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for UtcSession {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        diesel::sql_query("SET TIME ZONE 'UTC'")
+            .execute(conn)
+            .map(|_| ())
+            .map_err(r2d2::Error::QueryError)
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::Mutex;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::uniqueness::{self, UniquenessRules};
+use crate::DbPool;
+
+// Startup checks that must pass before the service reports ready on /readyz
+#[derive(Default)]
+pub struct Readiness {
+    problems: Mutex<Vec<String>>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.problems.lock().unwrap().is_empty()
+    }
+
+    pub fn problems(&self) -> Vec<String> {
+        self.problems.lock().unwrap().clone()
+    }
+
+    fn add_problem(&self, problem: String) {
+        self.problems.lock().unwrap().push(problem);
+    }
+}
+
+#[derive(QueryableByName)]
+struct InstalledExtension {
+    #[diesel(sql_type = Text)]
+    extname: String,
+}
+
+// Checks that every extension in `required` is installed. Missing ones are
+// created when `create` is set, otherwise they are reported as not ready.
+pub fn check_extensions(pool: &DbPool, required: &[String], create: bool, readiness: &Readiness) {
+    if required.is_empty() {
+        return;
+    }
+
+    let mut conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(e) => {
+            readiness.add_problem(format!("Could not check extensions: {}", e));
+            return;
+        }
+    };
+
+    let installed = match diesel::sql_query("SELECT extname::text FROM pg_extension")
+        .load::<InstalledExtension>(&mut conn)
+    {
+        Ok(installed) => installed,
+        Err(e) => {
+            readiness.add_problem(format!("Could not check extensions: {}", e));
+            return;
+        }
+    };
+
+    for name in required {
+        if installed.iter().any(|extension| &extension.extname == name) {
+            continue;
+        }
+
+        if !create {
+            readiness.add_problem(format!(
+                "Required extension {:?} is not installed, install it or set CREATE_EXTENSIONS=true",
+                name
+            ));
+            continue;
+        }
+
+        // Names are validated in the config, so quoting is enough here
+        if let Err(e) = diesel::sql_query(format!("CREATE EXTENSION IF NOT EXISTS \"{}\"", name))
+            .execute(&mut conn)
+        {
+            readiness.add_problem(format!("Could not create extension {:?}: {}", name, e));
+        }
+    }
+}
+
+// Puts the unique index for UNIQUENESS_POLICY in place. Users already
+// breaking the policy keep it from being created, which is reported as not
+// ready until they are cleaned up.
+pub fn check_uniqueness_index(pool: &DbPool, rules: UniquenessRules, readiness: &Readiness) {
+    let result = pool
+        .get()
+        .map_err(|e| e.to_string())
+        .and_then(|mut conn| uniqueness::ensure_index(&mut conn, rules).map_err(|e| e.to_string()));
+
+    if let Err(e) = result {
+        readiness.add_problem(format!(
+            "Could not create the unique index for UNIQUENESS_POLICY={}: {}",
+            rules.policy, e
+        ));
+    }
+}
+
+#[derive(QueryableByName)]
+struct MessageLocale {
+    #[diesel(sql_type = Text)]
+    lc_messages: String,
+}
+
+// Deadlocks are only recognized by Postgres' English message, see
+// `user_error::sqlstate`. Under another message locale they would fail with
+// 500 instead of being retried, so the service isn't ready until lc_messages
+// is English.
+pub fn check_message_locale(pool: &DbPool, readiness: &Readiness) {
+    let locale = pool.get().map_err(|e| e.to_string()).and_then(|mut conn| {
+        diesel::sql_query("SHOW lc_messages")
+            .get_result::<MessageLocale>(&mut conn)
+            .map_err(|e| e.to_string())
+    });
+
+    match locale {
+        Ok(locale) if is_english(&locale.lc_messages) => {}
+        Ok(locale) => readiness.add_problem(format!(
+            "lc_messages is {:?}, deadlocks would not be retried; set it to C or en_US",
+            locale.lc_messages
+        )),
+        Err(e) => readiness.add_problem(format!("Could not check lc_messages: {}", e)),
+    }
+}
+
+fn is_english(locale: &str) -> bool {
+    locale.is_empty()
+        || locale == "C"
+        || locale == "POSIX"
+        || locale.starts_with("C.")
+        || locale.starts_with("en")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::time::Duration;
+
+    #[test]
+    fn only_english_message_locales_are_ready() {
+        for english in ["", "C", "POSIX", "C.UTF-8", "en_US.UTF-8"] {
+            assert!(is_english(english), "{:?}", english);
+        }
+        for other in ["de_DE.UTF-8", "fr_FR", "ja_JP.UTF-8"] {
+            assert!(!is_english(other), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn an_english_lc_messages_is_ready() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+
+        let readiness = Readiness::default();
+        check_message_locale(&pool, &readiness);
+        assert!(readiness.is_ready(), "{:?}", readiness.problems());
+    }
+}
@@ -0,0 +1,70 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::{config::AppConfig, user_error::UserError};
+
+// Replaces read responses larger than MAX_RESPONSE_BYTES with a 413 asking
+// for smaller pages. Writes are left alone, they have been committed by the
+// time the response is seen. Only bodies of known size are checked, which is
+// every JSON response; streamed ones such as `/users/sync-stream` are the way
+// to fetch more than fits and pass through.
+pub async fn cap_response_size(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let read = matches!(*req.method(), Method::GET | Method::HEAD);
+    let res = next.call(req).await?;
+
+    let max = res
+        .request()
+        .app_data::<web::Data<AppConfig>>()
+        .filter(|_| read)
+        .map_or(0, |config| config.max_response_bytes);
+    match res.response().body().size() {
+        BodySize::Sized(size) if max > 0 && size > max => {
+            log::warn!(
+                "{} response of {} bytes over MAX_RESPONSE_BYTES={}",
+                res.request().path(),
+                size,
+                max
+            );
+            Ok(res.error_response(UserError::ResponseTooLarge(max)).map_into_right_body())
+        }
+        _ => Ok(res.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn reads_over_the_cap_are_answered_with_413() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        for n in 0..20 {
+            let address = format!("ada{}@example.com", n);
+            testing::insert(&mut conn, "Ada", "Lovelace", &address).unwrap();
+        }
+
+        let capped = testing::config(&[("MAX_RESPONSE_BYTES", "2000")]);
+        let unlimited = testing::config(&[]);
+        let get = |uri: &str| TestRequest::get().uri(uri);
+
+        let response = testing::call(&pool, &capped, get("/get?per_page=20")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = testing::call(&pool, &capped, get("/get?per_page=2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = testing::call(&pool, &unlimited, get("/get?per_page=20")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::OnceLock;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::config::UserTrim;
+use crate::models::User;
+
+// Set once at startup from MAX_USERS and MAX_USERS_TRIM, unset when the table
+// is unbounded
+pub static USER_CAP: OnceLock<UserCap> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserCap {
+    pub max_users: i64,
+    pub trim: UserTrim,
+}
+
+// Removes the oldest active users until at most `max_users` are left and
+// returns them, for the caller to publish once its transaction commits. Runs
+// in the inserting transaction, after the insert. The advisory lock
+// serializes inserts while a cap is set, otherwise two concurrent inserts
+// could both count before either trims and leave the table over the cap.
+pub fn trim_to_cap(conn: &mut PgConnection) -> QueryResult<Vec<User>> {
+    match USER_CAP.get() {
+        Some(cap) => trim(conn, *cap),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn trim(conn: &mut PgConnection, cap: UserCap) -> QueryResult<Vec<User>> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind::<Text, _>("users_max_users")
+        .execute(conn)?;
+
+    use crate::schema::users::dsl::*;
+
+    let active = users.filter(deleted_at.is_null());
+    let excess = active.count().get_result::<i64>(conn)? - cap.max_users;
+    if excess <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let oldest = active
+        .select(id)
+        .order((created_at.asc(), id.asc()))
+        .limit(excess)
+        .load::<i32>(conn)?;
+    let mut trimmed = match cap.trim {
+        UserTrim::Soft => diesel::update(users.filter(id.eq_any(&oldest)))
+            .set(deleted_at.eq(diesel::dsl::now))
+            .get_results::<User>(conn)?,
+        UserTrim::Hard => {
+            diesel::delete(users.filter(id.eq_any(&oldest))).get_results::<User>(conn)?
+        }
+    };
+    trimmed.sort_by_key(|user| user.id);
+    log::info!(
+        "trimmed {} oldest users ({:?}) to stay within MAX_USERS={}",
+        trimmed.len(),
+        cap.trim,
+        cap.max_users
+    );
+
+    Ok(trimmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::ChangeKind;
+    use crate::handler::Inserted;
+    use crate::testing;
+    use std::time::Duration;
+
+    #[test]
+    fn the_oldest_active_users_are_trimmed_and_returned() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let oldest = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let second = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let newest = testing::insert(&mut conn, "Alan", "Turing", "alan@example.com").unwrap();
+
+        let soft = UserCap {
+            max_users: 2,
+            trim: UserTrim::Soft,
+        };
+        let trimmed = trim(&mut conn, soft).unwrap();
+        assert_eq!(trimmed.iter().map(|user| user.id).collect::<Vec<_>>(), [oldest.id]);
+        assert!(trimmed[0].deleted_at.is_some());
+        assert!(trim(&mut conn, soft).unwrap().is_empty());
+
+        let hard = UserCap {
+            max_users: 1,
+            trim: UserTrim::Hard,
+        };
+        let trimmed = trim(&mut conn, hard).unwrap();
+        assert_eq!(trimmed.iter().map(|user| user.id).collect::<Vec<_>>(), [second.id]);
+        use crate::schema::users::dsl::*;
+        let left = users.select(id).order(id.asc()).load::<i32>(&mut conn).unwrap();
+        assert_eq!(left, [oldest.id, newest.id]);
+    }
+
+    #[test]
+    fn trimmed_users_are_published_as_deletes() {
+        let inserted = Inserted {
+            user: testing::user(3),
+            trimmed: vec![testing::user(1), testing::user(2)],
+        };
+        let events: Vec<_> = inserted.events().map(|event| (event.kind, event.user.id)).collect();
+        assert_eq!(
+            events,
+            [(ChangeKind::Upsert, 3), (ChangeKind::Delete, 1), (ChangeKind::Delete, 2)]
+        );
+    }
+}
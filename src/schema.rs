@@ -8,5 +8,13 @@ diesel::table! {
         last_name -> Varchar,
         email -> Varchar,
         created_at -> Timestamp,
+        updated_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        last_login -> Nullable<Timestamp>,
+        is_stale -> Bool,
+        email_normalized -> Varchar,
+        metadata -> Nullable<Jsonb>,
+        display_name -> Varchar,
+        created_by -> Nullable<Varchar>,
     }
 }
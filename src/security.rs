@@ -0,0 +1,137 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{DefaultHeaders, Next};
+use actix_web::{web, Error};
+
+use crate::{config::AppConfig, user_error::UserError};
+
+// Probed by the orchestrator directly rather than through the gateway
+const UNGUARDED_PATHS: &[&str] = &["/", "/healthz", "/readyz"];
+
+// API responses are JSON only, so nothing needs to be loaded or framed
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+const STRICT_TRANSPORT_SECURITY: &str = "max-age=31536000; includeSubDomains";
+
+// Hardening headers added to every response, each enabled separately
+pub fn security_headers(config: &AppConfig) -> DefaultHeaders {
+    let mut headers = DefaultHeaders::new();
+
+    if config.security_hsts {
+        headers = headers.add(("Strict-Transport-Security", STRICT_TRANSPORT_SECURITY));
+    }
+    if config.security_nosniff {
+        headers = headers.add(("X-Content-Type-Options", "nosniff"));
+    }
+    if config.security_frame_deny {
+        headers = headers.add(("X-Frame-Options", "DENY"));
+    }
+    if config.security_csp {
+        headers = headers.add(("Content-Security-Policy", CONTENT_SECURITY_POLICY));
+    }
+
+    headers
+}
+
+// Rejects requests missing any of REQUIRED_HEADERS with 400, for deployments
+// that must only be reached through a gateway adding them
+pub async fn require_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let missing = req
+        .app_data::<web::Data<AppConfig>>()
+        .filter(|_| !UNGUARDED_PATHS.contains(&req.path()))
+        .and_then(|config| {
+            config
+                .required_headers
+                .iter()
+                .find(|name| !req.headers().contains_key(name.as_str()))
+                .cloned()
+        });
+
+    match missing {
+        Some(name) => {
+            let e = UserError::BadRequest(format!("missing required header {}", name));
+            Ok(req.error_response(e).map_into_right_body())
+        }
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App, HttpResponse};
+
+    const HEADERS: &[&str] = &[
+        "Strict-Transport-Security",
+        "X-Content-Type-Options",
+        "X-Frame-Options",
+        "Content-Security-Policy",
+    ];
+
+    async fn response_headers(settings: &[(&str, &str)]) -> actix_web::http::header::HeaderMap {
+        let config = crate::testing::config(settings);
+        let app = test::init_service(
+            App::new()
+                .wrap(security_headers(&config))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        res.headers().clone()
+    }
+
+    #[actix_web::test]
+    async fn security_headers_are_sent_when_enabled() {
+        let headers = response_headers(&[
+            ("SECURITY_HSTS", "true"),
+            ("SECURITY_NOSNIFF", "true"),
+            ("SECURITY_FRAME_DENY", "true"),
+            ("SECURITY_CSP", "true"),
+        ])
+        .await;
+
+        assert_eq!(headers.get("Strict-Transport-Security").unwrap(), STRICT_TRANSPORT_SECURITY);
+        assert_eq!(headers.get("X-Content-Type-Options").unwrap(), "nosniff");
+        assert_eq!(headers.get("X-Frame-Options").unwrap(), "DENY");
+        assert_eq!(headers.get("Content-Security-Policy").unwrap(), CONTENT_SECURITY_POLICY);
+    }
+
+    #[actix_web::test]
+    async fn security_headers_are_off_by_default() {
+        let headers = response_headers(&[]).await;
+
+        assert!(HEADERS.iter().all(|name| !headers.contains_key(*name)));
+    }
+
+    #[actix_web::test]
+    async fn requests_missing_a_required_header_are_refused() {
+        let config = crate::testing::config(&[("REQUIRED_HEADERS", "X-Forwarded-For,X-Tenant-Id")]);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(actix_web::middleware::from_fn(require_headers))
+                .route("/users", web::get().to(HttpResponse::Ok))
+                .route("/healthz", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |uri: &str, headers: &[(&'static str, &'static str)]| {
+            let mut req = test::TestRequest::get().uri(uri);
+            for header in headers {
+                req = req.insert_header(*header);
+            }
+            test::call_service(&app, req.to_request())
+        };
+
+        let forwarded = ("X-Forwarded-For", "10.0.0.1");
+        let tenant = ("X-Tenant-Id", "acme");
+        let refused = call("/users", &[forwarded]).await;
+        assert_eq!(refused.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body = test::read_body(refused).await;
+        assert!(String::from_utf8_lossy(&body).contains("missing required header X-Tenant-Id"));
+
+        assert!(call("/users", &[forwarded, tenant]).await.status().is_success());
+        assert!(call("/healthz", &[]).await.status().is_success());
+    }
+}
@@ -0,0 +1,339 @@
+use std::future::Future;
+use std::time::Duration;
+
+use actix_rt::{Arbiter, ArbiterHandle};
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::coalesce::UpdateCoalescer;
+use crate::events::ChangeFeed;
+use crate::limiter::ReadShare;
+use crate::metrics::POOL_METRICS;
+use crate::{models, DbPool};
+
+// How often `spawn_pool_scaling` looks at the wait times
+const POOL_SCALING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Background work that shutdown waits for. It runs on its own arbiter, so
+// it outlives the HTTP workers, which are stopped first. Periodic tasks stop
+// at the next tick once `token` is cancelled; work already running is left
+// to finish.
+#[derive(Clone)]
+pub struct Background {
+    arbiter: ArbiterHandle,
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Background {
+    pub fn new() -> Background {
+        Background {
+            arbiter: Arbiter::new().handle(),
+            token: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        self.arbiter.spawn(self.tracker.track_future(task));
+    }
+
+    pub fn status(&self) -> models::BackgroundStatus {
+        models::BackgroundStatus {
+            tasks: self.tracker.len(),
+            shutting_down: self.token.is_cancelled(),
+        }
+    }
+
+    // Signals the tasks to stop and waits up to `grace` for them. Returns
+    // false if some were still running when the grace period ran out.
+    pub async fn shutdown(&self, grace: Duration) -> bool {
+        self.token.cancel();
+        self.tracker.close();
+        let drained = actix_rt::time::timeout(grace, self.tracker.wait()).await.is_ok();
+        self.arbiter.stop();
+        drained
+    }
+}
+
+// Reports the pools as too small when the p95 wait for a connection exceeds
+// `threshold`. `shared` is the pool serving every request, if there is one;
+// with a `cap` it is rebuilt at up to twice its size instead, until it reaches
+// the cap, and `read_share` is handed the new size. Per-worker pools are only
+// reported, at their configured `pool_size`.
+pub fn spawn_pool_scaling(
+    background: &Background,
+    shared: Option<DbPool>,
+    read_share: Option<ReadShare>,
+    pool_size: u32,
+    database_url: String,
+    threshold: Duration,
+    cap: Option<u32>,
+) {
+    let token = background.token.clone();
+
+    background.spawn(async move {
+        let mut interval = actix_rt::time::interval(POOL_SCALING_INTERVAL);
+        let mut last_acquired = 0;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = token.cancelled() => break,
+            }
+
+            // Nothing new to judge the pool by
+            let summary = POOL_METRICS.summary();
+            if summary.acquired == last_acquired {
+                continue;
+            }
+            last_acquired = summary.acquired;
+
+            let wait_p95 = Duration::from_secs_f64(summary.wait_p95_ms / 1000.0);
+            if wait_p95 <= threshold {
+                continue;
+            }
+
+            let size = shared.as_ref().map_or(pool_size, |pool| pool.max_size());
+            match (&shared, cap.filter(|cap| size < *cap)) {
+                (Some(pool), Some(cap)) => {
+                    let grown = (size * 2).min(cap);
+                    let (pool, database_url) = (pool.clone(), database_url.clone());
+                    match actix_web::web::block(move || pool.resize(&database_url, grown)).await {
+                        Ok(Ok(())) => {
+                            log::info!(
+                                "Pool wait p95 {:.1}ms over {:?}, grew the pool from {} to {}",
+                                summary.wait_p95_ms,
+                                threshold,
+                                size,
+                                grown
+                            );
+                            if let Some(read_share) = &read_share {
+                                read_share.resize(grown);
+                            }
+                            POOL_METRICS.reset_waits();
+                        }
+                        Ok(Err(e)) => log::error!("Growing the pool failed: {}", e),
+                        Err(e) => log::error!("Growing the pool failed: {}", e),
+                    }
+                }
+                _ => log::warn!(
+                    "Pool wait p95 {:.1}ms over {:?} with {} connections, consider raising POOL_SIZE{}",
+                    summary.wait_p95_ms,
+                    threshold,
+                    size,
+                    if cap.is_some() { " or POOL_MAX_SIZE_CAP" } else { "" }
+                ),
+            }
+        }
+    });
+}
+
+// Periodically flags users with no activity for `stale_days`. Activity is the
+// last login, or the creation time for users that never logged in. Users that
+// became active again are unflagged on the next run.
+pub fn spawn_stale_account_check(
+    background: &Background,
+    pool: DbPool,
+    stale_days: i64,
+    every: Duration,
+) {
+    let token = background.token.clone();
+
+    background.spawn(async move {
+        let mut interval = actix_rt::time::interval(every);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = token.cancelled() => break,
+            }
+
+            let pool = pool.clone();
+            match actix_web::web::block(move || flag_stale_accounts(&pool, stale_days)).await {
+                Ok(Ok(changed)) if changed > 0 => {
+                    log::info!("Stale account check updated {} users", changed)
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => log::error!("Stale account check failed: {}", e),
+                Err(e) => log::error!("Stale account check failed: {}", e),
+            }
+        }
+    });
+}
+
+// Keeps the update coalescer in step with the change feed. Falling behind
+// the feed forgets every response, since the missed changes are unknown.
+pub fn spawn_coalescer_invalidation(
+    background: &Background,
+    coalescer: UpdateCoalescer,
+    feed: &ChangeFeed,
+) {
+    let token = background.token.clone();
+    let mut events = feed.subscribe();
+
+    background.spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = token.cancelled() => break,
+            };
+
+            match event {
+                Ok(event) => coalescer.observe(&event),
+                Err(RecvError::Lagged(_)) => coalescer.clear(),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+// Periodically hard-deletes users soft-deleted more than `grace_days` ago,
+// ending the window in which they can be restored. Their tombstones go with
+// them, so `/users/changes` clients syncing less often than the grace period
+// miss those deletions.
+pub fn spawn_soft_delete_purge(
+    background: &Background,
+    pool: DbPool,
+    grace_days: i64,
+    every: Duration,
+) {
+    let token = background.token.clone();
+
+    background.spawn(async move {
+        let mut interval = actix_rt::time::interval(every);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = token.cancelled() => break,
+            }
+
+            let pool = pool.clone();
+            match actix_web::web::block(move || purge_soft_deleted(&pool, grace_days)).await {
+                Ok(Ok(purged)) if purged > 0 => log::info!(
+                    "Purged {} users soft-deleted more than {} days ago",
+                    purged,
+                    grace_days
+                ),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => log::error!("Soft delete purge failed: {}", e),
+                Err(e) => log::error!("Soft delete purge failed: {}", e),
+            }
+        }
+    });
+}
+
+fn purge_soft_deleted(pool: &DbPool, grace_days: i64) -> Result<usize, String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+
+    use crate::schema::users::dsl::*;
+    use diesel::dsl::{now, IntervalDsl};
+
+    // Against the database clock, the one deleted_at was set with
+    diesel::delete(users.filter(deleted_at.le((now - grace_days.days()).nullable())))
+        .execute(&mut conn)
+        .map_err(|e| e.to_string())
+}
+
+fn flag_stale_accounts(pool: &DbPool, stale_days: i64) -> Result<usize, String> {
+    let mut conn = pool.get().map_err(|e| e.to_string())?;
+    let stale_days = i32::try_from(stale_days).map_err(|e| e.to_string())?;
+
+    // Against the database clock, the one last_login and created_at were set with
+    diesel::sql_query(
+        "UPDATE users SET is_stale = \
+         (COALESCE(last_login, created_at) < now() - make_interval(days => $1)) \
+         WHERE deleted_at IS NULL \
+         AND is_stale IS DISTINCT FROM \
+         (COALESCE(last_login, created_at) < now() - make_interval(days => $1))",
+    )
+    .bind::<Integer, _>(stale_days)
+    .execute(&mut conn)
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn delete_days_ago(conn: &mut PgConnection, user_id: i32, days: i32) {
+        let query = "UPDATE users SET deleted_at = now() - make_interval(days => $2) WHERE id = $1";
+        diesel::sql_query(query)
+            .bind::<diesel::sql_types::Integer, _>(user_id)
+            .bind::<diesel::sql_types::Integer, _>(days)
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn soft_deleted_users_are_restorable_until_purged() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let recent = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let old = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        let gone = testing::insert(&mut conn, "Alan", "Turing", "alan@example.com").unwrap();
+        delete_days_ago(&mut conn, recent.id, 0);
+        delete_days_ago(&mut conn, old.id, 3);
+        delete_days_ago(&mut conn, gone.id, 3);
+
+        assert_eq!(purge_soft_deleted(&pool, 7), Ok(0));
+        assert_eq!(purge_soft_deleted(&pool, 2), Ok(2));
+
+        let config = testing::config(&[]);
+        let restore = |user_id: uuid::Uuid| {
+            TestRequest::post().uri(&format!("/users/{}/restore", user_id))
+        };
+        let response = testing::call(&pool, &config, restore(recent.user_id)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = testing::call(&pool, &config, restore(old.user_id)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        delete_days_ago(&mut conn, recent.id, 0);
+        assert_eq!(purge_soft_deleted(&pool, 0), Ok(1));
+        let left = crate::schema::users::table.count().get_result::<i64>(&mut conn).unwrap();
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn stale_accounts_are_flagged_and_cleared() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let idle = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let active = testing::insert(&mut conn, "Grace", "Hopper", "grace@example.com").unwrap();
+        diesel::sql_query("UPDATE users SET created_at = now() - interval '10 days' WHERE id = $1")
+            .bind::<Integer, _>(idle.id)
+            .execute(&mut conn)
+            .unwrap();
+        let stale = |conn: &mut PgConnection, user: i32| -> bool {
+            use crate::schema::users::dsl::*;
+            users.filter(id.eq(user)).select(is_stale).first(conn).unwrap()
+        };
+        drop(conn);
+
+        assert_eq!(flag_stale_accounts(&pool, 5), Ok(1));
+        assert_eq!(flag_stale_accounts(&pool, 5), Ok(0));
+        let mut conn = pool.get().unwrap();
+        assert!(stale(&mut conn, idle.id));
+        assert!(!stale(&mut conn, active.id));
+
+        diesel::sql_query("UPDATE users SET last_login = now() WHERE id = $1")
+            .bind::<Integer, _>(idle.id)
+            .execute(&mut conn)
+            .unwrap();
+        drop(conn);
+        assert_eq!(flag_stale_accounts(&pool, 5), Ok(1));
+        assert!(!stale(&mut pool.get().unwrap(), idle.id));
+    }
+}
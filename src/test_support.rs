@@ -0,0 +1,115 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use diesel::prelude::*;
+
+use crate::{
+    admin::require_admin, config::AppConfig, handler::get_conn_from_db, handler::insert_user,
+    models, user_error::UserError, validation, DbPool,
+};
+
+// Empties the users table and loads the users in the body, if any, as the
+// fixture set. For integration tests against a running instance only: the
+// route is mounted with TEST_MODE=true, which release builds refuse, and it
+// still needs the admin key. Ids restart at 1 so fixtures get known ids.
+pub async fn reset(
+    req: HttpRequest,
+    config: web::Data<AppConfig>,
+    pool: web::Data<DbPool>,
+    body: web::Bytes,
+) -> Result<HttpResponse, UserError> {
+    require_admin(&req, &config)?;
+    if !config.test_mode {
+        return Err(UserError::Forbidden);
+    }
+
+    let fixtures = if body.iter().all(u8::is_ascii_whitespace) {
+        Vec::new()
+    } else {
+        serde_json::from_slice::<Vec<models::NewUser>>(&body)
+            .map_err(|e| UserError::BadRequest(format!("fixtures must be a list of users: {}", e)))?
+            .into_iter()
+            .map(validation::validate_new_user)
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let rules = config.uniqueness();
+
+    let reset_result = web::block(move || {
+        let mut conn = get_conn_from_db(pool)?;
+
+        conn.transaction(|conn| {
+            diesel::sql_query("TRUNCATE users RESTART IDENTITY").execute(conn)?;
+            let inserted = fixtures
+                .into_iter()
+                .map(|fixture| insert_user(conn, rules, fixture, None))
+                .collect::<Result<Vec<_>, UserError>>()?;
+            // Fixtures over MAX_USERS were trimmed by later ones
+            let trimmed: Vec<i32> = inserted
+                .iter()
+                .flat_map(|inserted| inserted.trimmed.iter().map(|user| user.id))
+                .collect();
+            Ok(inserted
+                .into_iter()
+                .map(|inserted| inserted.user)
+                .filter(|user| !trimmed.contains(&user.id))
+                .collect::<Vec<_>>())
+        })
+    })
+    .await
+    .map_err(UserError::from)?;
+
+    log::warn!("TEST_MODE reset of the users table");
+
+    match reset_result {
+        Ok(loaded) => Ok(HttpResponse::Ok().json(models::GenericResponse {
+            status: "OK".to_string(),
+            message: "Users reset".to_string(),
+            data: Some(loaded),
+            warnings: Vec::new(),
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn reset_loads_the_fixtures_with_known_ids() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        let config = testing::config(&[("TEST_MODE", "true"), ("ADMIN_KEY", "s3cret")]);
+        let fixtures = serde_json::json!([
+            {"first_name": "Grace", "last_name": "Hopper", "email": "grace@example.com"},
+            {"first_name": "Alan", "last_name": "Turing", "email": "alan@example.com"},
+        ]);
+        let reset = || TestRequest::post().uri("/test/reset").set_json(fixtures.clone());
+
+        let response = testing::call(&pool, &config, reset()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = reset().insert_header(("X-Admin-Key", "s3cret"));
+        let (status, _) = testing::json(testing::call(&pool, &config, request).await).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let listing = TestRequest::get().uri("/get");
+        let (_, body) = testing::json(testing::call(&pool, &config, listing).await).await;
+        let users: Vec<_> = body["data"]["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| (user["id"].as_i64().unwrap(), user["email"].as_str().unwrap().to_string()))
+            .collect();
+        assert_eq!(
+            users,
+            [(1, "grace@example.com".to_string()), (2, "alan@example.com".to_string())]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use actix_web::body::to_bytes;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{test, App};
+use diesel::prelude::*;
+
+use crate::coalesce::UpdateCoalescer;
+use crate::{config::AppConfig, models, DbPool};
+
+// Tests that need Postgres run against TEST_DATABASE_URL, a migrated
+// database whose users table they may empty. Without it they are skipped.
+pub fn database_url() -> Option<String> {
+    let url = std::env::var("TEST_DATABASE_URL").ok();
+    if url.is_none() {
+        eprintln!("TEST_DATABASE_URL is not set, skipping");
+    }
+    url
+}
+
+pub fn pool(max_size: u32, get_timeout: Duration) -> Option<DbPool> {
+    let url = database_url()?;
+    Some(DbPool::build(&url, max_size, None, get_timeout).expect("test database"))
+}
+
+// A config from the given settings and the defaults for the rest
+pub fn config(settings: &[(&str, &str)]) -> AppConfig {
+    let mut settings = settings.to_vec();
+    settings.push(("DATABASE_URL", "postgres://localhost/test"));
+    AppConfig::from_settings(&settings).expect("valid test config")
+}
+
+// Sends `req` through the app as main builds it, with the config's middleware
+// and routes. The optional app data, like the coalescer, is left out.
+pub async fn call(pool: &DbPool, config: &AppConfig, req: test::TestRequest) -> ServiceResponse {
+    call_with(pool, config, None, req).await
+}
+
+// `call` with the given coalescer, which keeps its responses across calls
+pub async fn call_with(
+    pool: &DbPool,
+    config: &AppConfig,
+    coalescer: Option<&UpdateCoalescer>,
+    req: test::TestRequest,
+) -> ServiceResponse {
+    let mut app = App::new()
+        .app_data(Data::new(pool.clone()))
+        .app_data(Data::new(config.clone()))
+        .app_data(Data::new(crate::readiness::Readiness::default()))
+        .app_data(Data::new(crate::events::ChangeFeed::default()))
+        .app_data(Data::new(crate::jobs::JobStore::default()))
+        .app_data(Data::new(crate::tasks::Background::new()))
+        .app_data(Data::new(crate::access_log::RecentProblems::new(None)))
+        .app_data(Data::new(crate::mx::MxCache::new(Duration::from_secs(60))))
+        .app_data(Data::new(crate::metrics::UserMetricsCache::default()));
+    if let Some(coalescer) = coalescer {
+        app = app.app_data(Data::new(coalescer.clone()));
+    }
+    let app = test::init_service(
+        crate::with_middleware(app, config).configure(|cfg| crate::configure_routes(cfg, config)),
+    )
+    .await;
+    test::call_service(&app, req.to_request()).await.map_into_boxed_body()
+}
+
+// The status and JSON body of a response
+pub async fn json(response: ServiceResponse) -> (StatusCode, serde_json::Value) {
+    let status = response.status();
+    let body = to_bytes(response.into_body()).await.unwrap_or_default();
+    (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+}
+
+// Empties the users table, ids restart at 1
+pub fn reset(conn: &mut PgConnection) {
+    diesel::sql_query("TRUNCATE users RESTART IDENTITY")
+        .execute(conn)
+        .expect("truncate users");
+}
+
+// Stores a user as is, without the checks of `handler::insert_user`
+pub fn insert(
+    conn: &mut PgConnection,
+    first_name: &str,
+    last_name: &str,
+    email: &str,
+) -> QueryResult<models::User> {
+    diesel::insert_into(crate::schema::users::table)
+        .values(&models::Users {
+            id: None,
+            user_id: uuid::Uuid::new_v4(),
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            email: email.to_string(),
+            created_by: None,
+        })
+        .get_result(conn)
+}
+
+// Serializes tests that share the database or the process-wide statics,
+// like POOL_METRICS and the response format settings. Async tests can hold it
+// across awaits: each runs on a runtime of its own, so nothing else waits on
+// their thread.
+pub fn lock() -> MutexGuard<'static, ()> {
+    static SHARED: Mutex<()> = Mutex::new(());
+    SHARED.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// An active user with no optional fields set, `id` also picks the user_id
+pub fn user(id: i32) -> models::User {
+    let at = chrono::NaiveDate::from_ymd_opt(2026, 1, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    models::User {
+        id,
+        user_id: uuid::Uuid::from_u128(id as u128),
+        first_name: "Ada".to_string(),
+        last_name: "Lovelace".to_string(),
+        email: format!("ada{}@example.com", id),
+        created_at: at,
+        updated_at: at,
+        deleted_at: None,
+        last_login: None,
+        is_stale: false,
+        email_normalized: format!("ada{}@example.com", id),
+        metadata: None,
+        display_name: "Ada Lovelace".to_string(),
+        created_by: None,
+    }
+}
@@ -0,0 +1,321 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::result::Error as DieselError;
+use diesel::QueryResult;
+
+use crate::events::{ChangeEvent, ChangeFeed};
+use crate::user_error::{sqlstate, UserError};
+use crate::{handler::get_conn_from_db, DbPool};
+
+type PooledPg = PooledConnection<ConnectionManager<PgConnection>>;
+type CommitHook = Box<dyn FnOnce() + Send>;
+
+// A connection with a transaction opened for the current request. The
+// transaction is begun when a handler first extracts a `TxConn` and ended by
+// the `finish_transaction` middleware: committed if the response is 2xx,
+// rolled back otherwise.
+#[derive(Clone)]
+pub struct TxConn {
+    conn: Arc<Mutex<Option<PooledPg>>>,
+    // Published to the change feed if the transaction commits
+    events: Arc<Mutex<Vec<ChangeEvent>>>,
+    // Run if the transaction commits, after the events are published
+    hooks: Arc<Mutex<Vec<CommitHook>>>,
+}
+
+impl TxConn {
+    pub fn publish_on_commit(&self, event: ChangeEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    pub fn on_commit(&self, hook: impl FnOnce() + Send + 'static) {
+        self.hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    // Runs `f` on the transaction's connection in the blocking thread pool
+    pub async fn run<F, R>(&self, f: F) -> Result<R, BlockingError>
+    where
+        F: FnOnce(&mut PgConnection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self.conn.clone();
+
+        web::block(move || {
+            let mut conn = conn.lock().unwrap();
+            let conn = conn.as_mut().expect("Transaction already finished");
+            f(conn)
+        })
+        .await
+    }
+
+    // Like `run`, but when `f` fails with a serialization failure or deadlock
+    // the transaction is rolled back, a new one begun and `f` run again, up to
+    // `retries` times. `f` must hold all the work of the transaction, since
+    // anything done before it is rolled back with it.
+    pub async fn run_retrying<F, R>(
+        &self,
+        retries: u32,
+        f: F,
+    ) -> Result<Result<R, UserError>, BlockingError>
+    where
+        F: Fn(&mut PgConnection) -> Result<R, UserError> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run(move |conn| {
+            retry_on_conflict(retries, || {
+                let result = f(conn);
+                if matches!(&result, Err(e) if is_retryable(e)) {
+                    AnsiTransactionManager::rollback_transaction(conn)?;
+                    AnsiTransactionManager::begin_transaction(conn)?;
+                }
+                result
+            })
+        })
+        .await
+    }
+
+    // Commits or rolls back, and returns the connection to the pool
+    async fn finish(&self, commit: bool) -> Result<QueryResult<()>, BlockingError> {
+        let conn = self.conn.clone();
+
+        web::block(move || {
+            let mut conn = match conn.lock().unwrap().take() {
+                Some(conn) => conn,
+                None => return Ok(()),
+            };
+
+            if commit {
+                AnsiTransactionManager::commit_transaction(&mut *conn)
+            } else {
+                AnsiTransactionManager::rollback_transaction(&mut *conn)
+            }
+        })
+        .await
+    }
+}
+
+impl FromRequest for TxConn {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            if let Some(tx) = req.extensions().get::<TxConn>() {
+                return Ok(tx.clone());
+            }
+
+            let pool = req
+                .app_data::<web::Data<DbPool>>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorInternalServerError("No database pool"))?;
+
+            let conn = web::block(move || {
+                let mut conn = get_conn_from_db(pool)?;
+                AnsiTransactionManager::begin_transaction(&mut *conn)
+                    .map(|_| conn)
+                    .map_err(UserError::from)
+            })
+            .await
+            .map_err(UserError::from)??;
+
+            let tx = TxConn {
+                conn: Arc::new(Mutex::new(Some(conn))),
+                events: Arc::new(Mutex::new(Vec::new())),
+                hooks: Arc::new(Mutex::new(Vec::new())),
+            };
+            req.extensions_mut().insert(tx.clone());
+            Ok(tx)
+        })
+    }
+}
+
+// Ends the transaction of a request that extracted a `TxConn`. If the
+// middleware isn't mounted the connection is dropped mid-transaction, which
+// rolls back and gets it discarded by the pool.
+pub async fn finish_transaction(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let res = next.call(req).await?;
+
+    let tx = res.request().extensions().get::<TxConn>().cloned();
+    if let Some(tx) = tx {
+        let commit = res.status().is_success();
+        tx.finish(commit)
+            .await
+            .map_err(UserError::from)?
+            .map_err(UserError::DieselError)?;
+
+        let events = std::mem::take(&mut *tx.events.lock().unwrap());
+        if let (true, Some(feed)) = (commit, res.request().app_data::<web::Data<ChangeFeed>>()) {
+            events.into_iter().for_each(|event| feed.publish(event));
+        }
+        let hooks = std::mem::take(&mut *tx.hooks.lock().unwrap());
+        if commit {
+            hooks.into_iter().for_each(|hook| hook());
+        }
+    }
+
+    Ok(res)
+}
+
+// Runs `f` in a transaction that is always rolled back, for dry runs
+pub fn rolled_back<R>(
+    conn: &mut PgConnection,
+    f: impl FnOnce(&mut PgConnection) -> Result<R, UserError>,
+) -> Result<R, UserError> {
+    AnsiTransactionManager::begin_transaction(conn)?;
+    let result = f(conn);
+    AnsiTransactionManager::rollback_transaction(conn)?;
+    result
+}
+
+// Serialization failures (SQLSTATE 40001) and deadlocks (40P01), as
+// `sqlstate` tells them apart: the first by diesel's error kind, the second
+// by Postgres' English message
+fn is_retryable(e: &UserError) -> bool {
+    match e {
+        UserError::DieselError(DieselError::DatabaseError(kind, info)) => {
+            matches!(sqlstate(kind, info.as_ref()), Some("40001" | "40P01"))
+        }
+        UserError::BatchOperation(_, e) => is_retryable(e),
+        _ => false,
+    }
+}
+
+// 20ms doubling per retry, capped at 640ms
+fn backoff(retry: u32) -> Duration {
+    Duration::from_millis(10u64 << retry.min(6))
+}
+
+// Runs `attempt` again after a retryable failure, with a short exponential
+// backoff. Once the retries are used up the failure becomes a 409.
+pub fn retry_on_conflict<R>(
+    retries: u32,
+    mut attempt: impl FnMut() -> Result<R, UserError>,
+) -> Result<R, UserError> {
+    let mut retry = 0;
+
+    loop {
+        match attempt() {
+            Err(e) if is_retryable(&e) => {
+                if retry >= retries {
+                    return Err(UserError::Conflict(
+                        "The change conflicted with a concurrent one, try again".to_string(),
+                    ));
+                }
+                retry += 1;
+                log::warn!("retrying conflicted transaction ({}/{}): {}", retry, retries, e);
+                std::thread::sleep(backoff(retry));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::result::DatabaseErrorKind;
+
+    fn database_error(kind: DatabaseErrorKind, message: &str) -> UserError {
+        UserError::DieselError(DieselError::DatabaseError(kind, Box::new(message.to_string())))
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff(1), Duration::from_millis(20));
+        assert_eq!(backoff(6), Duration::from_millis(640));
+        assert_eq!(backoff(u32::MAX), Duration::from_millis(640));
+    }
+
+    #[test]
+    fn serialization_failures_and_deadlocks_are_retried() {
+        let deadlock = || database_error(DatabaseErrorKind::Unknown, "deadlock detected");
+        let serialization = || {
+            database_error(DatabaseErrorKind::SerializationFailure, "could not serialize access")
+        };
+        let mut failures = vec![deadlock(), serialization()];
+
+        let result = retry_on_conflict(2, || match failures.pop() {
+            Some(e) => Err(e),
+            None => Ok("done"),
+        });
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[test]
+    fn exhausted_retries_are_a_conflict() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_on_conflict(1, || {
+            attempts += 1;
+            Err(database_error(DatabaseErrorKind::Unknown, "deadlock detected"))
+        });
+
+        assert!(matches!(result, Err(UserError::Conflict(_))));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_on_conflict(3, || {
+            attempts += 1;
+            Err(database_error(DatabaseErrorKind::Unknown, "relation \"users\" is locked"))
+        });
+
+        assert!(matches!(result, Err(UserError::DieselError(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn a_real_deadlock_is_retryable() {
+        use diesel::prelude::*;
+
+        let _shared = crate::testing::lock();
+        let Some(pool) = crate::testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut first = pool.get().unwrap();
+        let mut second = pool.get().unwrap();
+        crate::testing::reset(&mut first);
+        crate::testing::insert(&mut first, "Ada", "Lovelace", "ada@example.com").unwrap();
+        crate::testing::insert(&mut first, "Grace", "Hopper", "grace@example.com").unwrap();
+
+        let lock = |conn: &mut PgConnection, id: i32| {
+            diesel::sql_query(format!("SELECT 1 FROM users WHERE id = {} FOR UPDATE", id))
+                .execute(conn)
+        };
+        AnsiTransactionManager::begin_transaction(&mut *first).unwrap();
+        AnsiTransactionManager::begin_transaction(&mut *second).unwrap();
+        lock(&mut first, 1).unwrap();
+        lock(&mut second, 2).unwrap();
+
+        // Each waits for the other's row, one of them is picked to fail
+        let blocked = std::thread::spawn(move || {
+            let result = lock(&mut first, 2);
+            AnsiTransactionManager::rollback_transaction(&mut *first).unwrap();
+            result
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        let result = lock(&mut second, 1);
+        AnsiTransactionManager::rollback_transaction(&mut *second).unwrap();
+
+        let failed = match (result, blocked.join().unwrap()) {
+            (Err(e), _) | (_, Err(e)) => UserError::from(e),
+            _ => panic!("no deadlock was detected"),
+        };
+        assert!(is_retryable(&failed), "{}", failed);
+    }
+}
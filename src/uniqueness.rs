@@ -0,0 +1,388 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use diesel::dsl::exists;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Text};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{models, user_error::UserError};
+
+// What makes two users duplicates, chosen with UNIQUENESS_POLICY
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UniquenessPolicy {
+    // The email is unique across all rows, soft-deleted ones included
+    Email,
+    // The email is unique among active rows, deleted users free their email
+    EmailActive,
+    // The (first_name, last_name, email) tuple is unique among active rows
+    NameEmail,
+}
+
+impl FromStr for UniquenessPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "email" => Ok(UniquenessPolicy::Email),
+            "email_active" => Ok(UniquenessPolicy::EmailActive),
+            "name_email" => Ok(UniquenessPolicy::NameEmail),
+            _ => Err("expected one of email, email_active, name_email".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for UniquenessPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UniquenessPolicy::Email => write!(f, "email"),
+            UniquenessPolicy::EmailActive => write!(f, "email_active"),
+            UniquenessPolicy::NameEmail => write!(f, "name_email"),
+        }
+    }
+}
+
+// The policy plus how emails are compared, from UNIQUENESS_POLICY and
+// EMAIL_CASE_INSENSITIVE
+#[derive(Debug, Clone, Copy)]
+pub struct UniquenessRules {
+    pub policy: UniquenessPolicy,
+    // Compare the generated lowercase `email_normalized` column instead of
+    // `email`, which keeps the casing the user typed
+    pub case_insensitive_email: bool,
+}
+
+// The unique indexes enforcing the policy in the database, so duplicates
+// are refused even from writers that don't go through `ensure_unique`. One of
+// them exists at a time, see `ensure_index`.
+pub const EMAIL_INDEX: &str = "users_email_key";
+pub const NAME_EMAIL_INDEX: &str = "users_name_email_key";
+
+impl UniquenessRules {
+    // What follows ON CONFLICT in an insert yielding to the index of these
+    // rules: its columns and, for partial indexes, its predicate
+    pub fn conflict_target(&self) -> String {
+        self.index().1
+    }
+
+    // The name of the index for these rules and what follows `ON users`
+    fn index(&self) -> (&'static str, String) {
+        let email_column = match self.case_insensitive_email {
+            true => "email_normalized",
+            false => "email",
+        };
+        match self.policy {
+            UniquenessPolicy::Email => (EMAIL_INDEX, format!("({})", email_column)),
+            UniquenessPolicy::EmailActive => (
+                EMAIL_INDEX,
+                format!("({}) WHERE deleted_at IS NULL", email_column),
+            ),
+            UniquenessPolicy::NameEmail => (
+                NAME_EMAIL_INDEX,
+                format!("(first_name, last_name, {}) WHERE deleted_at IS NULL", email_column),
+            ),
+        }
+    }
+}
+
+// The conflict reported for a violation of one of the indexes above
+pub fn index_conflict(constraint: &str) -> Option<UserError> {
+    let key = match constraint {
+        EMAIL_INDEX => "email",
+        NAME_EMAIL_INDEX => "name and email",
+        _ => return None,
+    };
+    Some(UserError::Conflict(format!("A user with this {} already exists", key)))
+}
+
+// The conflict `ensure_unique` reports under `rules`: the one of their index,
+// or a generic one for rules without an index of their own
+pub fn rules_conflict(rules: UniquenessRules) -> UserError {
+    index_conflict(rules.index().0).unwrap_or_else(|| {
+        UserError::Conflict("A user with these values already exists".to_string())
+    })
+}
+
+#[derive(QueryableByName)]
+struct IndexComment {
+    #[diesel(sql_type = Nullable<Text>)]
+    description: Option<String>,
+}
+
+// Creates the unique index for `rules` and drops the one of the other
+// policies. The definition is kept as the index comment, so an index that
+// already matches is left alone. Fails with a unique violation if existing
+// users break the policy, and then changes nothing.
+pub fn ensure_index(conn: &mut PgConnection, rules: UniquenessRules) -> QueryResult<()> {
+    let (name, definition) = rules.index();
+
+    conn.transaction(|conn| {
+        // Instances starting at the same time would otherwise race on the DDL
+        diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext('users_uniqueness_index'))")
+            .execute(conn)?;
+
+        for other in [EMAIL_INDEX, NAME_EMAIL_INDEX].into_iter().filter(|other| *other != name) {
+            diesel::sql_query(format!("DROP INDEX IF EXISTS {}", other)).execute(conn)?;
+        }
+
+        let current = diesel::sql_query(
+            "SELECT obj_description(to_regclass($1), 'pg_class') AS description",
+        )
+        .bind::<Text, _>(name)
+        .get_result::<IndexComment>(conn)?;
+        if current.description.as_deref() == Some(definition.as_str()) {
+            return Ok(());
+        }
+
+        diesel::sql_query(format!("DROP INDEX IF EXISTS {}", name)).execute(conn)?;
+        diesel::sql_query(format!("CREATE UNIQUE INDEX {} ON users {}", name, definition))
+            .execute(conn)?;
+        diesel::sql_query(format!("COMMENT ON INDEX {} IS '{}'", name, definition)).execute(conn)?;
+        log::info!("created unique index {} ON users {}", name, definition);
+        Ok(())
+    })
+}
+
+diesel::define_sql_function! {
+    fn lower(value: Text) -> Text;
+}
+
+// Fails with a conflict if storing these values would duplicate another user
+// under `rules`. `exclude` is the user being updated, if any.
+//
+// Must run inside a transaction. It takes a transaction scoped advisory lock
+// on the email so concurrent writers of the same email are serialized until
+// the caller commits its insert or update. The unique index of `ensure_index`
+// backs this up, its violations become the same conflict.
+pub fn ensure_unique(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    new_first_name: &str,
+    new_last_name: &str,
+    new_email: &str,
+    exclude: Option<Uuid>,
+) -> Result<(), UserError> {
+    diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext(lower($1)))")
+        .bind::<Text, _>(new_email)
+        .execute(conn)?;
+
+    use crate::schema::users::dsl::*;
+
+    let mut duplicates = duplicates_of(rules, new_first_name, new_last_name, new_email);
+    if let Some(excluded) = exclude {
+        duplicates = duplicates.filter(user_id.ne(excluded));
+    }
+
+    if diesel::select(exists(duplicates)).get_result::<bool>(conn)? {
+        return Err(rules_conflict(rules));
+    }
+
+    Ok(())
+}
+
+// The user these values would duplicate under `rules`, the one a conflict
+// from `ensure_unique` is about. An active user is preferred to a deleted one,
+// which only counts under UNIQUENESS_POLICY=email.
+pub fn find_duplicate(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    new_first_name: &str,
+    new_last_name: &str,
+    new_email: &str,
+) -> QueryResult<Option<models::User>> {
+    use crate::schema::users::dsl::*;
+
+    duplicates_of(rules, new_first_name, new_last_name, new_email)
+        .order((deleted_at.asc().nulls_first(), id.asc()))
+        .first::<models::User>(conn)
+        .optional()
+}
+
+fn duplicates_of(
+    rules: UniquenessRules,
+    new_first_name: &str,
+    new_last_name: &str,
+    new_email: &str,
+) -> crate::schema::users::BoxedQuery<'static, Pg> {
+    use crate::schema::users::dsl::*;
+
+    let duplicates = if rules.case_insensitive_email {
+        users
+            .into_boxed()
+            .filter(email_normalized.eq(lower(new_email.to_string())))
+    } else {
+        users.into_boxed().filter(email.eq(new_email.to_string()))
+    };
+
+    match rules.policy {
+        UniquenessPolicy::Email => duplicates,
+        UniquenessPolicy::EmailActive => duplicates.filter(deleted_at.is_null()),
+        UniquenessPolicy::NameEmail => duplicates
+            .filter(deleted_at.is_null())
+            .filter(first_name.eq(new_first_name.to_string()))
+            .filter(last_name.eq(new_last_name.to_string())),
+    }
+}
+
+// The emails of `new_users` (first name, last name, email) that would
+// conflict under `rules`, with another entry of `new_users` or with an
+// existing user. Checks all of them with one query, for reporting every
+// conflict up front instead of failing on the first in `ensure_unique`.
+pub fn find_conflicting_emails(
+    conn: &mut PgConnection,
+    rules: UniquenessRules,
+    new_users: &[(&str, &str, &str)],
+) -> QueryResult<Vec<String>> {
+    let key = |first: &str, last: &str, address: &str| {
+        let address = if rules.case_insensitive_email {
+            address.to_lowercase()
+        } else {
+            address.to_string()
+        };
+        match rules.policy {
+            UniquenessPolicy::NameEmail => (address, Some((first.to_string(), last.to_string()))),
+            _ => (address, None),
+        }
+    };
+
+    let mut conflicting: Vec<String> = Vec::new();
+    let mut report = |address: &str| {
+        if !conflicting.iter().any(|reported| reported == address) {
+            conflicting.push(address.to_string());
+        }
+    };
+
+    let mut seen = HashSet::new();
+    for (first, last, address) in new_users {
+        if !seen.insert(key(first, last, address)) {
+            report(address);
+        }
+    }
+
+    use crate::schema::users::dsl::*;
+
+    let addresses: HashSet<String> = seen.iter().map(|(address, _)| address.clone()).collect();
+    let mut existing = users.select((first_name, last_name, email)).into_boxed();
+    existing = if rules.case_insensitive_email {
+        existing.filter(email_normalized.eq_any(addresses))
+    } else {
+        existing.filter(email.eq_any(addresses))
+    };
+    if rules.policy != UniquenessPolicy::Email {
+        existing = existing.filter(deleted_at.is_null());
+    }
+
+    let existing: HashSet<_> = existing
+        .load::<(String, String, String)>(conn)?
+        .iter()
+        .map(|(first, last, address)| key(first, last, address))
+        .collect();
+    for (first, last, address) in new_users {
+        if existing.contains(&key(first, last, address)) {
+            report(address);
+        }
+    }
+
+    Ok(conflicting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::time::Duration;
+
+    fn conflict(result: QueryResult<crate::models::User>) -> String {
+        match result.map_err(UserError::from) {
+            Err(UserError::Conflict(message)) => message,
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_policy_reports_the_conflict_of_its_index() {
+        let message = |policy| match rules_conflict(UniquenessRules {
+            policy,
+            case_insensitive_email: false,
+        }) {
+            UserError::Conflict(message) => message,
+            other => panic!("expected a conflict, got {:?}", other),
+        };
+
+        assert_eq!(message(UniquenessPolicy::Email), "A user with this email already exists");
+        assert_eq!(message(UniquenessPolicy::EmailActive), "A user with this email already exists");
+        assert_eq!(
+            message(UniquenessPolicy::NameEmail),
+            "A user with this name and email already exists"
+        );
+        assert!(index_conflict("users_user_id_key").is_none());
+    }
+
+    #[test]
+    fn email_active_index_refuses_active_duplicates() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::EmailActive,
+            case_insensitive_email: true,
+        };
+        ensure_index(&mut conn, rules).unwrap();
+
+        let first = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let message = conflict(testing::insert(&mut conn, "Grace", "Hopper", "ADA@example.com"));
+        assert_eq!(message, "A user with this email already exists");
+
+        diesel::sql_query("UPDATE users SET deleted_at = now() WHERE id = $1")
+            .bind::<diesel::sql_types::Integer, _>(first.id)
+            .execute(&mut conn)
+            .unwrap();
+        testing::insert(&mut conn, "Grace", "Hopper", "ada@example.com").unwrap();
+    }
+
+    #[test]
+    fn name_email_index_refuses_the_same_name_and_email() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let rules = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        ensure_index(&mut conn, rules).unwrap();
+
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        testing::insert(&mut conn, "Ada", "King", "ada@example.com").unwrap();
+        let message = conflict(testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com"));
+        assert_eq!(message, "A user with this name and email already exists");
+    }
+
+    #[test]
+    fn ensure_index_fails_on_existing_duplicates() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(1, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let lenient = UniquenessRules {
+            policy: UniquenessPolicy::NameEmail,
+            case_insensitive_email: false,
+        };
+        ensure_index(&mut conn, lenient).unwrap();
+        testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        testing::insert(&mut conn, "Ada", "King", "ada@example.com").unwrap();
+
+        let strict = UniquenessRules {
+            policy: UniquenessPolicy::Email,
+            case_insensitive_email: false,
+        };
+        assert!(ensure_index(&mut conn, strict).is_err());
+        // The failed switch leaves the previous index in place
+        assert!(testing::insert(&mut conn, "Ada", "King", "ada@example.com").is_err());
+    }
+}
@@ -1,13 +1,106 @@
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use actix_web::error::BlockingError;
+use actix_web::http::{header, StatusCode};
 use actix_web::{HttpResponse, ResponseError};
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError};
+use serde::Serialize;
+
+use crate::config::ErrorEnvelope;
+
+// Set once at startup from VERBOSE_ERRORS. When on, error bodies carry the
+// internal error details next to the message; they must stay off in
+// production since they can leak queries and schema.
+pub static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Set once at startup from ERROR_ENVELOPE, generic until then
+pub static ERROR_ENVELOPE: OnceLock<ErrorEnvelope> = OnceLock::new();
+
+// RFC 7807 problem details. There are no problem type URIs, so `type` is
+// always about:blank and `title` the status reason.
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    // Extension member, see `UserError::code`
+    code: &'static str,
+    // The request path, filled in by `i18n::localize_errors`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    // Extension member, see `GenericError::errors`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Failure>,
+    // Extension members with VERBOSE_ERRORS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chain: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct GenericError {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Failure>,
+}
+
+// One entry of `errors`, for `UserError::ValidationMany`
+#[derive(Serialize)]
+struct Failure {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct VerboseError {
+    code: &'static str,
+    message: String,
+    debug: String,
+    // `source()` chain of the underlying error, outermost first
+    chain: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Failure>,
+}
+
+// The state of the limiter that refused a request, sent back as Retry-After
+// and X-RateLimit-* headers so clients can back off. Reset is in seconds
+// from now, like Retry-After.
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    pub limit: usize,
+    pub remaining: usize,
+    pub retry_after_secs: u64,
+}
 
 #[derive(Debug)]
 pub enum UserError {
     NotFound,
+    JobNotFound,
     AddingUser,
-    UpdatingUser,
-    DeletingUser,
+    BadRequest(String),
+    Forbidden,
+    Validation(String),
+    // Every failure of one input, with VALIDATION_ERRORS=all
+    ValidationMany(Vec<UserError>),
+    Conflict(String),
+    PreconditionFailed,
+    Unavailable(String),
+    TooManyRequests(Throttle),
+    // No pool connection freed up within the get timeout
+    PoolExhausted(Throttle),
+    // A `Range: items=` that can't be served, with the total when known
+    RangeNotSatisfiable(Option<i64>),
+    // The response would exceed this many bytes, see MAX_RESPONSE_BYTES
+    ResponseTooLarge(u64),
+    // A failed operation in an atomic batch, with its index in the batch
+    BatchOperation(usize, Box<UserError>),
+    // The blocking task running the database work panicked or was canceled
+    Blocking(BlockingError),
     DieselError(DieselError),
 }
 
@@ -15,19 +108,445 @@ impl fmt::Display for UserError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             UserError::NotFound => write!(f, "User not found"),
+            UserError::JobNotFound => write!(f, "Job not found"),
             UserError::AddingUser => write!(f, "Error adding user"),
-            UserError::UpdatingUser => write!(f, "Error updating user"),
-            UserError::DeletingUser => write!(f, "Error deleting user"),
+            UserError::BadRequest(message) => write!(f, "{}", message),
+            UserError::Forbidden => write!(f, "Forbidden"),
+            UserError::Validation(message) => write!(f, "{}", message),
+            UserError::ValidationMany(failures) => {
+                let messages: Vec<String> = failures.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("; "))
+            }
+            UserError::Conflict(message) => write!(f, "{}", message),
+            UserError::PreconditionFailed => {
+                write!(f, "User was modified after the If-Unmodified-Since date")
+            }
+            UserError::Unavailable(message) => write!(f, "{}", message),
+            UserError::TooManyRequests(_) => write!(f, "Too many requests in flight"),
+            UserError::PoolExhausted(_) => write!(f, "No database connection available, try again"),
+            UserError::RangeNotSatisfiable(_) => write!(f, "Requested range not satisfiable"),
+            UserError::ResponseTooLarge(max) => write!(
+                f,
+                "The response would exceed {} bytes, request smaller pages with per_page or use /users/sync-stream",
+                max
+            ),
+            UserError::BatchOperation(index, e) => write!(f, "Operation {} failed: {}", index, e),
+            UserError::Blocking(_) => write!(f, "The request failed unexpectedly, try again"),
             UserError::DieselError(diesel_error) => write!(f, "Diesel error: {}", diesel_error),
         }
     }
 }
 
+// Violations of the uniqueness index are conflicts like the ones
+// `ensure_unique` reports
+impl From<DieselError> for UserError {
+    fn from(diesel_error: DieselError) -> Self {
+        if let DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) = &diesel_error {
+            if let Some(conflict) = info.constraint_name().and_then(crate::uniqueness::index_conflict) {
+                return conflict;
+            }
+        }
+        UserError::DieselError(diesel_error)
+    }
+}
+
+impl From<BlockingError> for UserError {
+    fn from(blocking_error: BlockingError) -> Self {
+        UserError::Blocking(blocking_error)
+    }
+}
+
 impl ResponseError for UserError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UserError::NotFound | UserError::JobNotFound => StatusCode::NOT_FOUND,
+            UserError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            UserError::Forbidden => StatusCode::FORBIDDEN,
+            UserError::Validation(_) | UserError::ValidationMany(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            UserError::Conflict(_) => StatusCode::CONFLICT,
+            UserError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            UserError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            UserError::PoolExhausted(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            UserError::ResponseTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            // Keep the status of the underlying failure
+            UserError::BatchOperation(_, e) => e.status_code(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
         match self {
-            UserError::NotFound => HttpResponse::NotFound().json(self.to_string()),
-            _ => HttpResponse::InternalServerError().json(self.to_string()),
+            UserError::DieselError(diesel_error) => log_diesel_error(diesel_error),
+            UserError::BatchOperation(_, e) => {
+                if let UserError::DieselError(diesel_error) = e.as_ref() {
+                    log_diesel_error(diesel_error)
+                }
+            }
+            UserError::Blocking(blocking_error) => {
+                log::error!("blocking task failed: {}", blocking_error)
+            }
+            _ => {}
+        }
+
+        self.render(self.to_string(), None)
+    }
+}
+
+impl UserError {
+    // Identifies the error for clients, unlike the message it is the same in
+    // every language and never changes wording
+    pub fn code(&self) -> &'static str {
+        match self {
+            UserError::NotFound => "not_found",
+            UserError::JobNotFound => "job_not_found",
+            UserError::AddingUser => "adding_user_failed",
+            UserError::BadRequest(_) => "bad_request",
+            UserError::Forbidden => "forbidden",
+            UserError::Validation(_) | UserError::ValidationMany(_) => "validation_failed",
+            UserError::Conflict(_) => "conflict",
+            UserError::PreconditionFailed => "precondition_failed",
+            UserError::Unavailable(_) => "unavailable",
+            UserError::TooManyRequests(_) => "too_many_requests",
+            UserError::PoolExhausted(_) => "pool_exhausted",
+            UserError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            UserError::ResponseTooLarge(_) => "response_too_large",
+            // The code of the underlying failure, like the status
+            UserError::BatchOperation(_, e) => e.code(),
+            UserError::Blocking(_) => "internal_error",
+            UserError::DieselError(_) => "database_error",
+        }
+    }
+
+    // The error body in the ERROR_ENVELOPE format: by default the message
+    // and code, plus the internal details with VERBOSE_ERRORS. `instance` is
+    // the request path, used by problem+json only.
+    pub fn render(&self, message: String, instance: Option<&str>) -> HttpResponse {
+        let envelope = ERROR_ENVELOPE.get().copied().unwrap_or(ErrorEnvelope::Generic);
+        self.render_as(envelope, message, instance)
+    }
+
+    fn render_as(
+        &self,
+        envelope: ErrorEnvelope,
+        message: String,
+        instance: Option<&str>,
+    ) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let verbose = VERBOSE_ERRORS.load(Ordering::Relaxed);
+        if let UserError::RangeNotSatisfiable(Some(total)) = self {
+            response.insert_header((header::CONTENT_RANGE, format!("items */{}", total)));
+        }
+        if let UserError::TooManyRequests(throttle) | UserError::PoolExhausted(throttle) = self {
+            response
+                .insert_header((header::RETRY_AFTER, throttle.retry_after_secs))
+                .insert_header(("X-RateLimit-Limit", throttle.limit))
+                .insert_header(("X-RateLimit-Remaining", throttle.remaining))
+                .insert_header(("X-RateLimit-Reset", throttle.retry_after_secs));
+        }
+
+        match envelope {
+            ErrorEnvelope::Generic => {}
+            ErrorEnvelope::Bare => {
+                return response.content_type("text/plain; charset=utf-8").body(message);
+            }
+            ErrorEnvelope::Problem => {
+                let status = self.status_code();
+                let body = Problem {
+                    problem_type: "about:blank",
+                    title: status.canonical_reason().unwrap_or("Error"),
+                    status: status.as_u16(),
+                    detail: message,
+                    code: self.code(),
+                    instance: instance.map(str::to_string),
+                    errors: self.failures(),
+                    debug: verbose.then(|| format!("{:?}", self)),
+                    chain: verbose.then(|| self.chain()),
+                };
+                let body = serde_json::to_string(&body).unwrap_or_default();
+                return response.content_type("application/problem+json").body(body);
+            }
         }
+
+        if !verbose {
+            return response.json(GenericError {
+                code: self.code(),
+                message,
+                errors: self.failures(),
+            });
+        }
+
+        response.json(VerboseError {
+            code: self.code(),
+            message,
+            debug: format!("{:?}", self),
+            chain: self.chain(),
+            errors: self.failures(),
+        })
+    }
+
+    // The failures of ValidationMany, each with its own code. Their messages
+    // are the details of the handler and stay untranslated.
+    fn failures(&self) -> Vec<Failure> {
+        match self {
+            UserError::ValidationMany(failures) => failures
+                .iter()
+                .map(|e| Failure {
+                    code: e.code(),
+                    message: e.to_string(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn chain(&self) -> Vec<String> {
+        let mut chain = Vec::new();
+        let mut source: Option<&dyn std::error::Error> = match self {
+            UserError::DieselError(diesel_error) => Some(diesel_error),
+            UserError::BatchOperation(_, e) => return e.chain(),
+            _ => None,
+        };
+
+        while let Some(e) = source {
+            chain.push(e.to_string());
+            source = e.source();
+        }
+        chain
+    }
+}
+
+// Logs database errors with the SQLSTATE and the constraint, table and column
+// Postgres reported, as `key=value` pairs for grepping and categorizing
+fn log_diesel_error(diesel_error: &DieselError) {
+    log::error!("{}", describe_diesel_error(diesel_error));
+}
+
+fn describe_diesel_error(diesel_error: &DieselError) -> String {
+    match diesel_error {
+        DieselError::DatabaseError(kind, info) => format!(
+            "database error: sqlstate={} kind={:?} constraint={} table={} column={} message={:?}",
+            sqlstate(kind, info.as_ref()).unwrap_or("unavailable"),
+            kind,
+            info.constraint_name().unwrap_or("-"),
+            info.table_name().unwrap_or("-"),
+            info.column_name().unwrap_or("-"),
+            info.message(),
+        ),
+        other => format!("diesel error: {}", other),
+    }
+}
+
+// Diesel doesn't expose the SQLSTATE, only the kind it maps it to, and its
+// error information can't be downcast to the Postgres one. These kinds each
+// come from exactly one SQLSTATE, so it is known for them. Deadlocks have no
+// kind of their own; they are the Unknown errors with Postgres' fixed
+// deadlock message, which is English unless lc_messages says otherwise. The
+// service isn't ready under another locale, see
+// `readiness::check_message_locale`. For every other error it is logged as
+// `unavailable`.
+pub fn sqlstate(
+    kind: &DatabaseErrorKind,
+    info: &dyn DatabaseErrorInformation,
+) -> Option<&'static str> {
+    match kind {
+        DatabaseErrorKind::Unknown if info.message().starts_with("deadlock detected") => {
+            Some("40P01")
+        }
+        DatabaseErrorKind::UniqueViolation => Some("23505"),
+        DatabaseErrorKind::ForeignKeyViolation => Some("23503"),
+        DatabaseErrorKind::NotNullViolation => Some("23502"),
+        DatabaseErrorKind::CheckViolation => Some("23514"),
+        DatabaseErrorKind::RestrictViolation => Some("23001"),
+        DatabaseErrorKind::ExclusionViolation => Some("23P01"),
+        DatabaseErrorKind::SerializationFailure => Some("40001"),
+        DatabaseErrorKind::ReadOnlyTransaction => Some("25006"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    async fn body(response: HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn generic_body_has_the_code() {
+        let _shared = crate::testing::lock();
+        let error = UserError::Conflict("A user with this email already exists".to_string());
+        let response = error.render_as(ErrorEnvelope::Generic, error.to_string(), None);
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = body(response).await;
+        assert_eq!(body["code"], "conflict");
+        assert_eq!(body["message"], "A user with this email already exists");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn problem_body_has_the_code() {
+        let _shared = crate::testing::lock();
+        let error = UserError::PoolExhausted(Throttle { limit: 10, remaining: 0, retry_after_secs: 1 });
+        let response = error.render_as(ErrorEnvelope::Problem, error.to_string(), Some("/get"));
+
+        let body = body(response).await;
+        assert_eq!(body["code"], "pool_exhausted");
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["instance"], "/get");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn every_failure_is_listed_with_its_code() {
+        let _shared = crate::testing::lock();
+        let error = UserError::ValidationMany(vec![
+            UserError::Validation("first_name must not be empty".to_string()),
+            UserError::Conflict("A user with this email already exists".to_string()),
+        ]);
+        let expected = serde_json::json!([
+            {"code": "validation_failed", "message": "first_name must not be empty"},
+            {"code": "conflict", "message": "A user with this email already exists"},
+        ]);
+
+        let response = error.render_as(ErrorEnvelope::Generic, error.to_string(), None);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let generic = body(response).await;
+        assert_eq!(generic["code"], "validation_failed");
+        assert_eq!(
+            generic["message"],
+            "first_name must not be empty; A user with this email already exists"
+        );
+        assert_eq!(generic["errors"], expected);
+
+        let response = error.render_as(ErrorEnvelope::Problem, error.to_string(), None);
+        assert_eq!(body(response).await["errors"], expected);
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn throttled_responses_carry_the_rate_limit_headers() {
+        let _shared = crate::testing::lock();
+        let throttle = Throttle { limit: 4, remaining: 0, retry_after_secs: 3 };
+
+        for error in [UserError::TooManyRequests(throttle), UserError::PoolExhausted(throttle)] {
+            let response = error.render_as(ErrorEnvelope::Generic, error.to_string(), None);
+            let headers = response.headers();
+            assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "3");
+            assert_eq!(headers.get("X-RateLimit-Limit").unwrap(), "4");
+            assert_eq!(headers.get("X-RateLimit-Remaining").unwrap(), "0");
+            assert_eq!(headers.get("X-RateLimit-Reset").unwrap(), "3");
+        }
+
+        let response = UserError::NotFound.render_as(ErrorEnvelope::Generic, String::new(), None);
+        assert!(response.headers().get("X-RateLimit-Remaining").is_none());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn problem_body_follows_rfc_7807() {
+        let _shared = crate::testing::lock();
+        let error = UserError::NotFound;
+        let response = error.render_as(ErrorEnvelope::Problem, error.to_string(), None);
+
+        let content_type = response.headers().get(header::CONTENT_TYPE).unwrap();
+        assert_eq!(content_type, "application/problem+json");
+        let body = body(response).await;
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["detail"], "User not found");
+        assert!(body.get("instance").is_none());
+    }
+
+    #[actix_web::test]
+    async fn bare_body_is_the_message() {
+        let error = UserError::Validation("email is not valid".to_string());
+        let response = error.render_as(ErrorEnvelope::Bare, error.to_string(), None);
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let content_type = response.headers().get(header::CONTENT_TYPE).unwrap();
+        assert_eq!(content_type, "text/plain; charset=utf-8");
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "email is not valid");
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn verbose_fields_appear_only_with_verbose_errors() {
+        let _shared = crate::testing::lock();
+        let error = UserError::DieselError(DieselError::DatabaseError(
+            DatabaseErrorKind::Unknown,
+            Box::new("relation \"users\" does not exist".to_string()),
+        ));
+
+        let quiet = body(error.render_as(ErrorEnvelope::Generic, error.to_string(), None)).await;
+        assert_eq!(quiet.as_object().unwrap().len(), 2, "{}", quiet);
+        let quiet = body(error.render_as(ErrorEnvelope::Problem, error.to_string(), None)).await;
+        assert!(quiet.get("debug").is_none() && quiet.get("chain").is_none(), "{}", quiet);
+
+        VERBOSE_ERRORS.store(true, Ordering::Relaxed);
+        let verbose = body(error.render_as(ErrorEnvelope::Generic, error.to_string(), None)).await;
+        let problem = body(error.render_as(ErrorEnvelope::Problem, error.to_string(), None)).await;
+        VERBOSE_ERRORS.store(false, Ordering::Relaxed);
+
+        for body in [verbose, problem] {
+            assert!(body["debug"].as_str().unwrap().contains("DatabaseError"), "{}", body);
+            assert_eq!(body["chain"][0], "relation \"users\" does not exist");
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_blocking_tasks_are_internal_errors() {
+        let failed: Result<(), _> =
+            actix_web::web::block(|| panic!("database work panicked")).await;
+        let error = UserError::from(failed.unwrap_err());
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.code(), "internal_error");
+    }
+
+    #[test]
+    fn batch_operations_keep_the_code_of_the_failure() {
+        let error = UserError::BatchOperation(2, Box::new(UserError::NotFound));
+        assert_eq!(error.code(), "not_found");
+    }
+
+    #[test]
+    fn constraint_violations_are_logged_with_sqlstate() {
+        let _shared = crate::testing::lock();
+        let Some(pool) = crate::testing::pool(1, std::time::Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        crate::testing::reset(&mut conn);
+        crate::testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+
+        use diesel::prelude::*;
+        // A second user with the same user_id
+        let violation = diesel::sql_query(
+            "INSERT INTO users (user_id, first_name, last_name, email) \
+             SELECT user_id, 'Grace', 'Hopper', 'grace@example.com' FROM users",
+        )
+        .execute(&mut conn)
+        .unwrap_err();
+
+        let logged = describe_diesel_error(&violation);
+        assert!(logged.starts_with("database error: sqlstate=23505 kind=UniqueViolation"), "{}", logged);
+        assert!(logged.contains("constraint=users_user_id_key table=users"), "{}", logged);
+    }
+
+    #[test]
+    fn unmapped_database_errors_have_no_sqlstate() {
+        let error = DieselError::DatabaseError(
+            DatabaseErrorKind::Unknown,
+            Box::new("invalid regular expression".to_string()),
+        );
+        let logged = describe_diesel_error(&error);
+        assert!(logged.starts_with("database error: sqlstate=unavailable kind=Unknown"), "{}", logged);
     }
 }
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use serde::ser::SerializeSeq;
+use serde::Serializer;
+use uuid::Uuid;
+
+use crate::user_error::UserError;
+
+// How user ids are written in responses, chosen per request with
+// `?uuid_format=`. Both forms are accepted on input anyway, `Uuid::parse_str`
+// and serde take either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UuidFormat {
+    // 67e55044-10b1-426f-9247-bb680e5fe0c8
+    #[default]
+    Hyphenated,
+    // 67e5504410b1426f9247bb680e5fe0c8
+    Simple,
+}
+
+impl FromStr for UuidFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hyphenated" => Ok(UuidFormat::Hyphenated),
+            "simple" => Ok(UuidFormat::Simple),
+            _ => Err("uuid_format must be one of hyphenated, simple".to_string()),
+        }
+    }
+}
+
+tokio::task_local! {
+    static UUID_FORMAT: UuidFormat;
+}
+
+fn current() -> UuidFormat {
+    UUID_FORMAT.try_with(|format| *format).unwrap_or_default()
+}
+
+fn write<S: Serializer>(value: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    match current() {
+        UuidFormat::Hyphenated => serializer.collect_str(&value.hyphenated()),
+        UuidFormat::Simple => serializer.collect_str(&value.simple()),
+    }
+}
+
+// For `#[serde(serialize_with)]` on user ids
+pub fn serialize<S: Serializer>(value: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    write(value, serializer)
+}
+
+pub fn serialize_all<S: Serializer>(values: &[Uuid], serializer: S) -> Result<S::Ok, S::Error> {
+    struct Formatted<'a>(&'a Uuid);
+
+    impl serde::Serialize for Formatted<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            write(self.0, serializer)
+        }
+    }
+
+    let mut seq = serializer.serialize_seq(Some(values.len()))?;
+    for value in values {
+        seq.serialize_element(&Formatted(value))?;
+    }
+    seq.end()
+}
+
+// Runs the request with its `?uuid_format=`. Responses are serialized by the
+// handler, inside this scope. Streamed bodies are serialized later, when they
+// are polled outside of it, and always use the hyphenated form.
+pub async fn format_uuids(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let requested = actix_web::web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .ok()
+        .and_then(|params| {
+            params
+                .into_inner()
+                .into_iter()
+                .find(|(name, _)| name == "uuid_format")
+                .map(|(_, value)| value)
+        });
+    let format = match requested.map(|value| value.parse()) {
+        Some(Ok(format)) => format,
+        // A response rather than an error, so `i18n::localize_errors` sees it
+        Some(Err(message)) => {
+            let e = UserError::BadRequest(message);
+            return Ok(req.error_response(e).map_into_right_body());
+        }
+        None => UuidFormat::Hyphenated,
+    };
+
+    Ok(UUID_FORMAT.scope(format, next.call(req)).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::http::StatusCode;
+    use std::time::Duration;
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    #[test]
+    fn user_ids_are_written_in_the_requested_form() {
+        let user = testing::user(1);
+        let written = |format| {
+            let value = UUID_FORMAT.sync_scope(format, || serde_json::to_value(&user).unwrap());
+            value["user_id"].as_str().unwrap().to_string()
+        };
+        assert_eq!(written(UuidFormat::Hyphenated), user.user_id.hyphenated().to_string());
+        assert_eq!(written(UuidFormat::Simple), user.user_id.simple().to_string());
+
+        // Outside of a request, as for streamed bodies
+        let outside = serde_json::to_value(&user).unwrap();
+        assert_eq!(outside["user_id"], user.user_id.hyphenated().to_string());
+
+        assert_eq!("simple".parse(), Ok(UuidFormat::Simple));
+        assert!("braced".parse::<UuidFormat>().is_err());
+    }
+
+    #[actix_web::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn both_forms_are_accepted_in_paths() {
+        let _shared = testing::lock();
+        let Some(pool) = testing::pool(2, Duration::from_secs(5)) else { return };
+        let mut conn = pool.get().unwrap();
+        testing::reset(&mut conn);
+        let ada = testing::insert(&mut conn, "Ada", "Lovelace", "ada@example.com").unwrap();
+        let simple = ada.user_id.simple().to_string();
+        let config = testing::config(&[]);
+        let get = |uri: String| actix_web::test::TestRequest::get().uri(&uri);
+
+        let uri = format!("/get/{}?uuid_format=simple", ada.user_id.hyphenated());
+        let (status, body) = testing::json(testing::call(&pool, &config, get(uri)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["user_id"], simple);
+
+        let uri = format!("/get/{}", simple);
+        let (status, body) = testing::json(testing::call(&pool, &config, get(uri)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["user_id"], ada.user_id.hyphenated().to_string());
+
+        let uri = format!("/get/{}?uuid_format=braced", ID);
+        let response = testing::call(&pool, &config, get(uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
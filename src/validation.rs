@@ -0,0 +1,565 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{models, user_error::UserError};
+use uuid::Uuid;
+
+// Set once at startup from SANITIZE_INPUT=strip. Otherwise control
+// characters in user fields are rejected.
+pub static STRIP_CONTROL_CHARS: AtomicBool = AtomicBool::new(false);
+
+// Set once at startup from EMAIL_TRIM and EMAIL_LOWERCASE, see `clean_email`
+pub static TRIM_EMAILS: AtomicBool = AtomicBool::new(true);
+pub static LOWERCASE_EMAILS: AtomicBool = AtomicBool::new(false);
+
+// Trims every field and sanitizes control characters, emails as configured
+// by `clean_email`. A required field that is blank is rejected, so `"   "` is
+// treated the same as `""`.
+pub fn validate_new_user(form: models::NewUser) -> Result<models::NewUser, UserError> {
+    Ok(models::NewUser {
+        first_name: required("first_name", &form.first_name, sanitize)?,
+        last_name: required("last_name", &form.last_name, sanitize)?,
+        email: required("email", &form.email, clean_email)?,
+    })
+}
+
+// Trims every field and sanitizes control characters, emails as configured
+// by `clean_email`. An optional field that is blank becomes None, which
+// leaves the stored value unchanged.
+pub fn validate_update_user(form: models::UpdateUser) -> Result<models::UpdateUser, UserError> {
+    Ok(models::UpdateUser {
+        first_name: optional("first_name", form.first_name, sanitize)?,
+        last_name: optional("last_name", form.last_name, sanitize)?,
+        email: optional("email", form.email, clean_email)?,
+    })
+}
+
+// Every field `validate_new_user` would refuse, in field order, rather than
+// only the first
+pub fn new_user_failures(form: &models::NewUser) -> Vec<UserError> {
+    [
+        required("first_name", &form.first_name, sanitize),
+        required("last_name", &form.last_name, sanitize),
+        required("email", &form.email, clean_email),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect()
+}
+
+// Every field `validate_update_user` would refuse
+pub fn update_user_failures(form: &models::UpdateUser) -> Vec<UserError> {
+    [
+        optional("first_name", form.first_name.clone(), sanitize),
+        optional("last_name", form.last_name.clone(), sanitize),
+        optional("email", form.email.clone(), clean_email),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect()
+}
+
+// Like `validate_new_user` and `validate_update_user` but never failing:
+// fields that would be refused are only trimmed. Equal to the validated user
+// whenever validation passes, so uniqueness can be checked on it first.
+pub fn lenient_new_user(form: &models::NewUser) -> models::NewUser {
+    models::NewUser {
+        first_name: lenient("first_name", &form.first_name, sanitize),
+        last_name: lenient("last_name", &form.last_name, sanitize),
+        email: lenient("email", &form.email, clean_email),
+    }
+}
+
+pub fn lenient_update_user(form: &models::UpdateUser) -> models::UpdateUser {
+    let field = |field, value: &Option<String>, clean: Clean| {
+        value
+            .as_deref()
+            .map(|value| lenient(field, value, clean))
+            .filter(|value| !value.trim().is_empty())
+    };
+    models::UpdateUser {
+        first_name: field("first_name", &form.first_name, sanitize),
+        last_name: field("last_name", &form.last_name, sanitize),
+        email: field("email", &form.email, clean_email),
+    }
+}
+
+fn lenient(field: &str, value: &str, clean: Clean) -> String {
+    clean(field, value).unwrap_or_else(|_| value.trim().to_string())
+}
+
+// An email the way it is stored, for looking users up by one:
+// trimmed unless EMAIL_TRIM is off, lowercased if EMAIL_LOWERCASE is on
+pub fn normalize_email(value: &str) -> String {
+    let value = match TRIM_EMAILS.load(Ordering::Relaxed) {
+        true => value.trim(),
+        false => value,
+    };
+    match LOWERCASE_EMAILS.load(Ordering::Relaxed) {
+        true => value.to_lowercase(),
+        false => value.to_string(),
+    }
+}
+
+// Well known disposable email providers. Addresses there are accepted but
+// reported back as a warning.
+const DISPOSABLE_EMAIL_DOMAINS: &[&str] = &[
+    "10minutemail.com",
+    "guerrillamail.com",
+    "mailinator.com",
+    "sharklasers.com",
+    "temp-mail.org",
+    "throwawaymail.com",
+    "trashmail.com",
+    "yopmail.com",
+];
+
+// Soft checks: input that is valid but suspicious. The request still
+// succeeds, with these in the response's `warnings`.
+pub fn email_warnings(email: &str) -> Vec<String> {
+    let domain = email.rsplit('@').next().unwrap_or_default().to_lowercase();
+
+    let mut warnings = Vec::new();
+    if DISPOSABLE_EMAIL_DOMAINS.contains(&domain.as_str()) {
+        warnings.push(format!("email domain {} is a known disposable provider", domain));
+    }
+    warnings
+}
+
+type Clean = fn(&str, &str) -> Result<String, UserError>;
+
+fn required(field: &str, value: &str, clean: Clean) -> Result<String, UserError> {
+    let value = clean(field, value)?;
+    if value.trim().is_empty() {
+        return Err(UserError::Validation(format!("{} must not be empty", field)));
+    }
+    Ok(value)
+}
+
+fn optional(field: &str, value: Option<String>, clean: Clean) -> Result<Option<String>, UserError> {
+    Ok(value
+        .map(|value| clean(field, &value))
+        .transpose()?
+        .filter(|value| !value.trim().is_empty()))
+}
+
+// Null bytes are always refused, Postgres can't store them in text. Other
+// control characters (tabs, newlines, escapes) are stripped or refused
+// depending on STRIP_CONTROL_CHARS. The result is trimmed.
+fn sanitize(field: &str, value: &str) -> Result<String, UserError> {
+    sanitize_with(field, value, true)
+}
+
+fn sanitize_with(field: &str, value: &str, trim: bool) -> Result<String, UserError> {
+    if value.contains('\0') {
+        return Err(UserError::Validation(format!("{} must not contain null bytes", field)));
+    }
+
+    let value = if trim { value.trim() } else { value };
+    if !value.chars().any(char::is_control) {
+        return Ok(value.to_string());
+    }
+    if STRIP_CONTROL_CHARS.load(Ordering::Relaxed) {
+        let stripped: String = value.chars().filter(|c| !c.is_control()).collect();
+        return Ok(if trim { stripped.trim().to_string() } else { stripped });
+    }
+    Err(UserError::Validation(format!("{} must not contain control characters", field)))
+}
+
+// `sanitize` with EMAIL_TRIM and EMAIL_LOWERCASE applied. Without trimming,
+// surrounding whitespace is stored as sent, though a blank email is still
+// refused.
+fn clean_email(field: &str, value: &str) -> Result<String, UserError> {
+    let value = sanitize_with(field, value, TRIM_EMAILS.load(Ordering::Relaxed))?;
+    match LOWERCASE_EMAILS.load(Ordering::Relaxed) {
+        true => Ok(value.to_lowercase()),
+        false => Ok(value),
+    }
+}
+
+// Accepts host names like `example.com`: dot separated labels of ASCII
+// letters, digits and inner hyphens, with at least two labels.
+pub fn validate_domain(domain: &str) -> Result<String, UserError> {
+    let domain = domain.trim().to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    let valid = domain.len() <= 253
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if !valid {
+        return Err(UserError::BadRequest(format!("{:?} is not a valid domain", domain)));
+    }
+    Ok(domain)
+}
+
+// A path reference to a user, by user_id or by the internal integer id
+#[derive(Clone, Copy)]
+pub enum UserRef {
+    UserId(Uuid),
+    Id(i32),
+}
+
+// Metadata is merged key by key, so only objects make sense
+pub fn validate_metadata(
+    value: serde_json::Value,
+) -> Result<serde_json::Map<String, serde_json::Value>, UserError> {
+    match value {
+        serde_json::Value::Object(object) => Ok(object),
+        _ => Err(UserError::Validation("metadata must be a JSON object".to_string())),
+    }
+}
+
+const MAX_META_FILTERS: usize = 10;
+
+// `meta.<key>=<value>` query parameters, as (key, value) pairs
+pub fn parse_meta_filters(query_string: &str) -> Result<Vec<(String, String)>, UserError> {
+    let params = actix_web::web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| UserError::BadRequest(e.to_string()))?;
+
+    let filters: Vec<(String, String)> = params
+        .into_inner()
+        .into_iter()
+        .filter_map(|(name, value)| name.strip_prefix("meta.").map(|key| (key.to_string(), value)))
+        .collect();
+
+    if filters.iter().any(|(key, _)| key.is_empty()) {
+        return Err(UserError::BadRequest("meta filters need a key, as in meta.<key>".to_string()));
+    }
+    if filters.len() > MAX_META_FILTERS {
+        return Err(UserError::BadRequest(format!(
+            "at most {} meta filters are allowed",
+            MAX_META_FILTERS
+        )));
+    }
+
+    Ok(filters)
+}
+
+// A single `@` with a non-empty local part and a valid domain. Returns the
+// lowercased domain.
+pub fn email_domain(email: &str) -> Option<String> {
+    let (local, domain) = email.trim().split_once('@')?;
+    if local.is_empty() || domain.contains('@') || local.chars().any(char::is_whitespace) {
+        return None;
+    }
+    validate_domain(domain).ok()
+}
+
+const MAX_EMAIL_PATTERN_LEN: usize = 100;
+
+// Caps the length of a `/users/email-regex` pattern and refuses
+// backreferences, the one construct that makes Postgres regex matching
+// expensive. Syntax errors are left to Postgres.
+pub fn validate_email_pattern(pattern: &str) -> Result<String, UserError> {
+    if pattern.is_empty() {
+        return Err(UserError::BadRequest("pattern must not be empty".to_string()));
+    }
+    if pattern.chars().count() > MAX_EMAIL_PATTERN_LEN {
+        return Err(UserError::BadRequest(format!(
+            "pattern must be at most {} characters",
+            MAX_EMAIL_PATTERN_LEN
+        )));
+    }
+
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.next().is_some_and(|next| next.is_ascii_digit()) {
+            return Err(UserError::BadRequest("backreferences are not allowed".to_string()));
+        }
+    }
+    Ok(pattern.to_string())
+}
+
+// Tries a UUID first. Integer ids are only accepted with
+// ACCEPT_INTEGER_IDS, since sequential ids make users easy to enumerate.
+pub fn parse_user_ref(value: &str, accept_integer_ids: bool) -> Result<UserRef, UserError> {
+    if let Ok(parsed) = Uuid::parse_str(value.trim()) {
+        return Ok(UserRef::UserId(parsed));
+    }
+    match value.trim().parse::<i32>() {
+        Ok(id) if accept_integer_ids => Ok(UserRef::Id(id)),
+        _ => Err(UserError::BadRequest(format!("{:?} is not a valid user id", value))),
+    }
+}
+
+// `Range: items=<first>-<last>` or `items=<first>-`, 0-based and inclusive.
+// Other units are ignored as RFC 9110 asks; a malformed items range is a 416.
+pub fn parse_items_range(value: Option<&str>) -> Result<Option<(i64, Option<i64>)>, UserError> {
+    let spec = match value.and_then(|value| value.trim().strip_prefix("items=")) {
+        Some(spec) => spec.trim(),
+        None => return Ok(None),
+    };
+
+    let (first, last) = spec.split_once('-').ok_or(UserError::RangeNotSatisfiable(None))?;
+    let first = first
+        .parse::<i64>()
+        .ok()
+        .filter(|first| *first >= 0)
+        .ok_or(UserError::RangeNotSatisfiable(None))?;
+    let last = match last {
+        "" => None,
+        last => Some(
+            last.parse::<i64>()
+                .ok()
+                .filter(|last| *last >= first)
+                .ok_or(UserError::RangeNotSatisfiable(None))?,
+        ),
+    };
+
+    Ok(Some((first, last)))
+}
+
+pub fn parse_feed_cursor(value: &str) -> Result<models::FeedCursor, UserError> {
+    use base64::Engine;
+
+    let invalid = || UserError::BadRequest("cursor is not valid".to_string());
+
+    let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim())
+        .map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (micros, id) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    let created_at = micros
+        .parse::<i64>()
+        .ok()
+        .and_then(chrono::DateTime::from_timestamp_micros)
+        .ok_or_else(invalid)?
+        .naive_utc();
+    let id = id.parse::<i32>().map_err(|_| invalid())?;
+
+    Ok(models::FeedCursor { created_at, id })
+}
+
+// An RFC 3339 query parameter as UTC, which is how timestamps are stored
+pub fn parse_timestamp(name: &str, value: &str) -> Result<chrono::NaiveDateTime, UserError> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|parsed| parsed.naive_utc())
+        .map_err(|_| UserError::BadRequest(format!("{} must be an RFC 3339 timestamp", name)))
+}
+
+// Normalizes, lowercases and dedupes a list of emails for a lookup. The list
+// and every entry must be non-empty.
+pub fn normalize_emails(emails: Vec<String>) -> Result<Vec<String>, UserError> {
+    if emails.is_empty() {
+        return Err(UserError::BadRequest("at least one email is required".to_string()));
+    }
+
+    let mut normalized: Vec<String> = Vec::with_capacity(emails.len());
+    for email in emails {
+        let email = normalize_email(&email).to_lowercase();
+        if email.trim().is_empty() {
+            return Err(UserError::BadRequest("emails must not be empty".to_string()));
+        }
+        if !normalized.contains(&email) {
+            normalized.push(email);
+        }
+    }
+    Ok(normalized)
+}
+
+// For STRICT_QUERY: fails naming the first query parameter not in `known`.
+// A known name ending in `*` matches every parameter with that prefix.
+pub fn reject_unknown_params(query_string: &str, known: &[&str]) -> Result<(), UserError> {
+    let params = actix_web::web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| UserError::BadRequest(e.to_string()))?;
+
+    let is_known = |name: &str| {
+        known.iter().any(|known| match known.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == *known,
+        })
+    };
+
+    match params.iter().find(|(name, _)| !is_known(name)) {
+        Some((name, _)) => Err(UserError::BadRequest(format!(
+            "unknown query parameter {:?}, expected one of {}",
+            name,
+            known.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn new_user(first_name: &str, last_name: &str, email: &str) -> models::NewUser {
+        models::NewUser {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    #[test]
+    fn whitespace_only_required_name_is_rejected() {
+        let _shared = testing::lock();
+        let error = validate_new_user(new_user("   ", "Doe", "jane@example.com")).unwrap_err();
+        assert!(matches!(error, UserError::Validation(message) if message == "first_name must not be empty"));
+    }
+
+    #[test]
+    fn whitespace_only_optional_name_is_left_unchanged() {
+        let _shared = testing::lock();
+        let form = models::UpdateUser {
+            first_name: Some("  Jane ".to_string()),
+            last_name: Some("   ".to_string()),
+            email: None,
+        };
+        let validated = validate_update_user(form).unwrap();
+        assert_eq!(validated.first_name.as_deref(), Some("Jane"));
+        assert_eq!(validated.last_name, None);
+    }
+
+    #[test]
+    fn parse_timestamp_converts_offsets_to_utc() {
+        let parsed = parse_timestamp("since", "2026-10-14T08:30:00+02:00").unwrap();
+        assert_eq!(parsed.to_string(), "2026-10-14 06:30:00");
+    }
+
+    #[test]
+    fn unknown_query_params_are_named() {
+        let known = ["page", "per_page", "meta.*"];
+        assert!(reject_unknown_params("page=2&per_page=10&meta.team=core", &known).is_ok());
+
+        match reject_unknown_params("page=2&pageSize=10", &known) {
+            Err(UserError::BadRequest(message)) => {
+                assert!(message.starts_with("unknown query parameter \"pageSize\""), "{}", message)
+            }
+            other => panic!("expected a 400, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn user_refs_are_uuids_first_then_integers() {
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let parsed = parse_user_ref(uuid, false);
+        assert!(matches!(parsed, Ok(UserRef::UserId(parsed)) if parsed.to_string() == uuid));
+        assert!(matches!(parse_user_ref("42", true), Ok(UserRef::Id(42))));
+
+        assert!(matches!(parse_user_ref("42", false), Err(UserError::BadRequest(_))));
+        assert!(matches!(parse_user_ref("not-an-id", true), Err(UserError::BadRequest(_))));
+    }
+
+    #[test]
+    fn feed_cursors_round_trip() {
+        let cursor = models::FeedCursor {
+            created_at: testing::user(1).created_at + chrono::Duration::microseconds(123_456),
+            id: 42,
+        };
+        assert_eq!(parse_feed_cursor(&cursor.encode()).unwrap(), cursor);
+
+        for invalid in ["", "not base64!", "MTIzNDU2", "YWJjOjQy"] {
+            let parsed = parse_feed_cursor(invalid);
+            assert!(matches!(parsed, Err(UserError::BadRequest(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn control_characters_are_rejected_or_stripped() {
+        let _shared = testing::lock();
+        let message = |result: Result<models::NewUser, UserError>| match result {
+            Err(UserError::Validation(message)) => message,
+            other => panic!("expected a 422, got {:?}", other),
+        };
+
+        let tabbed = || new_user("Ada\tMarie", "Lovelace", "ada@example.com");
+        let null = || new_user("Ada\u{0000}", "Lovelace", "ada@example.com");
+        assert_eq!(
+            message(validate_new_user(tabbed())),
+            "first_name must not contain control characters"
+        );
+        assert_eq!(message(validate_new_user(null())), "first_name must not contain null bytes");
+
+        STRIP_CONTROL_CHARS.store(true, Ordering::Relaxed);
+        let stripped = validate_new_user(tabbed());
+        let null_stripped = validate_new_user(null());
+        STRIP_CONTROL_CHARS.store(false, Ordering::Relaxed);
+
+        assert_eq!(stripped.unwrap().first_name, "AdaMarie");
+        assert_eq!(message(null_stripped), "first_name must not contain null bytes");
+    }
+
+    #[test]
+    fn items_ranges_are_parsed_or_refused() {
+        assert_eq!(parse_items_range(None).unwrap(), None);
+        assert_eq!(parse_items_range(Some("bytes=0-99")).unwrap(), None);
+        assert_eq!(parse_items_range(Some("items=0-49")).unwrap(), Some((0, Some(49))));
+        assert_eq!(parse_items_range(Some(" items= 10- ")).unwrap(), Some((10, None)));
+
+        for invalid in ["items=", "items=5", "items=9-3", "items=-5", "items=a-b"] {
+            let refused = parse_items_range(Some(invalid));
+            assert!(
+                matches!(refused, Err(UserError::RangeNotSatisfiable(None))),
+                "{} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn email_trimming_and_lowercasing_are_independent() {
+        let _shared = testing::lock();
+        let sent = "  Ada@Example.COM ";
+
+        for (trim, lowercase, stored) in [
+            (true, false, "Ada@Example.COM"),
+            (false, false, "  Ada@Example.COM "),
+            (true, true, "ada@example.com"),
+            (false, true, "  ada@example.com "),
+        ] {
+            TRIM_EMAILS.store(trim, Ordering::Relaxed);
+            LOWERCASE_EMAILS.store(lowercase, Ordering::Relaxed);
+            let added = validate_new_user(new_user("Ada", "Lovelace", sent)).map(|user| user.email);
+            let changes = models::UpdateUser {
+                first_name: None,
+                last_name: None,
+                email: Some(sent.to_string()),
+            };
+            let updated = validate_update_user(changes).map(|changes| changes.email);
+            let looked_up = normalize_email(sent);
+            TRIM_EMAILS.store(true, Ordering::Relaxed);
+            LOWERCASE_EMAILS.store(false, Ordering::Relaxed);
+
+            assert_eq!(added.unwrap(), stored, "trim={} lowercase={}", trim, lowercase);
+            assert_eq!(updated.unwrap().as_deref(), Some(stored));
+            assert_eq!(looked_up, stored);
+        }
+    }
+
+    #[test]
+    fn every_refused_field_is_reported() {
+        let _shared = testing::lock();
+        let messages = |failures: Vec<UserError>| -> Vec<String> {
+            failures.iter().map(ToString::to_string).collect()
+        };
+
+        let form = new_user(" ", "Love\tlace", "ada@example.com");
+        assert_eq!(
+            messages(new_user_failures(&form)),
+            ["first_name must not be empty", "last_name must not contain control characters"]
+        );
+        let first = validate_new_user(form).unwrap_err();
+        assert_eq!(first.to_string(), "first_name must not be empty");
+        assert!(new_user_failures(&new_user("Ada", "Lovelace", "ada@example.com")).is_empty());
+
+        let changes = models::UpdateUser {
+            first_name: Some("   ".to_string()),
+            last_name: Some("Love\u{0000}lace".to_string()),
+            email: Some("ada\n@example.com".to_string()),
+        };
+        assert_eq!(
+            messages(update_user_failures(&changes)),
+            ["last_name must not contain null bytes", "email must not contain control characters"]
+        );
+    }
+}
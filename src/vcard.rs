@@ -0,0 +1,79 @@
+use crate::models::User;
+
+// Formats a user as a vCard 3.0 (RFC 2426)
+pub fn to_vcard(user: &User) -> String {
+    let lines = [
+        "BEGIN:VCARD".to_string(),
+        "VERSION:3.0".to_string(),
+        format!("N:{};{};;;", escape(&user.last_name), escape(&user.first_name)),
+        format!("FN:{}", escape(&user.display_name)),
+        format!("EMAIL;TYPE=INTERNET:{}", escape(&user.email)),
+        format!("UID:urn:uuid:{}", user.user_id),
+        // Sessions run in UTC, see `pool::UtcSession`
+        format!("REV:{}", user.updated_at.format("%Y-%m-%dT%H:%M:%SZ")),
+        "END:VCARD".to_string(),
+    ];
+
+    // vCard lines are CRLF terminated
+    lines.iter().map(|line| format!("{}\r\n", fold(line))).collect()
+}
+
+// Lines longer than 75 octets are folded: broken with a CRLF and continued
+// after a single space. Breaks fall between characters, never inside one.
+fn fold(line: &str) -> String {
+    const MAX_OCTETS: usize = 75;
+
+    let mut folded = String::with_capacity(line.len());
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+// Backslash, comma, semicolon and newlines have to be escaped in text values
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_values_are_escaped() {
+        assert_eq!(escape("Smith, Jr.; \\o/"), "Smith\\, Jr.\\; \\\\o/");
+        assert_eq!(escape("two\r\nlines"), "two\\nlines");
+        assert_eq!(escape("Zoë"), "Zoë");
+    }
+
+    #[test]
+    fn long_lines_are_folded_at_75_octets() {
+        let mut user = crate::testing::user(1);
+        user.display_name = "é".repeat(60);
+
+        let card = to_vcard(&user);
+        for line in card.split("\r\n") {
+            assert!(line.len() <= 75, "{:?} is {} octets", line, line.len());
+        }
+        let unfolded = card.replace("\r\n ", "");
+        assert!(unfolded.contains(&format!("\r\nFN:{}\r\n", user.display_name)));
+        assert!(unfolded.contains("\r\nREV:2026-01-01T12:00:00Z\r\n"));
+    }
+}